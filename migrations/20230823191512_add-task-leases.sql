@@ -0,0 +1,5 @@
+alter table sandbox_tasks
+    add lease_worker_id text;
+
+alter table sandbox_tasks
+    add lease_expires_at timestamp with time zone;
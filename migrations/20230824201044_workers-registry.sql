@@ -0,0 +1,14 @@
+drop table sandbox_workers;
+
+create table sandbox_workers
+(
+    worker_id     text                                   not null
+        constraint sandbox_workers_pk
+            primary key,
+    hostname      text                                   not null,
+    version       text                                   not null,
+    task_kinds    text[]                                 not null,
+    models        text[]                                 not null,
+    registered_at timestamp with time zone default now() not null,
+    last_ping_at  timestamp with time zone default now() not null
+);
@@ -0,0 +1,5 @@
+create type task_visibility as enum('private', 'unlisted', 'public');
+
+-- before visibility levels were introduced, any task could be opened by anyone who knows the link.
+alter table sandbox_tasks
+    add visibility task_visibility default 'unlisted' not null;
@@ -0,0 +1,24 @@
+create or replace function sandbox_notify_task_update() returns trigger as $$
+begin
+    if tg_op = 'DELETE' then
+        perform pg_notify('sandbox_task_updates', old.task_id);
+    else
+        perform pg_notify('sandbox_task_updates', new.task_id);
+    end if;
+
+    return null;
+end;
+$$ language plpgsql;
+
+-- lease renewals do not touch these columns, so they do not wake up task watchers.
+create trigger sandbox_tasks_notify_update
+    after insert or delete or update of status, params, visibility on sandbox_tasks
+    for each row execute function sandbox_notify_task_update();
+
+create trigger sandbox_task_assets_notify_update
+    after insert or delete on sandbox_task_assets
+    for each row execute function sandbox_notify_task_update();
+
+create trigger sandbox_chat_messages_notify_update
+    after insert or update or delete on sandbox_chat_messages
+    for each row execute function sandbox_notify_task_update();
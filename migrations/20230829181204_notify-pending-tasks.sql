@@ -0,0 +1,12 @@
+create or replace function sandbox_notify_pending_task() returns trigger as $$
+begin
+    perform pg_notify('sandbox_pending_tasks', new.task_id);
+    return null;
+end;
+$$ language plpgsql;
+
+-- wakes up idle worker sessions when there is a new task to run.
+create trigger sandbox_tasks_notify_pending
+    after insert or update of is_pending on sandbox_tasks
+    for each row when (new.is_pending)
+    execute function sandbox_notify_pending_task();
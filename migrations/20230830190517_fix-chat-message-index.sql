@@ -0,0 +1,11 @@
+-- every appended message used to get index 0, so order of existing conversations is restored from creation time.
+update sandbox_chat_messages messages
+set message_index = ordered.message_index
+from (
+    select message_id, (row_number() over (partition by task_id order by created_at, message_id) - 1)::int as message_index
+    from sandbox_chat_messages
+) ordered
+where messages.message_id = ordered.message_id;
+
+alter table sandbox_chat_messages
+    add constraint sandbox_chat_messages_task_id_message_index_key unique (task_id, message_index);
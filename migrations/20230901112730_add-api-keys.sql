@@ -0,0 +1,23 @@
+create type api_key_scope as enum('read_only', 'create_tasks');
+
+create table sandbox_api_keys
+(
+    key_id text not null
+        constraint sandbox_api_keys_pk
+            primary key,
+    user_id text not null,
+    name text not null,
+    scope api_key_scope not null,
+    -- first characters of the key, so that user can tell keys apart. Key itself is never stored.
+    key_prefix text not null,
+    key_hash text not null,
+    created_at timestamp with time zone default now() not null,
+    last_used_at timestamp with time zone,
+    revoked_at timestamp with time zone
+);
+
+create unique index sandbox_api_keys_key_hash_uindex
+    on sandbox_api_keys (key_hash);
+
+create index sandbox_api_keys_user_id_index
+    on sandbox_api_keys (user_id);
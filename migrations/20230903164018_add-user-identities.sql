@@ -0,0 +1,14 @@
+alter table sandbox_users add column auth_provider text;
+alter table sandbox_users add column auth_subject text;
+
+-- all existing users logged in with google. Their subject is not known yet, so it is filled in on next login.
+update sandbox_users set auth_provider = 'google';
+alter table sandbox_users alter column auth_provider set not null;
+
+drop index sandbox_users_email_uindex;
+
+create unique index sandbox_users_auth_identity_uindex
+    on sandbox_users (auth_provider, auth_subject);
+
+create index sandbox_users_email_index
+    on sandbox_users (email);
@@ -0,0 +1,3 @@
+-- local accounts are identified by username and do not have email.
+alter table sandbox_users alter column email drop not null;
+alter table sandbox_users add column password_hash text;
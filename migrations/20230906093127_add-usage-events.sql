@@ -0,0 +1,15 @@
+-- one row per generation request (new task or new chat reply), used to enforce quotas.
+create table sandbox_usage_events
+(
+    event_id   bigserial   not null
+        constraint sandbox_usage_events_pk
+            primary key,
+    -- "user:<user id>" or "ip:<address>" for anonymous requests
+    requester  text        not null,
+    task_id    text        not null,
+    tokens     bigint      not null,
+    created_at timestamptz not null default now()
+);
+
+create index sandbox_usage_events_requester_created_at_index
+    on sandbox_usage_events (requester, created_at);
@@ -0,0 +1 @@
+alter table sandbox_tasks add column capability_token_hash text;
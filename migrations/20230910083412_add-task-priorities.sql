@@ -0,0 +1,18 @@
+alter table sandbox_tasks add column priority integer not null default 0;
+
+create index sandbox_tasks_pending_created_at on sandbox_tasks (created_at) where is_pending = true;
+
+-- pending tasks in the order they are handed to workers: higher priority first, then one task per owner in each round
+-- (owners with tasks already running wait for the rounds they have used), then short chat tasks ahead of image batches,
+-- then oldest first. Tasks created without an account are their own owner.
+create view sandbox_task_queue as
+select
+    t.task_id,
+    t.params,
+    t.priority,
+    t.created_at,
+    t.params ? 'ChatMessageGeneration' as is_short,
+    row_number() over (partition by coalesce(t.user_id, t.task_id), t.params ? 'ChatMessageGeneration' order by t.created_at)
+        + (select count(*) from sandbox_tasks r where r.user_id = t.user_id and r.lease_worker_id is not null) as owner_turn
+from sandbox_tasks t
+where t.is_pending = true;
@@ -0,0 +1,4 @@
+-- image generation continues from this index when more images are requested for a finished task.
+alter table sandbox_tasks add column images_generated integer not null default 0;
+
+update sandbox_tasks t set images_generated = (select count(*) from sandbox_task_assets a where a.task_id = t.task_id);
@@ -0,0 +1,2 @@
+-- number of times the task was handed to a worker since it was created or last retried by the user.
+alter table sandbox_tasks add column attempts integer not null default 0;
@@ -0,0 +1,11 @@
+-- prompts and chat messages are indexed separately, a task matches the search if either of them does.
+alter table sandbox_tasks
+    add column search_vector tsvector
+        generated always as (to_tsvector('english', coalesce(params->'ImageGeneration'->>'prompt', ''))) stored;
+
+create index sandbox_tasks_search_vector on sandbox_tasks using gin (search_vector);
+
+alter table sandbox_chat_messages
+    add column search_vector tsvector generated always as (to_tsvector('english', content)) stored;
+
+create index sandbox_chat_messages_search_vector on sandbox_chat_messages using gin (search_vector);
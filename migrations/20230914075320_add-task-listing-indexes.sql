@@ -0,0 +1,9 @@
+-- task history is listed per user, newest first, with (created_at, task_id) as the page cursor.
+create index sandbox_tasks_user_id_created_at on sandbox_tasks (user_id, created_at desc, task_id desc);
+
+-- replaces partial index on pending tasks, also covers metrics which count tasks which are not pending.
+drop index sandbox_tasks_pending_created_at;
+create index sandbox_tasks_is_pending_created_at on sandbox_tasks (is_pending, created_at);
+
+-- primary key starts with asset_id, so it cannot be used to find assets of a task.
+create index sandbox_task_assets_task_id on sandbox_task_assets (task_id, created_at);
@@ -0,0 +1,54 @@
+create type task_status as enum('pending', 'in_progress', 'finished', 'cancelled', 'failed');
+
+-- jsonb status is still written by the server (and by instances not yet updated), columns below are derived from it
+-- by the trigger and are what queries read. Once all instances are updated, jsonb status can be dropped.
+alter table sandbox_tasks
+    add column status_kind task_status,
+    add column progress jsonb,
+    add column failure_reason text,
+    add column failure_retryable boolean,
+    add column worker_id text,
+    add column started_at timestamp with time zone,
+    add column finished_at timestamp with time zone;
+
+create function sandbox_task_status_kind(status jsonb) returns task_status as $$
+    select case
+        when status ? 'Pending' then 'pending'
+        when status ? 'InProgress' then 'in_progress'
+        when status ? 'Finished' then 'finished'
+        when status ? 'Cancelled' then 'cancelled'
+        when status ? 'Failed' then 'failed'
+    end::task_status;
+$$ language sql immutable;
+
+-- start and finish time of existing tasks is unknown, so they are left empty.
+update sandbox_tasks
+set
+    status_kind = sandbox_task_status_kind(status),
+    progress = status->'InProgress',
+    failure_reason = status->'Failed'->>'reason',
+    failure_retryable = (status->'Failed'->>'retryable')::boolean,
+    worker_id = lease_worker_id;
+
+alter table sandbox_tasks alter column status_kind set not null;
+
+create function sandbox_sync_task_status() returns trigger as $$
+begin
+    new.status_kind := sandbox_task_status_kind(new.status);
+    new.progress := new.status->'InProgress';
+    new.failure_reason := new.status->'Failed'->>'reason';
+    new.failure_retryable := (new.status->'Failed'->>'retryable')::boolean;
+
+    if tg_op = 'INSERT' or new.status_kind is distinct from old.status_kind then
+        new.finished_at := case when new.status_kind in ('finished', 'cancelled', 'failed') then now() end;
+    end if;
+
+    return new;
+end;
+$$ language plpgsql;
+
+create trigger sandbox_tasks_sync_status
+    before insert or update of status on sandbox_tasks
+    for each row execute function sandbox_sync_task_status();
+
+create index sandbox_tasks_status_kind_finished_at on sandbox_tasks (status_kind, finished_at);
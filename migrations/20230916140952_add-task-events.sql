@@ -0,0 +1,69 @@
+create type task_event_kind as enum('created', 'claimed', 'progress', 'asset_created', 'requeued', 'finished', 'cancelled', 'failed');
+
+create table sandbox_task_events
+(
+    event_id   bigserial                              primary key,
+    task_id    text                                   not null
+        references sandbox_tasks (task_id) on delete cascade,
+    kind       task_event_kind                        not null,
+    worker_id  text,
+    details    jsonb,
+    created_at timestamp with time zone default now() not null
+);
+
+create index sandbox_task_events_task_id on sandbox_task_events (task_id, event_id);
+
+-- events are recorded by triggers, so that every way a task changes state (including lease expiry and retries) is covered.
+-- Progress is recorded only when task starts running and when it starts generating next image, not on every step.
+create function sandbox_record_task_event() returns trigger as $$
+begin
+    if tg_op = 'INSERT' then
+        insert into sandbox_task_events (task_id, kind) values (new.task_id, 'created');
+        return null;
+    end if;
+
+    if old.lease_worker_id is null and new.lease_worker_id is not null then
+        insert into sandbox_task_events (task_id, kind, worker_id, details)
+        values (new.task_id, 'claimed', new.lease_worker_id, jsonb_build_object('attempt', new.attempts));
+    end if;
+
+    if new.is_pending and not old.is_pending then
+        insert into sandbox_task_events (task_id, kind) values (new.task_id, 'requeued');
+    end if;
+
+    if new.status_kind = 'in_progress' and (old.status_kind <> 'in_progress' or new.progress->'current_image' is distinct from old.progress->'current_image') then
+        insert into sandbox_task_events (task_id, kind, worker_id, details) values (new.task_id, 'progress', new.lease_worker_id, new.progress);
+    end if;
+
+    if new.status_kind <> old.status_kind and new.status_kind in ('finished', 'cancelled', 'failed') then
+        insert into sandbox_task_events (task_id, kind, worker_id, details)
+        values (
+            new.task_id,
+            new.status_kind::text::task_event_kind,
+            coalesce(new.lease_worker_id, old.lease_worker_id),
+            case when new.status_kind = 'failed' then jsonb_build_object('reason', new.failure_reason, 'retryable', new.failure_retryable) end
+        );
+    end if;
+
+    return null;
+end;
+$$ language plpgsql;
+
+-- lease renewals do not touch these columns.
+create trigger sandbox_tasks_record_event
+    after insert or update of status, is_pending, lease_worker_id on sandbox_tasks
+    for each row execute function sandbox_record_task_event();
+
+create function sandbox_record_task_asset_event() returns trigger as $$
+begin
+    insert into sandbox_task_events (task_id, kind, details) values (new.task_id, 'asset_created', jsonb_build_object('asset_id', new.asset_id));
+    return null;
+end;
+$$ language plpgsql;
+
+create trigger sandbox_task_assets_record_event
+    after insert on sandbox_task_assets
+    for each row execute function sandbox_record_task_asset_event();
+
+-- phase durations for metrics are computed from events recorded since the previous collection.
+create index sandbox_task_events_kind on sandbox_task_events (kind, event_id);
@@ -0,0 +1,12 @@
+-- kind is the only key of task params. It is kept in a column, so that task history can be filtered by kind using an index.
+alter table sandbox_tasks
+    add column kind text generated always as (
+        case
+            when params ? 'ImageGeneration' then 'ImageGeneration'
+            when params ? 'ChatMessageGeneration' then 'ChatMessageGeneration'
+        end
+    ) stored;
+
+-- filtered task history keeps the (created_at, task_id) order of the page cursor.
+create index sandbox_tasks_user_id_kind_created_at on sandbox_tasks (user_id, kind, created_at desc, task_id desc);
+create index sandbox_tasks_user_id_status_kind_created_at on sandbox_tasks (user_id, status_kind, created_at desc, task_id desc);
@@ -0,0 +1,245 @@
+use {
+    tonic::Status,
+    rand::distributions::{Alphanumeric, Distribution},
+    sha2::{Sha256, Digest},
+    crate::entities::{Task, TaskVisibility},
+};
+
+// tasks created without an account are controlled by whoever holds the token returned on creation.
+pub const TASK_TOKEN_HEADER: &str = "x-task-token";
+const TASK_TOKEN_LENGTH: usize = 40;
+
+/// Who is making the request: logged in user (if any) and capability token of the task (if any).
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    pub user_id: Option<String>,
+    pub task_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskOperation {
+    GetTask,
+    WatchTask,
+    GetTaskEvents,
+    GetAsset,
+    AddChatUserMessage,
+    CancelTask,
+    ExtendTask,
+    RetryTask,
+    DeleteTask,
+    DeleteTaskAsset,
+    SetTaskVisibility,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    View,
+    Modify,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AccessError {
+    // task is not visible to the caller, so its existence is not revealed.
+    NotFound,
+    PermissionDenied,
+}
+
+impl Caller {
+    pub fn new(user_id: Option<String>, task_token: Option<String>) -> Self {
+        Self {
+            user_id,
+            task_token,
+        }
+    }
+
+    pub fn is_owner(&self, task: &Task) -> bool {
+        let is_owning_user = self.user_id.is_some() && task.user_id == self.user_id;
+        let has_task_token = match (self.task_token.as_ref(), task.capability_token_hash.as_ref()) {
+            (Some(token), Some(hash)) => constant_time_eq(hash_task_token(token).as_bytes(), hash.as_bytes()),
+            _ => false,
+        };
+
+        is_owning_user || has_task_token
+    }
+
+    pub fn can_view(&self, task: &Task) -> bool {
+        match task.visibility {
+            TaskVisibility::Private => self.is_owner(task),
+            TaskVisibility::Unlisted | TaskVisibility::Public => true,
+        }
+    }
+}
+
+impl TaskOperation {
+    pub fn required_permission(&self) -> Permission {
+        match self {
+            Self::GetTask | Self::WatchTask | Self::GetTaskEvents | Self::GetAsset => Permission::View,
+            Self::AddChatUserMessage | Self::CancelTask | Self::ExtendTask | Self::RetryTask | Self::DeleteTask | Self::DeleteTaskAsset | Self::SetTaskVisibility => Permission::Modify,
+        }
+    }
+}
+
+impl From<AccessError> for Status {
+    fn from(err: AccessError) -> Self {
+        match err {
+            AccessError::NotFound => Status::not_found("task_not_found"),
+            AccessError::PermissionDenied => Status::permission_denied("not_task_owner"),
+        }
+    }
+}
+
+/// Single place where access to tasks is decided, every user-facing rpc goes through it.
+pub fn authorize(task: &Task, caller: &Caller, operation: TaskOperation) -> Result<(), AccessError> {
+    if !caller.can_view(task) {
+        return Err(AccessError::NotFound);
+    }
+
+    match operation.required_permission() {
+        Permission::View => Ok(()),
+        Permission::Modify if caller.is_owner(task) => Ok(()),
+        Permission::Modify => Err(AccessError::PermissionDenied),
+    }
+}
+
+/// Returns token to give to the client and its hash to store.
+pub fn generate_task_token() -> (String, String) {
+    let mut rng = rand::thread_rng();
+    let token: String = Alphanumeric.sample_iter(&mut rng)
+        .take(TASK_TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+    let hash = hash_task_token(&token);
+
+    (token, hash)
+}
+
+fn hash_task_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        chrono::Utc,
+        crate::entities::{TaskId, TaskStatus, TaskParams},
+        super::*,
+    };
+
+    const ALL_OPERATIONS: [TaskOperation; 11] = [
+        TaskOperation::GetTask,
+        TaskOperation::WatchTask,
+        TaskOperation::GetTaskEvents,
+        TaskOperation::GetAsset,
+        TaskOperation::AddChatUserMessage,
+        TaskOperation::CancelTask,
+        TaskOperation::ExtendTask,
+        TaskOperation::RetryTask,
+        TaskOperation::DeleteTask,
+        TaskOperation::DeleteTaskAsset,
+        TaskOperation::SetTaskVisibility,
+    ];
+
+    #[test]
+    fn owner_is_allowed_everything() {
+        for visibility in [TaskVisibility::Private, TaskVisibility::Unlisted, TaskVisibility::Public] {
+            let task = user_task("alice", visibility);
+            for operation in ALL_OPERATIONS {
+                assert_eq!(Ok(()), authorize(&task, &user("alice"), operation), "{:?}", operation);
+            }
+        }
+    }
+
+    #[test]
+    fn other_user_cannot_see_private_task() {
+        let task = user_task("alice", TaskVisibility::Private);
+        for caller in [user("bob"), anonymous(), Caller::new(None, Some("guessed-token".to_owned()))] {
+            for operation in ALL_OPERATIONS {
+                assert_eq!(Err(AccessError::NotFound), authorize(&task, &caller, operation), "{:?}", operation);
+            }
+        }
+    }
+
+    #[test]
+    fn other_user_can_only_view_shared_task() {
+        for visibility in [TaskVisibility::Unlisted, TaskVisibility::Public] {
+            let task = user_task("alice", visibility);
+            for caller in [user("bob"), anonymous()] {
+                for operation in ALL_OPERATIONS {
+                    let expected = match operation.required_permission() {
+                        Permission::View => Ok(()),
+                        Permission::Modify => Err(AccessError::PermissionDenied),
+                    };
+                    assert_eq!(expected, authorize(&task, &caller, operation), "{:?}", operation);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn anonymous_task_is_controlled_by_token() {
+        let (token, task) = anonymous_task();
+        for operation in ALL_OPERATIONS {
+            assert_eq!(Ok(()), authorize(&task, &Caller::new(None, Some(token.clone())), operation), "{:?}", operation);
+            // token is not tied to an account, so it works for logged in users as well.
+            assert_eq!(Ok(()), authorize(&task, &Caller::new(Some("bob".to_owned()), Some(token.clone())), operation), "{:?}", operation);
+        }
+    }
+
+    #[test]
+    fn anonymous_task_cannot_be_modified_without_token() {
+        let (_, task) = anonymous_task();
+        let (other_token, _) = generate_task_token();
+
+        for caller in [anonymous(), user("bob"), Caller::new(None, Some(other_token))] {
+            assert!(!caller.is_owner(&task));
+            for operation in ALL_OPERATIONS {
+                let expected = match operation.required_permission() {
+                    Permission::View => Ok(()),
+                    Permission::Modify => Err(AccessError::PermissionDenied),
+                };
+                assert_eq!(expected, authorize(&task, &caller, operation), "{:?}", operation);
+            }
+        }
+    }
+
+    #[test]
+    fn task_token_does_not_grant_access_to_user_tasks() {
+        let (token, _) = anonymous_task();
+        let task = user_task("alice", TaskVisibility::Private);
+
+        assert_eq!(Err(AccessError::NotFound), authorize(&task, &Caller::new(None, Some(token)), TaskOperation::GetTask));
+    }
+
+    fn user(id: &str) -> Caller {
+        Caller::new(Some(id.to_owned()), None)
+    }
+
+    fn anonymous() -> Caller {
+        Caller::default()
+    }
+
+    fn user_task(owner: &str, visibility: TaskVisibility) -> Task {
+        task(Some(owner.to_owned()), None, visibility)
+    }
+
+    fn anonymous_task() -> (String, Task) {
+        let (token, hash) = generate_task_token();
+        (token, task(None, Some(hash), TaskVisibility::Unlisted))
+    }
+
+    fn task(user_id: Option<String>, capability_token_hash: Option<String>, visibility: TaskVisibility) -> Task {
+        Task {
+            id: TaskId::new("task".to_owned()),
+            user_id,
+            capability_token_hash,
+            status: TaskStatus::Finished,
+            created_at: Utc::now(),
+            params: TaskParams::ChatMessageGenerationParams {},
+            visibility,
+        }
+    }
+}
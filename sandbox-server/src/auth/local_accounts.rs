@@ -0,0 +1,111 @@
+use {
+    std::{io::{stdin, BufRead}, sync::OnceLock},
+    tracing::{info, error},
+    tokio::task::spawn_blocking,
+    config::Config,
+    argon2::{Argon2, PasswordHasher, PasswordVerifier, password_hash::{SaltString, PasswordHash, rand_core::OsRng}},
+    crate::state::database::Database,
+};
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+// password of unknown users is checked against this hash, so that login takes the same time whether user exists or not.
+static DUMMY_PASSWORD_HASH: OnceLock<String> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalAccountsConfig {
+    pub enabled: bool,
+    pub registration_enabled: bool,
+}
+
+impl LocalAccountsConfig {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            enabled: config.get_bool("auth.local_accounts.enabled").unwrap_or(false),
+            registration_enabled: config.get_bool("auth.local_accounts.registration_enabled").unwrap_or(false),
+        }
+    }
+
+    pub fn is_registration_allowed(&self) -> bool {
+        self.enabled && self.registration_enabled
+    }
+}
+
+// usernames are case insensitive, so they are stored in lowercase.
+pub fn normalize_username(username: &str) -> Option<String> {
+    let username = username.trim().to_lowercase();
+    let is_valid = (3..=32).contains(&username.len())
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+
+    if is_valid {
+        Some(username)
+    } else {
+        None
+    }
+}
+
+pub fn is_password_valid(password: &str) -> bool {
+    (MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&password.chars().count())
+}
+
+// hashing is slow on purpose, so it is moved off the async runtime.
+pub async fn hash_password(password: String) -> String {
+    spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string()
+    }).await.unwrap()
+}
+
+pub async fn verify_password(password: String, password_hash: String) -> bool {
+    spawn_blocking(move || {
+        let password_hash = match PasswordHash::new(&password_hash) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("failed to parse password hash: {:?}", err);
+                return false;
+            }
+        };
+
+        Argon2::default().verify_password(password.as_bytes(), &password_hash).is_ok()
+    }).await.unwrap()
+}
+
+pub async fn verify_dummy_password(password: String) {
+    spawn_blocking(move || {
+        let password_hash = DUMMY_PASSWORD_HASH.get_or_init(|| {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default().hash_password(b"dummy password", &salt).unwrap().to_string()
+        });
+
+        let _ = Argon2::default().verify_password(password.as_bytes(), &PasswordHash::new(password_hash).unwrap());
+    }).await.unwrap()
+}
+
+// admin command, password is read from stdin so that it does not end up in shell history.
+pub async fn run_create_user_command(config: &Config, username: Option<&str>) {
+    let username = match username.and_then(normalize_username) {
+        Some(v) => v,
+        None => {
+            error!("usage: sandbox-server create-user <username>, username should be 3-32 characters long and contain only letters, digits, '_', '-' or '.'");
+            return;
+        }
+    };
+
+    info!("enter password for {}:", username);
+    let mut password = String::new();
+    stdin().lock().read_line(&mut password).unwrap();
+    let password = password.trim_end_matches(['\r', '\n']).to_owned();
+
+    if !is_password_valid(&password) {
+        error!("password should be {}-{} characters long", MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH);
+        return;
+    }
+
+    let database = Database::new(config, &config.get_string("database.connection_string").unwrap()).await.unwrap();
+    match database.create_local_user(&username, &hash_password(password).await).await {
+        Ok(Some(user_id)) => info!("created user {} with id {}", username, user_id.to_string()),
+        Ok(None) => error!("user {} already exists", username),
+        Err(err) => error!("failed to create user {}: {}", username, err),
+    }
+}
@@ -0,0 +1,152 @@
+use {
+    std::{sync::Arc, collections::HashSet, pin::Pin, future::Future, task::{Context, Poll}},
+    tracing::warn,
+    tonic::{Status, body::BoxBody, server::NamedService},
+    tower::{Layer, Service},
+    jsonwebtoken::DecodingKey,
+    crate::{
+        access::constant_time_eq,
+        handlers::{TokenDecodeResult, API_KEY_PREFIX, decode_token, hash_api_key},
+        state::database::Database,
+    },
+    super::policy::{Principal, Credential, Policy, policy_for_method},
+};
+
+pub const ACCESS_TOKEN_HEADER: &str = "x-access-token";
+pub const WORKER_ID_HEADER: &str = "x-worker-id";
+
+/// Resolves access token of the request into a principal: session token, api key or worker token.
+pub struct Authenticator {
+    database: Arc<Database>,
+    decoding_key: DecodingKey,
+    worker_token: String,
+    admins: HashSet<String>,
+}
+
+impl Authenticator {
+    pub fn new(database: Arc<Database>, decoding_key: DecodingKey, worker_token: String, admins: Vec<String>) -> Self {
+        Self {
+            database,
+            decoding_key,
+            worker_token,
+            admins: admins.into_iter().collect(),
+        }
+    }
+
+    pub async fn authenticate(&self, headers: &http::HeaderMap) -> Result<Principal, Status> {
+        let token = match headers.get(ACCESS_TOKEN_HEADER).and_then(|v| v.to_str().ok()) {
+            Some(v) => v,
+            None => return Ok(Principal::Anonymous),
+        };
+
+        if constant_time_eq(token.as_bytes(), self.worker_token.as_bytes()) {
+            return match headers.get(WORKER_ID_HEADER).and_then(|v| v.to_str().ok()) {
+                Some(v) => Ok(Principal::Worker { id: v.to_owned() }),
+                None => Err(Status::invalid_argument("missing_worker_id")),
+            };
+        }
+
+        let (id, credential) = if token.starts_with(API_KEY_PREFIX) {
+            match self.database.use_api_key(&hash_api_key(token)).await? {
+                Some((user_id, scope)) => (user_id, Credential::ApiKey(scope)),
+                None => return Err(Status::unauthenticated("invalid_api_key")),
+            }
+        } else {
+            match decode_token(&self.decoding_key, token) {
+                TokenDecodeResult::Token(v) => (v, Credential::Session),
+                TokenDecodeResult::TokenExpired => return Err(Status::unauthenticated("token expired")),
+                TokenDecodeResult::DecodeError(err) => {
+                    warn!("error while decoding token: {:?}", err);
+                    return Err(Status::unauthenticated("invalid_token"));
+                },
+                TokenDecodeResult::InvalidApiKey | TokenDecodeResult::InsufficientScope => return Err(Status::unauthenticated("invalid_token")),
+            }
+        };
+
+        Ok(if self.admins.contains(&id) {
+            Principal::Admin { id, credential }
+        } else {
+            Principal::User { id, credential }
+        })
+    }
+}
+
+/// Authenticates every rpc once and enforces the policy of the called method before it reaches the handler.
+#[derive(Clone)]
+pub struct AuthLayer {
+    authenticator: Arc<Authenticator>,
+}
+
+impl AuthLayer {
+    pub fn new(authenticator: Arc<Authenticator>) -> Self {
+        Self {
+            authenticator,
+        }
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthService {
+            inner,
+            authenticator: self.authenticator.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthService<S> {
+    inner: S,
+    authenticator: Arc<Authenticator>,
+}
+
+impl<S: NamedService> NamedService for AuthService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for AuthService<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        // the service which was polled ready is the one to be called, clone takes its place.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let authenticator = self.authenticator.clone();
+
+        Box::pin(async move {
+            let method = req.uri().path().rsplit('/').next().unwrap_or_default();
+            let policy = match policy_for_method(method) {
+                Some(v) => v,
+                None => return Ok(Status::unimplemented("unknown_method").to_http()),
+            };
+
+            let principal = match authenticator.authenticate(req.headers()).await {
+                Ok(v) => v,
+                // stale token should not prevent user from logging in again.
+                Err(_) if policy == Policy::Public => Principal::Anonymous,
+                Err(status) => return Ok(status.to_http()),
+            };
+
+            if let Err(status) = policy.check(&principal) {
+                return Ok(status.to_http());
+            }
+
+            req.extensions_mut().insert(principal);
+            inner.call(req).await
+        })
+    }
+}
@@ -0,0 +1,61 @@
+use {
+    std::{collections::HashMap, net::TcpListener},
+    axum::{Router, Json, Form, routing::{get, post}, http::{StatusCode, HeaderMap}},
+    serde_json::{json, Value},
+};
+
+pub const MOCK_AUTHORIZATION_CODE: &str = "mock-authorization-code";
+const MOCK_ACCESS_TOKEN: &str = "mock-access-token";
+
+// minimal oidc provider which accepts a single authorization code and returns the given identity. Returns issuer url.
+pub async fn run_mock_oidc_server(subject: &str, email: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+    let discovery = json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "userinfo_endpoint": format!("{}/userinfo", issuer),
+    });
+    let user_info = json!({
+        "sub": subject,
+        "email": email,
+        "name": "Test User",
+    });
+
+    let app = Router::new()
+        .route("/.well-known/openid-configuration", get(move || {
+            let discovery = discovery.clone();
+            async move { Json(discovery) }
+        }))
+        .route("/token", post(token))
+        .route("/userinfo", get(move |headers: HeaderMap| {
+            let user_info = user_info.clone();
+            async move { userinfo(headers, user_info) }
+        }));
+
+    tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+
+    issuer
+}
+
+async fn token(Form(form): Form<HashMap<String, String>>) -> Result<Json<Value>, StatusCode> {
+    if form.get("grant_type").map(|v| v.as_str()) != Some("authorization_code") || form.get("code").map(|v| v.as_str()) != Some(MOCK_AUTHORIZATION_CODE) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(Json(json!({
+        "access_token": MOCK_ACCESS_TOKEN,
+        "token_type": "Bearer",
+    })))
+}
+
+fn userinfo(headers: HeaderMap, user_info: Value) -> Result<Json<Value>, StatusCode> {
+    let expected = format!("Bearer {}", MOCK_ACCESS_TOKEN);
+    if headers.get("authorization").and_then(|v| v.to_str().ok()) != Some(expected.as_str()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(Json(user_info))
+}
@@ -0,0 +1,363 @@
+use {
+    std::collections::HashSet,
+    tracing::info,
+    serde::Deserialize,
+    anyhow::{Result, Context, anyhow, bail},
+    config::{Config, ConfigError},
+    reqwest::header::ACCEPT,
+    self::local_accounts::LocalAccountsConfig,
+};
+
+pub mod local_accounts;
+pub mod middleware;
+pub mod policy;
+#[cfg(test)]
+pub(crate) mod mock_oidc;
+
+// users registered with username and password are stored with this provider id.
+pub const LOCAL_AUTH_PROVIDER: &str = "local";
+
+// used by deployments which were configured before identity providers became configurable.
+const LEGACY_GOOGLE_CLIENT_ID: &str = "916750455653-biu6q4c7llj7q1k14h3qaquktcdlkeo4.apps.googleusercontent.com";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthProviderKind {
+    Oidc,
+    Github,
+    Google,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuthProviderConfig {
+    pub id: String,
+    pub name: Option<String>,
+    pub kind: AuthProviderKind,
+    pub client_id: String,
+    pub client_secret: String,
+    // required for oidc providers, endpoints are discovered from it.
+    pub issuer: Option<String>,
+    pub scopes: Option<Vec<String>>,
+}
+
+pub struct AuthProvider {
+    pub id: String,
+    pub name: String,
+    pub kind: AuthProviderKind,
+    pub client_id: String,
+    client_secret: String,
+    pub authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    pub scopes: Vec<String>,
+}
+
+pub struct ExternalIdentity {
+    pub subject: String,
+    pub email: String,
+    pub name: String,
+}
+
+pub struct AuthProviders {
+    client: reqwest::Client,
+    providers: Vec<AuthProvider>,
+    pub local_accounts: LocalAccountsConfig,
+}
+
+#[derive(Deserialize)]
+struct OidcDiscoveryDocument {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct CodeExchangeResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct OidcUserInfo {
+    sub: String,
+    email: Option<String>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubUser {
+    id: u64,
+    login: String,
+    name: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+impl AuthProviders {
+    pub async fn from_config(config: &Config) -> Result<Self> {
+        let provider_configs: Vec<AuthProviderConfig> = match config.get("auth.providers") {
+            Ok(v) => v,
+            Err(ConfigError::NotFound(_)) => legacy_provider_configs(config)?,
+            Err(err) => return Err(err.into()),
+        };
+
+        Self::from_provider_configs(provider_configs, LocalAccountsConfig::from_config(config)).await
+    }
+
+    pub async fn from_provider_configs(provider_configs: Vec<AuthProviderConfig>, local_accounts: LocalAccountsConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            // github api rejects requests without user agent.
+            .user_agent("sandbox")
+            .build()?;
+
+        let mut ids = HashSet::new();
+        let mut providers = Vec::new();
+        for provider_config in provider_configs {
+            if provider_config.id == LOCAL_AUTH_PROVIDER {
+                bail!("auth provider id {} is reserved for local accounts", LOCAL_AUTH_PROVIDER);
+            }
+
+            if !ids.insert(provider_config.id.clone()) {
+                bail!("auth provider {} is configured more than once", provider_config.id);
+            }
+
+            let provider = AuthProvider::from_config(&client, provider_config).await?;
+            info!("using auth provider {} ({:?})", provider.id, provider.kind);
+            providers.push(provider);
+        }
+
+        if local_accounts.enabled {
+            info!("local accounts are enabled (registration enabled: {})", local_accounts.registration_enabled);
+        }
+
+        Ok(Self {
+            client,
+            providers,
+            local_accounts,
+        })
+    }
+
+    pub fn providers(&self) -> &[AuthProvider] {
+        &self.providers
+    }
+
+    pub fn provider(&self, id: &str) -> Option<&AuthProvider> {
+        self.providers.iter().find(|v| v.id == id)
+    }
+
+    pub async fn identity_from_code(&self, provider: &AuthProvider, code: &str, redirect_uri: &str) -> Result<ExternalIdentity> {
+        let res: CodeExchangeResponse = self.client
+            .post(&provider.token_endpoint)
+            // github responds with form encoded body unless json is requested explicitly.
+            .header(ACCEPT, "application/json")
+            .form(&[
+                ("client_id", provider.client_id.as_str()),
+                ("client_secret", provider.client_secret.as_str()),
+                ("code", code),
+                ("grant_type", "authorization_code"),
+                ("redirect_uri", redirect_uri),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("failed to get code exchange response")?;
+
+        match provider.kind {
+            AuthProviderKind::Github => self.github_identity(provider, &res.access_token).await,
+            AuthProviderKind::Oidc | AuthProviderKind::Google => self.oidc_identity(provider, &res.access_token).await,
+        }
+    }
+
+    async fn oidc_identity(&self, provider: &AuthProvider, access_token: &str) -> Result<ExternalIdentity> {
+        let user_info: OidcUserInfo = self.client
+            .get(&provider.userinfo_endpoint)
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("failed to get user info response")?;
+
+        let email = user_info.email.ok_or_else(|| anyhow!("auth provider {} did not return email", provider.id))?;
+
+        Ok(ExternalIdentity {
+            subject: user_info.sub,
+            name: user_info.name.or(user_info.preferred_username).unwrap_or(email.clone()),
+            email,
+        })
+    }
+
+    async fn github_identity(&self, provider: &AuthProvider, access_token: &str) -> Result<ExternalIdentity> {
+        let user: GithubUser = self.client
+            .get(&provider.userinfo_endpoint)
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("failed to get github user")?;
+
+        // public email is not set for most of the users, so primary one is requested separately.
+        let email = match user.email {
+            Some(v) => v,
+            None => self.client
+                .get(format!("{}/emails", provider.userinfo_endpoint))
+                .bearer_auth(access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Vec<GithubEmail>>()
+                .await
+                .context("failed to get github user emails")?
+                .into_iter()
+                .find(|v| v.primary && v.verified)
+                .map(|v| v.email)
+                .ok_or_else(|| anyhow!("github user {} has no verified primary email", user.login))?,
+        };
+
+        Ok(ExternalIdentity {
+            subject: user.id.to_string(),
+            name: user.name.unwrap_or(user.login),
+            email,
+        })
+    }
+}
+
+impl AuthProvider {
+    async fn from_config(client: &reqwest::Client, config: AuthProviderConfig) -> Result<Self> {
+        let (authorization_endpoint, token_endpoint, userinfo_endpoint, default_scopes, default_name) = match config.kind {
+            AuthProviderKind::Google => (
+                "https://accounts.google.com/o/oauth2/v2/auth".to_owned(),
+                "https://oauth2.googleapis.com/token".to_owned(),
+                "https://openidconnect.googleapis.com/v1/userinfo".to_owned(),
+                vec!["openid", "email", "profile"],
+                "Google",
+            ),
+            AuthProviderKind::Github => (
+                "https://github.com/login/oauth/authorize".to_owned(),
+                "https://github.com/login/oauth/access_token".to_owned(),
+                "https://api.github.com/user".to_owned(),
+                vec!["read:user", "user:email"],
+                "GitHub",
+            ),
+            AuthProviderKind::Oidc => {
+                let issuer = config.issuer.as_ref().ok_or_else(|| anyhow!("issuer is required for oidc auth provider {}", config.id))?;
+                let discovery = discover_oidc_endpoints(client, issuer).await
+                    .with_context(|| format!("failed to discover endpoints of auth provider {}", config.id))?;
+
+                (
+                    discovery.authorization_endpoint,
+                    discovery.token_endpoint,
+                    discovery.userinfo_endpoint,
+                    vec!["openid", "email", "profile"],
+                    config.id.as_str(),
+                )
+            },
+        };
+
+        Ok(Self {
+            name: config.name.unwrap_or(default_name.to_owned()),
+            id: config.id,
+            kind: config.kind,
+            client_id: config.client_id,
+            client_secret: config.client_secret,
+            authorization_endpoint,
+            token_endpoint,
+            userinfo_endpoint,
+            scopes: config.scopes.unwrap_or(default_scopes.into_iter().map(|v| v.to_owned()).collect()),
+        })
+    }
+}
+
+async fn discover_oidc_endpoints(client: &reqwest::Client, issuer: &str) -> Result<OidcDiscoveryDocument> {
+    Ok(client
+        .get(format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/')))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+fn legacy_provider_configs(config: &Config) -> Result<Vec<AuthProviderConfig>> {
+    let client_secret: String = match config.get("auth.oauth_client_secret") {
+        Ok(v) => v,
+        Err(ConfigError::NotFound(_)) => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(vec![AuthProviderConfig {
+        id: "google".to_owned(),
+        name: None,
+        kind: AuthProviderKind::Google,
+        client_id: config.get("auth.oauth_client_id").unwrap_or(LEGACY_GOOGLE_CLIENT_ID.to_owned()),
+        client_secret,
+        issuer: None,
+        scopes: None,
+    }])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{*, mock_oidc::{run_mock_oidc_server, MOCK_AUTHORIZATION_CODE}};
+
+    #[tokio::test]
+    async fn oidc_provider_is_discovered_and_code_is_exchanged() {
+        let issuer = run_mock_oidc_server("subject-1", "user@example.com").await;
+
+        let providers = AuthProviders::from_provider_configs(vec![oidc_provider_config(&issuer)], local_accounts_disabled()).await.unwrap();
+        let provider = providers.provider("mock").unwrap();
+        assert_eq!(format!("{}/authorize", issuer), provider.authorization_endpoint);
+        assert_eq!(vec!["openid", "email", "profile"], provider.scopes);
+
+        let identity = providers.identity_from_code(provider, MOCK_AUTHORIZATION_CODE, "http://localhost/login").await.unwrap();
+        assert_eq!("subject-1", identity.subject);
+        assert_eq!("user@example.com", identity.email);
+    }
+
+    #[tokio::test]
+    async fn invalid_code_is_rejected() {
+        let issuer = run_mock_oidc_server("subject-1", "user@example.com").await;
+
+        let providers = AuthProviders::from_provider_configs(vec![oidc_provider_config(&issuer)], local_accounts_disabled()).await.unwrap();
+        let provider = providers.provider("mock").unwrap();
+
+        assert!(providers.identity_from_code(provider, "wrong-code", "http://localhost/login").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn duplicate_provider_ids_are_rejected() {
+        let issuer = run_mock_oidc_server("subject-1", "user@example.com").await;
+
+        assert!(AuthProviders::from_provider_configs(vec![oidc_provider_config(&issuer), oidc_provider_config(&issuer)], local_accounts_disabled()).await.is_err());
+    }
+
+    fn local_accounts_disabled() -> LocalAccountsConfig {
+        LocalAccountsConfig {
+            enabled: false,
+            registration_enabled: false,
+        }
+    }
+
+    fn oidc_provider_config(issuer: &str) -> AuthProviderConfig {
+        AuthProviderConfig {
+            id: "mock".to_owned(),
+            name: Some("Mock".to_owned()),
+            kind: AuthProviderKind::Oidc,
+            client_id: "client-id".to_owned(),
+            client_secret: "client-secret".to_owned(),
+            issuer: Some(issuer.to_owned()),
+            scopes: None,
+        }
+    }
+}
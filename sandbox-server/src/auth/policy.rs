@@ -0,0 +1,212 @@
+use {
+    tonic::Status,
+    crate::entities::ApiKeyScope,
+};
+
+/// Who is making the request, resolved once by the auth layer and attached to request extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    Anonymous,
+    User { id: String, credential: Credential },
+    Admin { id: String, credential: Credential },
+    Worker { id: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Credential {
+    Session,
+    ApiKey(ApiKeyScope),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    // login and other rpcs which are called before the user has a token.
+    Public,
+    // anonymous users are allowed as well, api keys need the given scope.
+    AnyUser(ApiKeyScope),
+    User(ApiKeyScope),
+    // api keys cannot be used to manage api keys or passwords, so that leaked key cannot be used to take over the account.
+    SessionUser,
+    Admin,
+    Worker,
+}
+
+impl Principal {
+    pub fn user_id(&self) -> Option<&str> {
+        match self {
+            Self::User { id, .. } | Self::Admin { id, .. } => Some(id),
+            Self::Anonymous | Self::Worker { .. } => None,
+        }
+    }
+
+    // priority is meant for automation and operators, web ui sessions of regular users cannot raise it.
+    pub fn can_set_task_priority(&self) -> bool {
+        matches!(self, Self::Admin { .. } | Self::User { credential: Credential::ApiKey(_), .. })
+    }
+
+    pub fn worker_id(&self) -> Option<&str> {
+        match self {
+            Self::Worker { id } => Some(id),
+            _ => None,
+        }
+    }
+}
+
+impl Policy {
+    pub fn check(&self, principal: &Principal) -> Result<(), Status> {
+        match (self, principal) {
+            (Self::Public, _) => Ok(()),
+
+            (Self::Worker, Principal::Worker { .. }) => Ok(()),
+            (Self::Worker, Principal::Anonymous) => Err(Status::unauthenticated("unauthenticated")),
+            (Self::Worker, _) => Err(Status::permission_denied("worker_only")),
+            (_, Principal::Worker { .. }) => Err(Status::permission_denied("workers_not_allowed")),
+
+            (Self::AnyUser(_), Principal::Anonymous) => Ok(()),
+            (_, Principal::Anonymous) => Err(Status::unauthenticated("unauthenticated")),
+
+            (Self::Admin, Principal::Admin { .. }) => Ok(()),
+            (Self::Admin, _) => Err(Status::permission_denied("admin_only")),
+
+            (Self::SessionUser, Principal::User { credential, .. } | Principal::Admin { credential, .. }) => match credential {
+                Credential::Session => Ok(()),
+                Credential::ApiKey(_) => Err(Status::permission_denied("api_key_not_allowed")),
+            },
+
+            (Self::AnyUser(scope) | Self::User(scope), Principal::User { credential, .. } | Principal::Admin { credential, .. }) => match credential {
+                Credential::Session => Ok(()),
+                Credential::ApiKey(key_scope) if key_scope.allows(*scope) => Ok(()),
+                Credential::ApiKey(_) => Err(Status::permission_denied("api_key_scope_insufficient")),
+            },
+        }
+    }
+}
+
+/// Policy for every rpc of the sandbox service. Rpcs missing here are rejected.
+pub fn policy_for_method(method: &str) -> Option<Policy> {
+    Some(match method {
+        "ListAuthProviders" | "OAuthLogin" | "PasswordLogin" | "RegisterLocalAccount" => Policy::Public,
+
+        "GetTask" | "WatchTask" | "GetTaskEvents" => Policy::AnyUser(ApiKeyScope::ReadOnly),
+        "CreateTask" | "AddChatUserMessage" | "CancelTask" | "ExtendTask" | "RetryTask" | "DeleteTask" | "DeleteTaskAsset" | "SetTaskVisibility" => Policy::AnyUser(ApiKeyScope::CreateTasks),
+
+        "GetAllTasks" => Policy::User(ApiKeyScope::ReadOnly),
+
+        "ChangePassword" | "CreateApiKey" | "ListApiKeys" | "RevokeApiKey" => Policy::SessionUser,
+
+        "ListWorkers" => Policy::Admin,
+
+        "WorkerSession" | "RegisterWorker" | "GetTaskToRun" | "CreateTaskAsset" | "GetChatMessages" | "AddChatAssistantMessage"
+            | "UpdateTaskStatus" | "ExtendTaskLease" => Policy::Worker,
+
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        tonic::Code,
+        super::*,
+    };
+
+    const WORKER_METHODS: [&str; 8] = [
+        "WorkerSession",
+        "RegisterWorker",
+        "GetTaskToRun",
+        "CreateTaskAsset",
+        "GetChatMessages",
+        "AddChatAssistantMessage",
+        "UpdateTaskStatus",
+        "ExtendTaskLease",
+    ];
+
+    const USER_METHODS: [&str; 18] = [
+        "ChangePassword",
+        "CreateTask",
+        "GetTask",
+        "WatchTask",
+        "GetTaskEvents",
+        "GetAllTasks",
+        "AddChatUserMessage",
+        "CancelTask",
+        "ExtendTask",
+        "RetryTask",
+        "DeleteTask",
+        "DeleteTaskAsset",
+        "SetTaskVisibility",
+        "ListWorkers",
+        "CreateApiKey",
+        "ListApiKeys",
+        "RevokeApiKey",
+        "ListAuthProviders",
+    ];
+
+    #[test]
+    fn users_cannot_call_worker_rpcs() {
+        for method in WORKER_METHODS {
+            let policy = policy_for_method(method).unwrap();
+            for principal in [Principal::Anonymous, user(Credential::Session), admin(), user(Credential::ApiKey(ApiKeyScope::CreateTasks))] {
+                assert!(policy.check(&principal).is_err(), "{} {:?}", method, principal);
+            }
+            assert_eq!(Ok(()), policy.check(&worker()).map_err(|v| v.code()), "{}", method);
+        }
+    }
+
+    #[test]
+    fn workers_cannot_call_user_rpcs() {
+        for method in USER_METHODS {
+            let policy = policy_for_method(method).unwrap();
+            if policy == Policy::Public {
+                continue;
+            }
+
+            assert_eq!(Err(Code::PermissionDenied), policy.check(&worker()).map_err(|v| v.code()), "{}", method);
+        }
+    }
+
+    #[test]
+    fn api_keys_are_limited_by_scope() {
+        let read_only = user(Credential::ApiKey(ApiKeyScope::ReadOnly));
+        let create_tasks = user(Credential::ApiKey(ApiKeyScope::CreateTasks));
+
+        assert!(policy_for_method("GetTask").unwrap().check(&read_only).is_ok());
+        assert!(policy_for_method("CreateTask").unwrap().check(&read_only).is_err());
+        assert!(policy_for_method("CreateTask").unwrap().check(&create_tasks).is_ok());
+        assert!(policy_for_method("CreateApiKey").unwrap().check(&create_tasks).is_err());
+        assert!(policy_for_method("CreateApiKey").unwrap().check(&user(Credential::Session)).is_ok());
+    }
+
+    #[test]
+    fn anonymous_users_can_only_call_task_rpcs() {
+        for method in USER_METHODS {
+            let policy = policy_for_method(method).unwrap();
+            let expected = matches!(policy, Policy::Public | Policy::AnyUser(_));
+            assert_eq!(expected, policy.check(&Principal::Anonymous).is_ok(), "{}", method);
+        }
+    }
+
+    #[test]
+    fn only_admins_can_list_workers() {
+        let policy = policy_for_method("ListWorkers").unwrap();
+        assert!(policy.check(&admin()).is_ok());
+        assert_eq!(Err(Code::PermissionDenied), policy.check(&user(Credential::Session)).map_err(|v| v.code()));
+    }
+
+    #[test]
+    fn unknown_methods_have_no_policy() {
+        assert_eq!(None, policy_for_method("DropAllTasks"));
+    }
+
+    fn user(credential: Credential) -> Principal {
+        Principal::User { id: "alice".to_owned(), credential }
+    }
+
+    fn admin() -> Principal {
+        Principal::Admin { id: "root".to_owned(), credential: Credential::Session }
+    }
+
+    fn worker() -> Principal {
+        Principal::Worker { id: "worker-1".to_owned() }
+    }
+}
@@ -1,16 +1,21 @@
 use {
-    std::str::FromStr,
+    std::{str::FromStr, time::Duration},
     ulid::Ulid,
     chrono::{DateTime, Utc},
 };
 
 pub struct Task {
     pub id: TaskId,
+    pub user_id: Option<String>,
+    // set for tasks created without an account, see `access` module.
+    pub capability_token_hash: Option<String>,
     pub status: TaskStatus,
     pub created_at: DateTime<Utc>,
     pub params: TaskParams,
+    pub visibility: TaskVisibility,
 }
 
+#[derive(Clone, Eq, PartialEq, Debug)]
 pub struct TaskId {
     id: String,
 }
@@ -39,6 +44,57 @@ impl From<TaskId> for rpc::TaskId {
     }
 }
 
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum TaskVisibility {
+    Private,
+    Unlisted,
+    Public,
+}
+
+impl From<rpc::TaskVisibility> for TaskVisibility {
+    fn from(value: rpc::TaskVisibility) -> Self {
+        match value {
+            rpc::TaskVisibility::Private => Self::Private,
+            rpc::TaskVisibility::Unlisted => Self::Unlisted,
+            rpc::TaskVisibility::Public => Self::Public,
+        }
+    }
+}
+
+impl From<TaskVisibility> for rpc::TaskVisibility {
+    fn from(value: TaskVisibility) -> Self {
+        match value {
+            TaskVisibility::Private => Self::Private,
+            TaskVisibility::Unlisted => Self::Unlisted,
+            TaskVisibility::Public => Self::Public,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum TaskKind {
+    ImageGeneration,
+    ChatMessageGeneration,
+}
+
+impl From<rpc::TaskKind> for TaskKind {
+    fn from(value: rpc::TaskKind) -> Self {
+        match value {
+            rpc::TaskKind::ImageGeneration => Self::ImageGeneration,
+            rpc::TaskKind::ChatMessageGeneration => Self::ChatMessageGeneration,
+        }
+    }
+}
+
+impl From<TaskKind> for rpc::TaskKind {
+    fn from(value: TaskKind) -> Self {
+        match value {
+            TaskKind::ImageGeneration => Self::ImageGeneration,
+            TaskKind::ChatMessageGeneration => Self::ChatMessageGeneration,
+        }
+    }
+}
+
 #[derive(Eq, PartialEq)]
 pub enum TaskStatus {
     Pending,
@@ -48,6 +104,73 @@ pub enum TaskStatus {
         total_steps: u32,
     },
     Finished,
+    Cancelled,
+    // retryable failures are caused by the worker or environment, running the task again may succeed.
+    Failed {
+        reason: String,
+        retryable: bool,
+    },
+}
+
+impl TaskStatus {
+    pub fn is_active(&self) -> bool {
+        matches!(self, Self::Pending | Self::InProgress { .. })
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum TaskStatusKind {
+    Pending,
+    InProgress,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+impl From<rpc::TaskStatusKind> for TaskStatusKind {
+    fn from(value: rpc::TaskStatusKind) -> Self {
+        match value {
+            rpc::TaskStatusKind::Pending => Self::Pending,
+            rpc::TaskStatusKind::InProgress => Self::InProgress,
+            rpc::TaskStatusKind::Finished => Self::Finished,
+            rpc::TaskStatusKind::Cancelled => Self::Cancelled,
+            rpc::TaskStatusKind::Failed => Self::Failed,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TaskFilter {
+    pub kinds: Vec<TaskKind>,
+    pub statuses: Vec<TaskStatusKind>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub query: Option<String>,
+}
+
+/// Position in the task list, tasks are listed newest first. Encoded as "<created_at micros>_<task id>" for clients.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct TaskCursor {
+    pub created_at_micros: i64,
+    pub task_id: TaskId,
+}
+
+impl TaskCursor {
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.created_at_micros, self.task_id.as_str())
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let (created_at_micros, task_id) = cursor.split_once('_')?;
+        if task_id.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            created_at_micros: created_at_micros.parse().ok()?,
+            task_id: TaskId::new(task_id.to_owned()),
+        })
+    }
 }
 
 pub struct UserId {
@@ -66,6 +189,104 @@ impl UserId {
     }
 }
 
+pub struct Worker {
+    pub id: String,
+    pub hostname: String,
+    pub version: String,
+    pub task_kinds: Vec<TaskKind>,
+    pub models: Vec<String>,
+    pub registered_at: DateTime<Utc>,
+    pub last_ping_at: DateTime<Utc>,
+}
+
+impl Worker {
+    pub fn is_active(&self) -> bool {
+        Utc::now() - self.last_ping_at < chrono::Duration::minutes(10)
+    }
+}
+
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scope: ApiKeyScope,
+    pub key_prefix: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum ApiKeyScope {
+    ReadOnly,
+    CreateTasks,
+}
+
+impl ApiKeyScope {
+    pub fn allows(&self, required: ApiKeyScope) -> bool {
+        match required {
+            ApiKeyScope::ReadOnly => true,
+            ApiKeyScope::CreateTasks => *self == ApiKeyScope::CreateTasks,
+        }
+    }
+}
+
+impl From<rpc::ApiKeyScope> for ApiKeyScope {
+    fn from(value: rpc::ApiKeyScope) -> Self {
+        match value {
+            rpc::ApiKeyScope::ReadOnly => Self::ReadOnly,
+            rpc::ApiKeyScope::CreateTasks => Self::CreateTasks,
+        }
+    }
+}
+
+impl From<ApiKeyScope> for rpc::ApiKeyScope {
+    fn from(value: ApiKeyScope) -> Self {
+        match value {
+            ApiKeyScope::ReadOnly => Self::ReadOnly,
+            ApiKeyScope::CreateTasks => Self::CreateTasks,
+        }
+    }
+}
+
+pub struct TaskEvent {
+    pub kind: TaskEventKind,
+    pub worker_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+pub enum TaskEventKind {
+    Created,
+    Claimed { attempt: u32 },
+    Progress { current_image: u32, current_step: u32, total_steps: u32 },
+    AssetCreated { asset_id: AssetId },
+    Requeued,
+    Finished,
+    Cancelled,
+    Failed { reason: String, retryable: bool },
+}
+
+/// Time a task spent in one phase, derived from its events.
+pub struct TaskPhaseDuration {
+    pub event_id: i64,
+    pub phase: TaskPhase,
+    pub duration: Duration,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum TaskPhase {
+    // from creation or requeue until a worker claims the task.
+    QueueWait,
+    // from claim until the task finishes, is cancelled or fails (or goes back to the queue, which is not counted).
+    Run(TaskStatusKind),
+}
+
+// usage of a single requester within the last day.
+pub struct Usage {
+    pub active_tasks: u32,
+    pub daily_tasks: u32,
+    pub daily_tokens: u64,
+    pub oldest_event_at: Option<DateTime<Utc>>,
+}
+
 pub struct AssetId {
     id: Ulid,
 }
@@ -77,6 +298,11 @@ impl AssetId {
         }
     }
 
+    // for ids coming from clients, which may be malformed.
+    pub fn parse(id: &str) -> Option<Self> {
+        Ulid::from_str(id).ok().map(|id| Self { id })
+    }
+
     pub fn to_string(&self) -> String {
         self.id.to_string()
     }
@@ -127,6 +353,7 @@ pub struct ChatMessage {
     pub index: u32,
 }
 
+#[derive(Clone, Debug)]
 pub struct MessageId {
     id: String,
 }
@@ -137,6 +364,10 @@ impl MessageId {
             id,
         }
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
 }
 
 impl From<MessageId> for rpc::MessageId {
@@ -161,4 +392,27 @@ impl From<ChatMessageRole> for rpc::ChatMessageRole {
             ChatMessageRole::Assistant => Self::Assistant,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_cursor_round_trip() {
+        let cursor = TaskCursor {
+            created_at_micros: 1694505333123456,
+            task_id: TaskId::new("01HA2Q3Z7E3V5J4K9M8N6P0R1S".to_owned()),
+        };
+
+        assert_eq!(Some(cursor.clone()), TaskCursor::decode(&cursor.encode()));
+    }
+
+    #[test]
+    fn invalid_task_cursor_is_rejected() {
+        assert_eq!(None, TaskCursor::decode(""));
+        assert_eq!(None, TaskCursor::decode("abc_01HA2Q3Z7E3V5J4K9M8N6P0R1S"));
+        assert_eq!(None, TaskCursor::decode("1694505333123456_"));
+        assert_eq!(None, TaskCursor::decode("1694505333123456"));
+    }
+}
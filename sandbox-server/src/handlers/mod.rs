@@ -1,13 +1,17 @@
 use {
-    std::sync::Arc,
+    std::{sync::Arc, time::Duration, pin::Pin, ops::RangeInclusive, net::SocketAddr},
     tracing::{info, error},
-    tonic::{Status, Request, Response},
+    tokio::sync::{mpsc, broadcast::error::RecvError},
+    futures::Stream,
+    tonic::{Status, Request, Response, Streaming},
     serde::{Serialize, Deserialize},
+    axum::extract::ConnectInfo,
     anyhow::Result,
-    chrono::{Utc, Timelike},
+    chrono::{Utc, DateTime, NaiveDateTime, Timelike},
     jsonwebtoken::{EncodingKey, DecodingKey, Validation, Algorithm, errors::ErrorKind as JwtErrorKind},
     rand::distributions::{Alphanumeric, Distribution},
     prost_types::Timestamp,
+    sha2::{Sha256, Digest},
     rpc::{
         self,
         sandbox_service_server::SandboxService,
@@ -21,8 +25,16 @@ use {
         GetTaskResponse,
         GetAllTasksRequest,
         GetAllTasksResponse,
+        ListAuthProvidersRequest,
+        ListAuthProvidersResponse,
         OAuthLoginRequest,
         OAuthLoginResponse,
+        PasswordLoginRequest,
+        PasswordLoginResponse,
+        RegisterLocalAccountRequest,
+        RegisterLocalAccountResponse,
+        ChangePasswordRequest,
+        ChangePasswordResponse,
         CreateTaskAssetRequest,
         CreateTaskAssetResponse,
         GetChatMessagesRequest,
@@ -31,14 +43,51 @@ use {
         AddChatAssistantMessageResponse,
         AddChatUserMessageRequest,
         AddChatUserMessageResponse,
+        ExtendTaskLeaseRequest,
+        ExtendTaskLeaseResponse,
+        RegisterWorkerRequest,
+        RegisterWorkerResponse,
+        ListWorkersRequest,
+        ListWorkersResponse,
+        CancelTaskRequest,
+        CancelTaskResponse,
+        ExtendTaskRequest,
+        ExtendTaskResponse,
+        RetryTaskRequest,
+        RetryTaskResponse,
+        DeleteTaskRequest,
+        DeleteTaskResponse,
+        DeleteTaskAssetRequest,
+        DeleteTaskAssetResponse,
+        SetTaskVisibilityRequest,
+        SetTaskVisibilityResponse,
+        WatchTaskRequest,
+        WatchTaskResponse,
+        GetTaskEventsRequest,
+        GetTaskEventsResponse,
+        WorkerMessage,
+        ServerMessage,
+        worker_message,
+        CreateApiKeyRequest,
+        CreateApiKeyResponse,
+        ListApiKeysRequest,
+        ListApiKeysResponse,
+        RevokeApiKeyRequest,
+        RevokeApiKeyResponse,
     },
     crate::{
-        entities::{Task, TaskId, TaskStatus, UserId, AssetId, TaskParams, ChatMessageRole},
-        state::database::Database,
+        access::{Caller, TaskOperation, TASK_TOKEN_HEADER, authorize, generate_task_token},
+        auth::{AuthProviders, policy::Principal, local_accounts::{normalize_username, is_password_valid, hash_password, verify_password, verify_dummy_password}},
+        entities::{Task, TaskId, TaskStatus, UserId, AssetId, TaskParams, ChatMessage, ChatMessageRole, TaskKind, TaskStatusKind, TaskFilter, TaskCursor, TaskEvent, TaskEventKind, TaskVisibility, Worker, ApiKey, ApiKeyScope},
+        state::database::{Database, DatabaseError, DatabaseResult},
+        quotas::Quotas,
     },
+    self::worker_session::WorkerSession,
 };
 
+pub mod openai;
 pub mod rest;
+mod worker_session;
 
 #[derive(Serialize, Deserialize)]
 struct TokenClaims {
@@ -48,45 +97,73 @@ struct TokenClaims {
     name: String,
 }
 
-#[derive(Deserialize)]
-struct OAuthCodeExchangeResponse {
-    access_token: String,
-}
-
-#[derive(Deserialize)]
-struct UserInfoResponse {
-    #[serde(rename = "id")]
-    _id: String,
-    email: String,
-    name: String,
-}
-
-enum TokenDecodeResult {
+pub(crate) enum TokenDecodeResult {
     Token(String),
     TokenExpired,
     DecodeError(String),
+    InvalidApiKey,
+    InsufficientScope,
 }
 
+pub(crate) const API_KEY_PREFIX: &str = "sk-sandbox-";
+const TASK_PRIORITY_RANGE: RangeInclusive<i32> = -100..=100;
+const DEFAULT_TASKS_PAGE_SIZE: u32 = 50;
+const MAX_TASKS_PAGE_SIZE: u32 = 100;
+// timestamps from requests end up in postgres through `time`, which supports years up to 9999.
+const MAX_UNIX_TIMESTAMP: i64 = 253402300799;
+
 pub struct SandboxServiceHandler {
     database: Arc<Database>,
 
     token_encoding_key: EncodingKey,
-    token_decoding_key: DecodingKey,
-    worker_token: String,
-    oauth_secret: String,
+    auth_providers: Arc<AuthProviders>,
+    quotas: Arc<Quotas>,
+    task_lease_duration: Duration,
 }
 
 impl SandboxServiceHandler {
-    pub async fn new(database: Arc<Database>, token_encoding_key: EncodingKey, token_decoding_key: DecodingKey, worker_token: String, oauth_secret: String) -> Result<Self> {
+    pub async fn new(
+        database: Arc<Database>,
+        token_encoding_key: EncodingKey,
+        auth_providers: Arc<AuthProviders>,
+        quotas: Arc<Quotas>,
+        task_lease_duration: Duration,
+    ) -> Result<Self> {
         Ok(Self {
             database,
             token_encoding_key,
-            token_decoding_key,
-            worker_token,
-            oauth_secret,
+            auth_providers,
+            quotas,
+            task_lease_duration,
         })
     }
 
+    async fn check_task_lease<T>(&self, req: &Request<T>, task_id: &TaskId) -> Result<(), Status> {
+        let worker_id = worker_id(req)?;
+
+        self.database.update_worker_last_ping_time(&worker_id).await?;
+
+        if !self.database.extend_task_lease(task_id, &worker_id, self.task_lease_duration).await? {
+            return Err(Status::failed_precondition("task_lease_lost"));
+        }
+
+        Ok(())
+    }
+
+    // tasks which do not exist and tasks which are not visible are reported the same way.
+    async fn load_task(&self, task_id: &TaskId) -> Result<Task, Status> {
+        match self.database.find_task(task_id).await? {
+            Some(v) => Ok(v),
+            None => Err(Status::not_found("task_not_found")),
+        }
+    }
+
+    async fn authorized_task(&self, task_id: &TaskId, caller: &Caller, operation: TaskOperation) -> Result<Task, Status> {
+        let task = self.load_task(task_id).await?;
+        authorize(&task, caller, operation)?;
+        Ok(task)
+    }
+
     fn issue_token(&self, id: &UserId, email: &str, name: &str) -> String {
         jsonwebtoken::encode(
             &jsonwebtoken::Header::new(Algorithm::RS384),
@@ -100,131 +177,202 @@ impl SandboxServiceHandler {
         ).unwrap()
     }
 
-    fn decode_token(&self, token: &str) -> TokenDecodeResult {
-        match jsonwebtoken::decode::<TokenClaims>(token, &self.token_decoding_key, &Validation::new(Algorithm::RS384)) {
-            Ok(v) => TokenDecodeResult::Token(v.claims.sub),
-            Err(err) => match err.kind() {
-                JwtErrorKind::ExpiredSignature => TokenDecodeResult::TokenExpired,
-                other => TokenDecodeResult::DecodeError(format!("{:?}", other)),   
-            }
+    fn api_key_to_rpc_api_key(&self, api_key: ApiKey) -> rpc::ApiKey {
+        rpc::ApiKey {
+            id: api_key.id,
+            name: api_key.name,
+            scope: rpc::ApiKeyScope::from(api_key.scope).into(),
+            key_prefix: api_key.key_prefix,
+            created_at: Some(Timestamp {
+                seconds: api_key.created_at.timestamp(),
+                nanos: api_key.created_at.nanosecond() as i32,
+            }),
+            last_used_at: api_key.last_used_at.map(|v| Timestamp {
+                seconds: v.timestamp(),
+                nanos: v.nanosecond() as i32,
+            }),
         }
     }
 
-    fn user_id_from_request_headers(&self, headers: &http::HeaderMap) -> Result<Option<String>, Status> {
-        let user_id = headers.get("x-access-token")
-            .map(|v| v.to_str().unwrap().to_owned())
-            .map(|v| self.decode_token(&v));
-
-        Ok(match user_id {
-            Some(v) => match v {
-                TokenDecodeResult::Token(t) => Some(t),
-                TokenDecodeResult::DecodeError(err) => {
-                    error!("error while decoding token: {:?}", err);
-                    return Err(Status::internal("internal server error"));
-                },
-                TokenDecodeResult::TokenExpired => return Err(Status::unauthenticated("token expired")),
-            },
-            None => None,
-        })
+    fn worker_to_rpc_worker(&self, worker: Worker) -> rpc::Worker {
+        let is_active = worker.is_active();
+
+        rpc::Worker {
+            id: worker.id,
+            hostname: worker.hostname,
+            version: worker.version,
+            task_kinds: worker.task_kinds.into_iter().map(|v| rpc::TaskKind::from(v).into()).collect(),
+            models: worker.models,
+            registered_at: Some(Timestamp {
+                seconds: worker.registered_at.timestamp(),
+                nanos: worker.registered_at.nanosecond() as i32,
+            }),
+            last_ping_at: Some(Timestamp {
+                seconds: worker.last_ping_at.timestamp(),
+                nanos: worker.last_ping_at.nanosecond() as i32,
+            }),
+            is_active,
+        }
     }
+}
 
-    fn task_to_rpc_task(&self, task: Task, assets: Vec<AssetId>) -> rpc::Task {
-        rpc::Task {
-            id: Some(rpc::TaskId::from(task.id)),
-            created_at: Some(Timestamp {
-                seconds: task.created_at.timestamp(),
-                nanos: task.created_at.nanosecond() as i32,
-            }),
-            status: match task.status {
-                TaskStatus::Pending => Some(rpc::task::Status::PendingDetails(rpc::PendingTaskDetails {})),
-                TaskStatus::InProgress { current_step, total_steps, current_image } => Some(rpc::task::Status::InProgressDetails(rpc::InProgressTaskDetails {
-                    current_step,
-                    total_steps,
-                    current_image,
-                })),
-                TaskStatus::Finished => Some(rpc::task::Status::FinishedDetails(rpc::FinishedTaskDetails {})),
+impl From<DatabaseError> for Status {
+    fn from(err: DatabaseError) -> Self {
+        match err {
+            DatabaseError::NotFound => Status::not_found("not_found"),
+            DatabaseError::Unavailable(err) => {
+                error!("database is unavailable: {:?}", err);
+                Status::unavailable("database_unavailable")
+            },
+            DatabaseError::Storage(err) => {
+                error!("object storage request failed: {:?}", err);
+                Status::unavailable("storage_unavailable")
+            },
+            other => {
+                error!("database error: {}", other);
+                Status::internal("internal_error")
             },
-            assets: assets.into_iter().map(|v| rpc::TaskAsset {
-                id: v.to_string(),
-            }).collect(),
-            params: Some(rpc::TaskParams {
-                params: Some(rpc::task_params::Params::from(task.params)),
-            }),
         }
     }
 }
 
 #[tonic::async_trait]
 impl SandboxService for SandboxServiceHandler {
+    type WatchTaskStream = Pin<Box<dyn Stream<Item = Result<WatchTaskResponse, Status>> + Send>>;
+    type WorkerSessionStream = Pin<Box<dyn Stream<Item = Result<ServerMessage, Status>> + Send>>;
+
+    async fn list_auth_providers(&self, _req: Request<ListAuthProvidersRequest>) -> Result<Response<ListAuthProvidersResponse>, Status> {
+        Ok(Response::new(ListAuthProvidersResponse {
+            providers: self.auth_providers.providers()
+                .iter()
+                .map(|v| rpc::AuthProvider {
+                    id: v.id.clone(),
+                    name: v.name.clone(),
+                    authorization_endpoint: v.authorization_endpoint.clone(),
+                    client_id: v.client_id.clone(),
+                    scopes: v.scopes.clone(),
+                })
+                .collect(),
+            local_accounts_enabled: self.auth_providers.local_accounts.enabled,
+            registration_enabled: self.auth_providers.local_accounts.is_registration_allowed(),
+        }))
+    }
+
     async fn o_auth_login(&self, req: Request<OAuthLoginRequest>) -> Result<Response<OAuthLoginResponse>, Status> {
         let req = req.into_inner();
-        
-        let client = reqwest::Client::new();
-        let res = client
-            .post("https://oauth2.googleapis.com/token")
-            .form(&[
-                ("client_id", "916750455653-biu6q4c7llj7q1k14h3qaquktcdlkeo4.apps.googleusercontent.com"),
-                ("client_secret", &self.oauth_secret),
-                ("code", &req.code),
-                ("grant_type", "authorization_code"),
-                ("redirect_uri", &req.redirect_uri),
-            ])
-            .send()
-            .await;
-
-        let res = match res {
-            Ok(v) => v,
-            Err(err) => {
-                error!("failed to run code exchange request: {:?}", err);
-                return Err(Status::internal("something went wrong"));
-            }
-        }.text().await.unwrap();
 
-        let res: OAuthCodeExchangeResponse = match serde_json::from_str(&res) {
+        let provider = match self.auth_providers.provider(&req.provider) {
+            Some(v) => v,
+            None => return Err(Status::invalid_argument("unknown_auth_provider")),
+        };
+
+        let identity = match self.auth_providers.identity_from_code(provider, &req.code, &req.redirect_uri).await {
             Ok(v) => v,
             Err(err) => {
-                error!("failed to get code exchange response: {:?} for response {:?}", err, res);
-                return Err(Status::internal("failed to get token exchange response"));
+                error!("failed to get identity from auth provider {}: {:?}", provider.id, err);
+                return Err(Status::internal("failed to get identity from auth provider"));
             }
         };
 
-        let res = client
-            .get("https://www.googleapis.com/oauth2/v1/userinfo")
-            .bearer_auth(res.access_token)
-            .send()
-            .await;
+        let user_id = self.database.create_or_get_user_by_identity(&provider.id, &identity.subject, &identity.email).await?;
 
-        let res = match res {
-            Ok(v) => v,
-            Err(err) => {
-                error!("failed to request user info: {:?}", err);
-                return Err(Status::internal("failed to request user info"));
-            }
+        let token = self.issue_token(&user_id, &identity.email, &identity.name);
+        info!("issued token for {} ({})", identity.email, provider.id);
+
+        Ok(Response::new(OAuthLoginResponse { token }))
+    }
+
+    async fn password_login(&self, req: Request<PasswordLoginRequest>) -> Result<Response<PasswordLoginResponse>, Status> {
+        if !self.auth_providers.local_accounts.enabled {
+            return Err(Status::failed_precondition("local_accounts_disabled"));
+        }
+
+        let req = req.into_inner();
+        let username = match normalize_username(&req.username) {
+            Some(v) => v,
+            None => return Err(Status::unauthenticated("invalid_credentials")),
         };
 
-        let res: UserInfoResponse = match res.json().await {
-            Ok(v) => v,
-            Err(err) => {
-                error!("failed to get user info response: {:?}", err);
-                return Err(Status::internal("failed to get user info response"));
-            }
+        let (user_id, password_hash) = match self.database.get_local_user(&username).await? {
+            Some(v) => v,
+            None => {
+                verify_dummy_password(req.password).await;
+                return Err(Status::unauthenticated("invalid_credentials"));
+            },
         };
 
-        let user_id = self.database.create_or_get_user_by_email(&res.email).await;
+        if !verify_password(req.password, password_hash).await {
+            return Err(Status::unauthenticated("invalid_credentials"));
+        }
 
-        let token = self.issue_token(&user_id, &res.email, &res.name);
-        info!("issued token for {}", res.email);
+        let token = self.issue_token(&user_id, "", &username);
+        info!("issued token for local user {}", username);
 
-        Ok(Response::new(OAuthLoginResponse { token }))
+        Ok(Response::new(PasswordLoginResponse { token }))
+    }
+
+    async fn register_local_account(&self, req: Request<RegisterLocalAccountRequest>) -> Result<Response<RegisterLocalAccountResponse>, Status> {
+        if !self.auth_providers.local_accounts.is_registration_allowed() {
+            return Err(Status::permission_denied("registration_disabled"));
+        }
+
+        let req = req.into_inner();
+        let username = match normalize_username(&req.username) {
+            Some(v) => v,
+            None => return Err(Status::invalid_argument("username_is_not_valid")),
+        };
+        if !is_password_valid(&req.password) {
+            return Err(Status::invalid_argument("password_is_not_valid"));
+        }
+
+        let user_id = match self.database.create_local_user(&username, &hash_password(req.password).await).await? {
+            Some(v) => v,
+            None => return Err(Status::already_exists("username_is_taken")),
+        };
+
+        let token = self.issue_token(&user_id, "", &username);
+        info!("registered local user {}", username);
+
+        Ok(Response::new(RegisterLocalAccountResponse { token }))
+    }
+
+    async fn change_password(&self, req: Request<ChangePasswordRequest>) -> Result<Response<ChangePasswordResponse>, Status> {
+        let user_id = UserId::from_string(user_id(&req)?);
+        let req = req.into_inner();
+
+        let password_hash = match self.database.get_user_password_hash(&user_id).await? {
+            Some(v) => v,
+            None => return Err(Status::failed_precondition("password_is_not_set")),
+        };
+
+        if !verify_password(req.current_password, password_hash).await {
+            return Err(Status::permission_denied("invalid_password"));
+        }
+        if !is_password_valid(&req.new_password) {
+            return Err(Status::invalid_argument("password_is_not_valid"));
+        }
+
+        self.database.set_user_password_hash(&user_id, &hash_password(req.new_password).await).await?;
+
+        Ok(Response::new(ChangePasswordResponse {}))
     }
 
     async fn create_task(&self, req: Request<CreateTaskRequest>) -> Result<Response<CreateTaskResponse>, Status> {
-        let user_id = self.user_id_from_request_headers(&req.metadata().clone().into_headers())?;
+        let headers = req.metadata().clone().into_headers();
+        let principal = principal(&req)?.clone();
+        let user_id = principal.user_id().map(|v| v.to_owned());
+        let requester = self.quotas.requester(user_id.clone(), &headers, remote_addr(&req));
 
         let req = req.into_inner();
 
         let task_id = generate_task_id();
-        let params = match req.params.unwrap().params.unwrap() {
+        let params = required(req.params, "params")?;
+        let visibility = match (TaskVisibility::from(params.visibility()), user_id.is_some()) {
+            // task token is not passed when loading assets, so tasks created without an account are never private.
+            (TaskVisibility::Private, false) => TaskVisibility::Unlisted,
+            (other, _) => other,
+        };
+        let params = match required(params.params, "params")? {
             rpc::task_params::Params::ImageGeneration(v) => TaskParams::ImageGenerationParams {
                 prompt: v.prompt,
                 iterations: v.iterations,
@@ -234,113 +382,466 @@ impl SandboxService for SandboxServiceHandler {
             },
         };
 
-        if let Some(message) = req.user_message.as_ref() {
-            self.database.append_chat_message(&task_id, message.clone(), ChatMessageRole::User).await;
+        validate_task_params(&params, req.user_message.as_deref())?;
+        if req.priority != 0 && !principal.can_set_task_priority() {
+            return Err(Status::permission_denied("priority_not_allowed"));
+        }
+        if !TASK_PRIORITY_RANGE.contains(&req.priority) {
+            return Err(Status::invalid_argument("priority_out_of_range"));
         }
 
-        self.database.new_task(user_id, &task_id, &params).await;
+        let mut usage = self.database.begin_usage_transaction(&requester.key()).await?;
+        let tokens = self.quotas.check_new_task(&mut usage, &requester, &params).await?;
+
+        // without an account there is nobody to own the task, so the token returned here is the only way to control it.
+        let (capability_token, capability_token_hash) = match user_id {
+            Some(_) => (String::new(), None),
+            None => {
+                let (token, hash) = generate_task_token();
+                (token, Some(hash))
+            }
+        };
+
+        let messages = req.user_message.map(|v| vec![(ChatMessageRole::User, v)]).unwrap_or_default();
+        self.database.new_task(&mut usage, user_id, capability_token_hash, &task_id, &params, visibility, req.priority, messages).await?;
+        usage.record_usage(&task_id, tokens).await?;
+        usage.commit().await?;
 
         Ok(Response::new(CreateTaskResponse {
             id: Some(rpc::TaskId::from(task_id)),
+            capability_token,
         }))
     }
 
     async fn get_task(&self, req: Request<GetTaskRequest>) -> Result<Response<GetTaskResponse>, Status> {
-        let task_id = TaskId::from(req.into_inner().id.unwrap());
-        let task = self.database.get_task(&task_id).await;
-        let assets = self.database.get_task_assets(&task_id).await;
+        let caller = caller(&req)?;
+
+        let task_id = TaskId::from(required(req.into_inner().id, "id")?);
+        let task = self.authorized_task(&task_id, &caller, TaskOperation::GetTask).await?;
+
+        let assets = self.database.get_task_assets(&task_id).await?;
+        let queue_position = queue_position(&self.database, &task).await?;
+        let is_owner = caller.is_owner(&task);
+        let messages = match task.params {
+            TaskParams::ChatMessageGenerationParams {} => chat_messages_to_rpc_chat_messages(self.database.get_chat_messages(&task_id).await?),
+            _ => vec![],
+        };
 
         Ok(Response::new(GetTaskResponse {
-            task: Some(self.task_to_rpc_task(task, assets)),
+            task: Some(task_to_rpc_task(&self.token_encoding_key, task, assets, queue_position)),
             
-            messages: vec![],
+            messages,
+
+            is_owner,
+        }))
+    }
+
+    async fn watch_task(&self, req: Request<WatchTaskRequest>) -> Result<Response<Self::WatchTaskStream>, Status> {
+        let caller = caller(&req)?;
+        let task_id = TaskId::from(required(req.into_inner().id, "id")?);
+
+        // subscribe before loading the first snapshot, so that no update is lost in between.
+        let mut updates = self.database.subscribe_to_task_updates();
+
+        let snapshot = watch_task_snapshot(&self.database, &self.token_encoding_key, &task_id, &caller).await?;
+
+        let (tx, rx) = mpsc::channel(16);
+        tx.send(Ok(snapshot)).await.unwrap();
+
+        let database = self.database.clone();
+        let encoding_key = self.token_encoding_key.clone();
+        tokio::spawn(async move {
+            loop {
+                let updated_task_id = tokio::select! {
+                    _ = tx.closed() => break,
+                    update = updates.recv() => match update {
+                        Ok(v) => v,
+                        // some notifications were dropped, so snapshot is reloaded just in case.
+                        Err(RecvError::Lagged(_)) => task_id.clone(),
+                        Err(RecvError::Closed) => break,
+                    },
+                };
+
+                if updated_task_id != task_id {
+                    continue;
+                }
+
+                let snapshot = match watch_task_snapshot(&database, &encoding_key, &task_id, &caller).await {
+                    Ok(v) => v,
+                    Err(err) => {
+                        // task was deleted, is no longer visible to this user or cannot be loaded right now.
+                        let _ = tx.send(Err(err)).await;
+                        break;
+                    }
+                };
+
+                if tx.send(Ok(snapshot)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let stream = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|v| (v, rx))
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_task_events(&self, req: Request<GetTaskEventsRequest>) -> Result<Response<GetTaskEventsResponse>, Status> {
+        let caller = caller(&req)?;
+        // worker ids are internal, like the list of workers.
+        let include_worker_ids = matches!(principal(&req)?, Principal::Admin { .. });
+
+        let task_id = TaskId::from(required(req.into_inner().task_id, "task_id")?);
+        self.authorized_task(&task_id, &caller, TaskOperation::GetTaskEvents).await?;
+
+        let events = self.database.get_task_events(&task_id).await?;
+
+        Ok(Response::new(GetTaskEventsResponse {
+            events: events.into_iter().map(|v| task_event_to_rpc_task_event(v, include_worker_ids)).collect(),
         }))
     }
 
     async fn get_all_tasks(&self, req: Request<GetAllTasksRequest>) -> Result<Response<GetAllTasksResponse>, Status> {
-        let user_id = match self.user_id_from_request_headers(&req.metadata().clone().into_headers())? {
-            Some(v) => v,
-            None => return Err(Status::unauthenticated("unauthenticated")),
+        let user_id = user_id(&req)?;
+
+        let req = req.into_inner();
+        let cursor = if req.cursor.is_empty() {
+            None
+        } else {
+            match TaskCursor::decode(&req.cursor) {
+                Some(v) if (0..=MAX_UNIX_TIMESTAMP * 1_000_000).contains(&v.created_at_micros) => Some(v),
+                _ => return Err(Status::invalid_argument("invalid_cursor")),
+            }
+        };
+        let limit = match req.limit {
+            0 => DEFAULT_TASKS_PAGE_SIZE,
+            other => other.min(MAX_TASKS_PAGE_SIZE),
+        };
+        let query = req.query.trim();
+
+        let filter = TaskFilter {
+            kinds: req.kinds().map(TaskKind::from).collect(),
+            statuses: req.statuses().map(TaskStatusKind::from).collect(),
+            created_after: req.created_after.map(|v| datetime_from_timestamp(&v, "created_after")).transpose()?,
+            created_before: req.created_before.map(|v| datetime_from_timestamp(&v, "created_before")).transpose()?,
+            query: if query.is_empty() { None } else { Some(query.to_owned()) },
         };
 
-        let tasks = self.database.get_user_tasks(&user_id).await;
-        let mut rpc_tasks = Vec::new();
+        let (tasks, next_cursor) = self.database.get_user_tasks(&user_id, &filter, cursor.as_ref(), limit).await?;
 
-        for task in tasks {
-            let assets = self.database.get_task_assets(&task.id).await;
-            rpc_tasks.push(self.task_to_rpc_task(task, assets));
-        }
-        
-        Ok(Response::new(GetAllTasksResponse { tasks: rpc_tasks }))
+        Ok(Response::new(GetAllTasksResponse {
+            tasks: tasks.into_iter().map(|(task, assets)| task_to_rpc_task(&self.token_encoding_key, task, assets, None)).collect(),
+            next_cursor: next_cursor.map(|v| v.encode()),
+        }))
     }
 
     async fn add_chat_user_message(&self, req: Request<AddChatUserMessageRequest>) -> Result<Response<AddChatUserMessageResponse>, Status> {
+        let headers = req.metadata().clone().into_headers();
+        let caller = caller(&req)?;
+        let requester = self.quotas.requester(caller.user_id.clone(), &headers, remote_addr(&req));
+
         let req = req.into_inner();
-        let task_id = TaskId::from(req.task_id.unwrap());
+        let task_id = TaskId::from(required(req.task_id, "task_id")?);
+        if req.content.trim().is_empty() {
+            return Err(Status::invalid_argument("message_is_empty"));
+        }
 
-        self.database.append_chat_message(&task_id, req.content, ChatMessageRole::User).await;
-        self.database.save_task_status(&task_id, &TaskStatus::Pending).await;
+        let task = self.authorized_task(&task_id, &caller, TaskOperation::AddChatUserMessage).await?;
+        if !matches!(task.params, TaskParams::ChatMessageGenerationParams {}) {
+            return Err(Status::invalid_argument("task_is_not_chat"));
+        }
+
+        if task.status.is_active() {
+            return Err(Status::failed_precondition("reply_is_being_generated"));
+        }
+
+        // every user message results in a new reply being generated, so it counts as a new task.
+        let mut usage = self.database.begin_usage_transaction(&requester.key()).await?;
+        let tokens = self.quotas.check_new_task(&mut usage, &requester, &task.params).await?;
+
+        if self.database.add_user_chat_message(&mut usage, &task_id, req.content).await?.is_none() {
+            return Err(Status::failed_precondition("reply_is_being_generated"));
+        }
+        usage.record_usage(&task_id, tokens).await?;
+        usage.commit().await?;
 
         Ok(Response::new(AddChatUserMessageResponse {}))
     }
 
-    async fn get_task_to_run(&self, req: Request<GetTaskToRunRequest>) -> Result<Response<GetTaskToRunResponse>, Status> {
+    async fn cancel_task(&self, req: Request<CancelTaskRequest>) -> Result<Response<CancelTaskResponse>, Status> {
+        let caller = caller(&req)?;
+
+        let task_id = TaskId::from(required(req.into_inner().task_id, "task_id")?);
+        let task = self.authorized_task(&task_id, &caller, TaskOperation::CancelTask).await?;
+
+        if !task.status.is_active() {
+            return Err(Status::failed_precondition("task_not_running"));
+        }
+
+        if self.database.cancel_task(&task_id).await? {
+            info!("task {} is cancelled by its owner", task_id.as_str());
+        }
+
+        Ok(Response::new(CancelTaskResponse {}))
+    }
+
+    async fn extend_task(&self, req: Request<ExtendTaskRequest>) -> Result<Response<ExtendTaskResponse>, Status> {
         let headers = req.metadata().clone().into_headers();
-        let token = match headers.get("x-access-token").map(|v| v.to_str().unwrap().to_owned()) {
-            Some(v) => v,
-            None => return Err(Status::unauthenticated("unauthenticated")),
+        let caller = caller(&req)?;
+        let requester = self.quotas.requester(caller.user_id.clone(), &headers, remote_addr(&req));
+
+        let req = req.into_inner();
+        let task_id = TaskId::from(required(req.task_id, "task_id")?);
+        if req.number_of_images == 0 {
+            return Err(Status::invalid_argument("number_of_images_is_zero"));
+        }
+
+        let task = self.authorized_task(&task_id, &caller, TaskOperation::ExtendTask).await?;
+        let (extended, added) = match task.params {
+            TaskParams::ImageGenerationParams { iterations, number_of_images, prompt } => (
+                TaskParams::ImageGenerationParams {
+                    iterations,
+                    number_of_images: number_of_images.checked_add(req.number_of_images).ok_or_else(|| Status::invalid_argument("number_of_images_too_large"))?,
+                    prompt: prompt.clone(),
+                },
+                TaskParams::ImageGenerationParams { iterations, number_of_images: req.number_of_images, prompt },
+            ),
+            TaskParams::ChatMessageGenerationParams {} => return Err(Status::invalid_argument("task_is_not_image_generation")),
         };
 
-        if token != self.worker_token {
-            return Err(Status::unauthenticated("wrong_token"));
+        if task.status != TaskStatus::Finished {
+            return Err(Status::failed_precondition("task_not_finished"));
         }
 
-        self.database.update_worker_last_ping_time().await;
+        let mut usage = self.database.begin_usage_transaction(&requester.key()).await?;
+        let tokens = self.quotas.check_extended_task(&mut usage, &requester, &extended, &added).await?;
 
-        let task_to_run = self.database.get_any_new_task().await;
+        if !self.database.extend_image_task(&mut usage, &task_id, req.number_of_images).await? {
+            return Err(Status::failed_precondition("task_not_finished"));
+        }
+        usage.record_usage(&task_id, tokens).await?;
+        usage.commit().await?;
+        info!("task {} is extended with {} more images", task_id.as_str(), req.number_of_images);
 
-        Ok(Response::new(GetTaskToRunResponse {
-            task_to_run: task_to_run.map(|v| rpc::get_task_to_run_response::TaskToRun {
-                id: Some(rpc::TaskId::from(v.id)),
-                params: Some(rpc::TaskParams {
-                    params: Some(rpc::task_params::Params::from(v.params)),
-                }),
-            }),
-        }))
+        Ok(Response::new(ExtendTaskResponse {}))
     }
 
-    async fn create_task_asset(&self, req: Request<CreateTaskAssetRequest>) -> Result<Response<CreateTaskAssetResponse>, Status> {
-        let token = match extract_access_token(&req) {
+    async fn retry_task(&self, req: Request<RetryTaskRequest>) -> Result<Response<RetryTaskResponse>, Status> {
+        let headers = req.metadata().clone().into_headers();
+        let caller = caller(&req)?;
+        let requester = self.quotas.requester(caller.user_id.clone(), &headers, remote_addr(&req));
+
+        let task_id = TaskId::from(required(req.into_inner().task_id, "task_id")?);
+        let task = self.authorized_task(&task_id, &caller, TaskOperation::RetryTask).await?;
+
+        match task.status {
+            TaskStatus::Failed { retryable: true, .. } => {},
+            TaskStatus::Failed { retryable: false, .. } => return Err(Status::failed_precondition("task_not_retryable")),
+            _ => return Err(Status::failed_precondition("task_not_failed")),
+        }
+
+        // task is generated again, so retry counts as a new task.
+        let mut usage = self.database.begin_usage_transaction(&requester.key()).await?;
+        let tokens = self.quotas.check_new_task(&mut usage, &requester, &task.params).await?;
+
+        if !self.database.retry_task(&mut usage, &task_id).await? {
+            return Err(Status::failed_precondition("task_not_failed"));
+        }
+        usage.record_usage(&task_id, tokens).await?;
+        usage.commit().await?;
+        info!("task {} is retried by its owner", task_id.as_str());
+
+        Ok(Response::new(RetryTaskResponse {}))
+    }
+
+    async fn delete_task(&self, req: Request<DeleteTaskRequest>) -> Result<Response<DeleteTaskResponse>, Status> {
+        let caller = caller(&req)?;
+
+        let task_id = TaskId::from(required(req.into_inner().task_id, "task_id")?);
+        let task = self.authorized_task(&task_id, &caller, TaskOperation::DeleteTask).await?;
+
+        if task.status.is_active() {
+            return Err(Status::failed_precondition("task_is_running"));
+        }
+
+        self.database.delete_task(&task_id).await?;
+        info!("task {} is deleted by its owner", task_id.as_str());
+
+        Ok(Response::new(DeleteTaskResponse {}))
+    }
+
+    async fn delete_task_asset(&self, req: Request<DeleteTaskAssetRequest>) -> Result<Response<DeleteTaskAssetResponse>, Status> {
+        let caller = caller(&req)?;
+
+        let asset_id = AssetId::parse(&req.into_inner().asset_id).ok_or_else(|| Status::invalid_argument("invalid_asset_id"))?;
+        let task_id = match self.database.get_asset_task_id(&asset_id).await? {
             Some(v) => v,
-            None => return Err(Status::unauthenticated("unauthenticated")),
+            None => return Err(Status::not_found("asset_not_found")),
         };
 
-        if token != self.worker_token {
-            return Err(Status::unauthenticated("wrong_token"));
+        self.authorized_task(&task_id, &caller, TaskOperation::DeleteTaskAsset).await?;
+
+        self.database.delete_task_asset(&asset_id).await?;
+
+        Ok(Response::new(DeleteTaskAssetResponse {}))
+    }
+
+    async fn set_task_visibility(&self, req: Request<SetTaskVisibilityRequest>) -> Result<Response<SetTaskVisibilityResponse>, Status> {
+        let caller = caller(&req)?;
+
+        let req = req.into_inner();
+        let visibility = TaskVisibility::from(req.visibility());
+        let task_id = TaskId::from(required(req.task_id, "task_id")?);
+        let task = self.authorized_task(&task_id, &caller, TaskOperation::SetTaskVisibility).await?;
+
+        // same as on creation, tasks without an account cannot be private.
+        if visibility == TaskVisibility::Private && task.user_id.is_none() {
+            return Err(Status::invalid_argument("private_task_requires_account"));
+        }
+
+        self.database.set_task_visibility(&task_id, visibility).await?;
+
+        Ok(Response::new(SetTaskVisibilityResponse {}))
+    }
+
+    async fn list_workers(&self, _req: Request<ListWorkersRequest>) -> Result<Response<ListWorkersResponse>, Status> {
+        let workers = self.database.get_workers().await?
+            .into_iter()
+            .map(|v| self.worker_to_rpc_worker(v))
+            .collect();
+
+        Ok(Response::new(ListWorkersResponse { workers }))
+    }
+
+    async fn create_api_key(&self, req: Request<CreateApiKeyRequest>) -> Result<Response<CreateApiKeyResponse>, Status> {
+        let user_id = user_id(&req)?;
+        let req = req.into_inner();
+
+        let name = req.name.trim();
+        if name.is_empty() {
+            return Err(Status::invalid_argument("name_is_empty"));
+        }
+
+        let key = generate_api_key();
+        let key_prefix = &key[..API_KEY_PREFIX.len() + 4];
+        let api_key = self.database.create_api_key(&user_id, name, ApiKeyScope::from(req.scope()), key_prefix, &hash_api_key(&key)).await?;
+
+        Ok(Response::new(CreateApiKeyResponse {
+            api_key: Some(self.api_key_to_rpc_api_key(api_key)),
+            key,
+        }))
+    }
+
+    async fn list_api_keys(&self, req: Request<ListApiKeysRequest>) -> Result<Response<ListApiKeysResponse>, Status> {
+        let user_id = user_id(&req)?;
+
+        let api_keys = self.database.get_user_api_keys(&user_id).await?
+            .into_iter()
+            .map(|v| self.api_key_to_rpc_api_key(v))
+            .collect();
+
+        Ok(Response::new(ListApiKeysResponse { api_keys }))
+    }
+
+    async fn revoke_api_key(&self, req: Request<RevokeApiKeyRequest>) -> Result<Response<RevokeApiKeyResponse>, Status> {
+        let user_id = user_id(&req)?;
+
+        if !self.database.revoke_api_key(&user_id, &req.into_inner().id).await? {
+            return Err(Status::not_found("api_key_not_found"));
         }
 
+        Ok(Response::new(RevokeApiKeyResponse {}))
+    }
+
+    async fn worker_session(&self, req: Request<Streaming<WorkerMessage>>) -> Result<Response<Self::WorkerSessionStream>, Status> {
+        let worker_id = worker_id(&req)?;
+
+        let mut inbound = req.into_inner();
+        let registration = match inbound.message().await? {
+            Some(WorkerMessage { message: Some(worker_message::Message::Register(v)) }) => v,
+            _ => return Err(Status::invalid_argument("expected_registration_message")),
+        };
+
+        let task_kinds: Vec<TaskKind> = registration.task_kinds()
+            .map(TaskKind::from)
+            .collect();
+
+        self.database.register_worker(&worker_id, &registration.hostname, &registration.version, &task_kinds, &registration.models).await?;
+        info!("worker {} ({}, version {}) connected, capable of running {:?}", worker_id, registration.hostname, registration.version, task_kinds);
+
+        let (tx, rx) = mpsc::channel(16);
+        let session = WorkerSession::new(self.database.clone(), worker_id, task_kinds, self.task_lease_duration, tx);
+        tokio::spawn(session.run(inbound));
+
+        let stream = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|v| (v, rx))
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn register_worker(&self, req: Request<RegisterWorkerRequest>) -> Result<Response<RegisterWorkerResponse>, Status> {
+        let worker_id = worker_id(&req)?;
+
         let req = req.into_inner();
-        let task_id = TaskId::from(req.task_id.unwrap());
+        let task_kinds: Vec<TaskKind> = req.task_kinds()
+            .map(TaskKind::from)
+            .collect();
 
-        self.database.create_task_asset(&task_id, req.image).await;
+        self.database.register_worker(&worker_id, &req.hostname, &req.version, &task_kinds, &req.models).await?;
+        info!("registered worker {} ({}, version {}) capable of running {:?}", worker_id, req.hostname, req.version, task_kinds);
 
-        Ok(Response::new(CreateTaskAssetResponse {}))
+        Ok(Response::new(RegisterWorkerResponse {}))
     }
 
-    async fn get_chat_messages(&self, req: Request<GetChatMessagesRequest>) -> Result<Response<GetChatMessagesResponse>, Status> {
-        let token = match extract_access_token(&req) {
+    async fn get_task_to_run(&self, req: Request<GetTaskToRunRequest>) -> Result<Response<GetTaskToRunResponse>, Status> {
+        let worker_id = worker_id(&req)?;
+
+        let worker = match self.database.get_worker(&worker_id).await? {
             Some(v) => v,
-            None => return Err(Status::unauthenticated("unauthenticated")),
+            None => return Err(Status::failed_precondition("worker_not_registered")),
         };
 
-        if token != self.worker_token {
-            return Err(Status::unauthenticated("wrong_token"));
-        }
+        self.database.update_worker_last_ping_time(&worker.id).await?;
+
+        let task_to_run = self.database.claim_new_task(&worker.id, &worker.task_kinds, self.task_lease_duration).await?;
+        let task_to_run = match task_to_run {
+            Some(v) => v,
+            None => return Ok(Response::new(GetTaskToRunResponse { task_to_run: None })),
+        };
+        info!("task {} is claimed by worker {}", task_to_run.id.as_str(), worker_id);
+
+        let first_image = self.database.images_generated(&task_to_run.id).await?;
+
+        Ok(Response::new(GetTaskToRunResponse {
+            task_to_run: Some(rpc::get_task_to_run_response::TaskToRun {
+                id: Some(rpc::TaskId::from(task_to_run.id)),
+                params: Some(rpc::TaskParams {
+                    params: Some(rpc::task_params::Params::from(task_to_run.params)),
+                    visibility: rpc::TaskVisibility::from(task_to_run.visibility).into(),
+                }),
+                first_image,
+            }),
+        }))
+    }
+
+    async fn create_task_asset(&self, req: Request<CreateTaskAssetRequest>) -> Result<Response<CreateTaskAssetResponse>, Status> {
+        let task_id = TaskId::from(required(req.get_ref().task_id.clone(), "task_id")?);
+        self.check_task_lease(&req, &task_id).await?;
 
         let req = req.into_inner();
-        let task_id = TaskId::from(req.task_id.unwrap());
+        self.database.create_task_asset(&task_id, req.image).await?;
+
+        Ok(Response::new(CreateTaskAssetResponse {}))
+    }
 
-        let messages = self.database.get_chat_messages(&task_id).await
+    async fn get_chat_messages(&self, req: Request<GetChatMessagesRequest>) -> Result<Response<GetChatMessagesResponse>, Status> {
+        let req = req.into_inner();
+        let task_id = TaskId::from(required(req.task_id, "task_id")?);
+
+        let messages = self.database.get_chat_messages(&task_id).await?
             .into_iter()
             .map(|v| rpc::get_chat_messages_response::ChatMessage {
                 message_id: Some(rpc::MessageId::from(v.message_id)),
@@ -356,56 +857,268 @@ impl SandboxService for SandboxServiceHandler {
     }
 
     async fn add_chat_assistant_message(&self, req: Request<AddChatAssistantMessageRequest>) -> Result<Response<AddChatAssistantMessageResponse>, Status> {
-        let token = match extract_access_token(&req) {
-            Some(v) => v,
-            None => return Err(Status::unauthenticated("unauthenticated")),
-        };
+        let task_id = TaskId::from(required(req.get_ref().task_id.clone(), "task_id")?);
+        self.check_task_lease(&req, &task_id).await?;
 
-        if token != self.worker_token {
-            return Err(Status::unauthenticated("wrong_token"));
-        }
-        
         let req = req.into_inner();
-        let task_id = TaskId::from(req.task_id.unwrap());
-
-        self.database.append_chat_message(&task_id, req.content, ChatMessageRole::Assistant).await;
+        self.database.append_chat_message(&task_id, req.content, ChatMessageRole::Assistant).await?;
 
         Ok(Response::new(AddChatAssistantMessageResponse {}))
     }
 
     async fn update_task_status(&self, req: Request<UpdateTaskStatusRequest>) -> Result<Response<UpdateTaskStatusResponse>, Status> {
-        let token = match extract_access_token(&req) {
-            Some(v) => v,
-            None => return Err(Status::unauthenticated("unauthenticated")),
-        };
-
-        if token != self.worker_token {
-            return Err(Status::unauthenticated("wrong_token"));
-        }
-
-        self.database.update_worker_last_ping_time().await;
+        let task_id = TaskId::from(required(req.get_ref().id.clone(), "id")?);
+        self.check_task_lease(&req, &task_id).await?;
+        let worker_id = worker_id(&req)?;
 
         let req = req.into_inner();
-        let task_status = match req.task_status.unwrap() {
+        let task_status = match required(req.task_status, "task_status")? {
             rpc::update_task_status_request::TaskStatus::InProgress(in_progress) => TaskStatus::InProgress { 
                 current_image: in_progress.current_image,
                 current_step: in_progress.current_step, 
                 total_steps: in_progress.total_steps,
             },
             rpc::update_task_status_request::TaskStatus::Finished(_) => TaskStatus::Finished,
+            rpc::update_task_status_request::TaskStatus::Failed(failed) => {
+                if self.database.fail_task(&task_id, &worker_id, &failed.reason, failed.retryable).await? {
+                    info!("task {} failed ({}), returning it to the queue", task_id.as_str(), failed.reason);
+                }
+                return Ok(Response::new(UpdateTaskStatusResponse { is_cancelled: false }));
+            },
         };
 
-        let task_id = TaskId::from(req.id.unwrap());
+        let is_finished = task_status == TaskStatus::Finished;
+        let is_cancelled = !self.database.save_task_status_unless_cancelled(&task_id, &worker_id, &task_status).await?;
+        if is_cancelled && is_finished {
+            // worker has stopped working on cancelled task, so it can be released.
+            self.database.release_task_lease(&task_id, &worker_id).await?;
+        }
+
+        Ok(Response::new(UpdateTaskStatusResponse { is_cancelled }))
+    }
+
+    async fn extend_task_lease(&self, req: Request<ExtendTaskLeaseRequest>) -> Result<Response<ExtendTaskLeaseResponse>, Status> {
+        let task_id = TaskId::from(required(req.get_ref().task_id.clone(), "task_id")?);
+        self.check_task_lease(&req, &task_id).await?;
+
+        Ok(Response::new(ExtendTaskLeaseResponse {
+            is_cancelled: self.database.is_task_cancelled(&task_id).await?,
+        }))
+    }
+}
+
+/// Access token is either a session token issued on login, or an api key.
+pub(crate) async fn resolve_access_token(database: &Database, decoding_key: &DecodingKey, token: &str, scope: ApiKeyScope) -> DatabaseResult<TokenDecodeResult> {
+    if !token.starts_with(API_KEY_PREFIX) {
+        return Ok(decode_token(decoding_key, token));
+    }
+
+    Ok(match database.use_api_key(&hash_api_key(token)).await? {
+        Some((user_id, key_scope)) if key_scope.allows(scope) => TokenDecodeResult::Token(user_id),
+        Some(_) => TokenDecodeResult::InsufficientScope,
+        None => TokenDecodeResult::InvalidApiKey,
+    })
+}
+
+pub(crate) fn decode_token(decoding_key: &DecodingKey, token: &str) -> TokenDecodeResult {
+    match jsonwebtoken::decode::<TokenClaims>(token, decoding_key, &Validation::new(Algorithm::RS384)) {
+        Ok(v) => TokenDecodeResult::Token(v.claims.sub),
+        Err(err) => match err.kind() {
+            JwtErrorKind::ExpiredSignature => TokenDecodeResult::TokenExpired,
+            other => TokenDecodeResult::DecodeError(format!("{:?}", other)),   
+        }
+    }
+}
+
+fn task_to_rpc_task(encoding_key: &EncodingKey, task: Task, assets: Vec<AssetId>, queue_position: Option<u32>) -> rpc::Task {
+    rpc::Task {
+        id: Some(rpc::TaskId::from(task.id)),
+        created_at: Some(Timestamp {
+            seconds: task.created_at.timestamp(),
+            nanos: task.created_at.nanosecond() as i32,
+        }),
+        status: match task.status {
+            TaskStatus::Pending => Some(rpc::task::Status::PendingDetails(rpc::PendingTaskDetails {
+                queue_position,
+            })),
+            TaskStatus::InProgress { current_step, total_steps, current_image } => Some(rpc::task::Status::InProgressDetails(rpc::InProgressTaskDetails {
+                current_step,
+                total_steps,
+                current_image,
+            })),
+            TaskStatus::Finished => Some(rpc::task::Status::FinishedDetails(rpc::FinishedTaskDetails {})),
+            TaskStatus::Cancelled => Some(rpc::task::Status::CancelledDetails(rpc::CancelledTaskDetails {})),
+            TaskStatus::Failed { reason, retryable } => Some(rpc::task::Status::FailedDetails(rpc::FailedTaskDetails {
+                reason,
+                retryable,
+            })),
+        },
+        assets: assets.into_iter().map(|v| rpc::TaskAsset {
+            url: rest::signed_asset_path(encoding_key, &v),
+            id: v.to_string(),
+        }).collect(),
+        params: Some(rpc::TaskParams {
+            params: Some(rpc::task_params::Params::from(task.params)),
+            visibility: rpc::TaskVisibility::from(task.visibility).into(),
+        }),
+    }
+}
+
+fn task_event_to_rpc_task_event(event: TaskEvent, include_worker_id: bool) -> rpc::TaskEvent {
+    let mut rpc_event = rpc::TaskEvent {
+        created_at: Some(Timestamp {
+            seconds: event.created_at.timestamp(),
+            nanos: event.created_at.nanosecond() as i32,
+        }),
+        worker_id: if include_worker_id { event.worker_id } else { None },
+        ..rpc::TaskEvent::default()
+    };
+
+    let kind = match event.kind {
+        TaskEventKind::Created => rpc::TaskEventKind::TaskCreated,
+        TaskEventKind::Claimed { attempt } => {
+            rpc_event.attempt = attempt;
+            rpc::TaskEventKind::TaskClaimed
+        },
+        TaskEventKind::Progress { current_image, current_step, total_steps } => {
+            rpc_event.progress = Some(rpc::InProgressTaskDetails {
+                current_step,
+                total_steps,
+                current_image,
+            });
+            rpc::TaskEventKind::TaskProgress
+        },
+        TaskEventKind::AssetCreated { asset_id } => {
+            rpc_event.asset_id = asset_id.to_string();
+            rpc::TaskEventKind::TaskAssetCreated
+        },
+        TaskEventKind::Requeued => rpc::TaskEventKind::TaskRequeued,
+        TaskEventKind::Finished => rpc::TaskEventKind::TaskFinished,
+        TaskEventKind::Cancelled => rpc::TaskEventKind::TaskCancelled,
+        TaskEventKind::Failed { reason, retryable } => {
+            rpc_event.failure = Some(rpc::FailedTaskDetails {
+                reason,
+                retryable,
+            });
+            rpc::TaskEventKind::TaskFailed
+        },
+    };
+    rpc_event.set_kind(kind);
+
+    rpc_event
+}
+
+async fn watch_task_snapshot(database: &Database, encoding_key: &EncodingKey, task_id: &TaskId, caller: &Caller) -> Result<WatchTaskResponse, Status> {
+    let task = match database.find_task(task_id).await? {
+        Some(v) => v,
+        None => return Err(Status::not_found("task_not_found")),
+    };
+    authorize(&task, caller, TaskOperation::WatchTask)?;
+
+    let is_owner = caller.is_owner(&task);
+    let assets = database.get_task_assets(task_id).await?;
+    let queue_position = queue_position(database, &task).await?;
+    let messages = match task.params {
+        TaskParams::ChatMessageGenerationParams {} => chat_messages_to_rpc_chat_messages(database.get_chat_messages(task_id).await?),
+        _ => vec![],
+    };
+
+    Ok(WatchTaskResponse {
+        task: Some(task_to_rpc_task(encoding_key, task, assets, queue_position)),
+        messages,
+        is_owner,
+    })
+}
+
+async fn queue_position(database: &Database, task: &Task) -> DatabaseResult<Option<u32>> {
+    match task.status {
+        TaskStatus::Pending => database.queue_position(&task.id).await,
+        _ => Ok(None),
+    }
+}
+
+fn chat_messages_to_rpc_chat_messages(mut messages: Vec<ChatMessage>) -> Vec<rpc::get_task_response::ChatMessage> {
+    messages.sort_by_key(|v| v.index);
+
+    messages.into_iter()
+        .map(|v| rpc::get_task_response::ChatMessage {
+            message_id: Some(rpc::MessageId::from(v.message_id)),
+            content: v.content,
+            role: rpc::ChatMessageRole::from(v.role).into(),
+            message_index: v.index,
+        })
+        .collect()
+}
+
+// attached by the auth layer, so it can be missing only if the service is mounted without it.
+fn principal<T>(req: &Request<T>) -> Result<&Principal, Status> {
+    req.extensions().get::<Principal>().ok_or_else(|| Status::internal("internal_error"))
+}
+
+fn user_id<T>(req: &Request<T>) -> Result<String, Status> {
+    principal(req)?.user_id().map(|v| v.to_owned()).ok_or_else(|| Status::unauthenticated("unauthenticated"))
+}
+
+fn worker_id<T>(req: &Request<T>) -> Result<String, Status> {
+    principal(req)?.worker_id().map(|v| v.to_owned()).ok_or_else(|| Status::permission_denied("worker_only"))
+}
+
+fn caller<T>(req: &Request<T>) -> Result<Caller, Status> {
+    let task_token = req.metadata().get(TASK_TOKEN_HEADER).and_then(|v| v.to_str().ok()).map(|v| v.to_owned());
+    Ok(Caller::new(principal(req)?.user_id().map(|v| v.to_owned()), task_token))
+}
+
+// grpc-web requests are served through axum, which passes client address in connect info instead.
+fn remote_addr<T>(req: &Request<T>) -> Option<SocketAddr> {
+    req.remote_addr().or_else(|| req.extensions().get::<ConnectInfo<SocketAddr>>().map(|v| v.0))
+}
 
-        self.database.save_task_status(&task_id, &task_status).await;
+fn required<T>(value: Option<T>, field: &str) -> Result<T, Status> {
+    value.ok_or_else(|| Status::invalid_argument(format!("{}_is_required", field)))
+}
+
+fn datetime_from_timestamp(timestamp: &Timestamp, field: &str) -> Result<DateTime<Utc>, Status> {
+    if !(0..=MAX_UNIX_TIMESTAMP).contains(&timestamp.seconds) {
+        return Err(Status::invalid_argument(format!("{}_is_out_of_range", field)));
+    }
 
-        Ok(Response::new(UpdateTaskStatusResponse {}))
+    Ok(DateTime::from_utc(NaiveDateTime::from_timestamp_opt(timestamp.seconds, 0).unwrap(), Utc))
+}
+
+fn validate_task_params(params: &TaskParams, user_message: Option<&str>) -> Result<(), Status> {
+    match params {
+        TaskParams::ImageGenerationParams { prompt, iterations, number_of_images } => {
+            if prompt.trim().is_empty() {
+                return Err(Status::invalid_argument("prompt_is_empty"));
+            }
+            if *number_of_images == 0 {
+                return Err(Status::invalid_argument("number_of_images_is_zero"));
+            }
+            if *iterations == 0 {
+                return Err(Status::invalid_argument("iterations_is_zero"));
+            }
+        },
+        TaskParams::ChatMessageGenerationParams {} => {
+            if user_message.map(|v| v.trim().is_empty()).unwrap_or(true) {
+                return Err(Status::invalid_argument("message_is_empty"));
+            }
+        },
     }
+
+    Ok(())
+}
+
+// keys are long random strings, so plain sha256 is enough here (unlike passwords).
+pub(crate) fn hash_api_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
 }
 
-fn extract_access_token<T>(req: &Request<T>) -> Option<String> {
-    let headers = req.metadata().clone().into_headers();
-    headers.get("x-access-token").map(|v| v.to_str().unwrap().to_owned())
+fn generate_api_key() -> String {
+    let mut rng = rand::thread_rng();
+    format!("{}{}", API_KEY_PREFIX, Alphanumeric.sample_iter(&mut rng)
+        .take(40)
+        .map(char::from)
+        .collect::<String>())
 }
 
 fn generate_task_id() -> TaskId {
@@ -414,4 +1127,286 @@ fn generate_task_id() -> TaskId {
         .take(14)
         .map(char::from)
         .collect())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::{collections::HashSet, sync::Arc, time::Duration},
+        tonic::{Code, Request, Status},
+        axum::http::{HeaderMap, StatusCode},
+        config::Config,
+        jsonwebtoken::{EncodingKey, DecodingKey},
+        sqlx::PgPool,
+        ulid::Ulid,
+        rpc::sandbox_service_server::SandboxService,
+        crate::{
+            access::{Permission, TASK_TOKEN_HEADER, generate_task_token},
+            auth::{AuthProviders, local_accounts::LocalAccountsConfig, policy::{Principal, Credential}},
+            entities::{TaskId, AssetId, UserId, TaskParams, TaskVisibility},
+            state::database::Database,
+            quotas::Quotas,
+        },
+        super::{SandboxServiceHandler, rest::authorize_asset_request},
+    };
+
+    const PROTO: &str = include_str!("../../../rpc/proto/sandbox.proto");
+
+    // key pair used only by tests.
+    const TEST_ENCODING_KEY: &str = include_str!("testdata/encoding_key.pem");
+    const TEST_DECODING_KEY: &str = include_str!("testdata/decoding_key.pem");
+
+    // rpcs which take a task, with the permission they require.
+    const TASK_RPCS: [(&str, Permission); 10] = [
+        ("GetTask", Permission::View),
+        ("WatchTask", Permission::View),
+        ("GetTaskEvents", Permission::View),
+        ("AddChatUserMessage", Permission::Modify),
+        ("CancelTask", Permission::Modify),
+        ("ExtendTask", Permission::Modify),
+        ("RetryTask", Permission::Modify),
+        ("DeleteTask", Permission::Modify),
+        ("DeleteTaskAsset", Permission::Modify),
+        ("SetTaskVisibility", Permission::Modify),
+    ];
+
+    // rpcs which work with tasks of the caller only, or are not about tasks at all.
+    const OTHER_USER_RPCS: [&str; 11] = [
+        "ListAuthProviders",
+        "OAuthLogin",
+        "PasswordLogin",
+        "RegisterLocalAccount",
+        "ChangePassword",
+        "CreateTask",
+        "GetAllTasks",
+        "ListWorkers",
+        "CreateApiKey",
+        "ListApiKeys",
+        "RevokeApiKey",
+    ];
+
+    // workers are allowed to change only the tasks they hold lease for.
+    const WORKER_RPCS: [&str; 8] = [
+        "WorkerSession",
+        "RegisterWorker",
+        "GetTaskToRun",
+        "CreateTaskAsset",
+        "GetChatMessages",
+        "AddChatAssistantMessage",
+        "UpdateTaskStatus",
+        "ExtendTaskLease",
+    ];
+
+    #[derive(Debug, Clone, Copy)]
+    enum Intruder {
+        OtherUser,
+        Anonymous,
+        WrongTaskToken,
+    }
+
+    const INTRUDERS: [Intruder; 3] = [Intruder::OtherUser, Intruder::Anonymous, Intruder::WrongTaskToken];
+
+    struct TestTask {
+        id: TaskId,
+        asset_id: AssetId,
+        visibility: TaskVisibility,
+        owner_user_id: Option<String>,
+        task_token: Option<String>,
+    }
+
+    #[test]
+    fn every_rpc_is_classified() {
+        let classified: HashSet<&str> = TASK_RPCS.iter().map(|v| v.0)
+            .chain(OTHER_USER_RPCS)
+            .chain(WORKER_RPCS)
+            .collect();
+        let rpcs: HashSet<&str> = PROTO.lines()
+            .filter_map(|v| v.trim().strip_prefix("rpc "))
+            .map(|v| v.split('(').next().unwrap().trim())
+            .collect();
+
+        assert_eq!(rpcs, classified);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn task_rpcs_reject_other_callers(pool: PgPool) {
+        let database = Arc::new(Database::for_tests(pool).await.unwrap());
+        let handler = test_handler(database.clone()).await;
+        let tasks = create_test_tasks(&database).await;
+
+        for task in &tasks {
+            for intruder in INTRUDERS {
+                for (rpc, permission) in TASK_RPCS {
+                    let result = call_task_rpc(&handler, rpc, task, intruder).await;
+
+                    match (&task.visibility, permission) {
+                        (TaskVisibility::Private, _) => assert_code(result, Code::NotFound, rpc, task, intruder),
+                        (_, Permission::Modify) => assert_code(result, Code::PermissionDenied, rpc, task, intruder),
+                        (_, Permission::View) => assert!(result.is_ok(), "{} on {:?} task as {:?}: {:?}", rpc, task.visibility, intruder, result),
+                    }
+                }
+            }
+        }
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn assets_reject_other_callers(pool: PgPool) {
+        let database = Arc::new(Database::for_tests(pool).await.unwrap());
+        let handler = test_handler(database.clone()).await;
+        let decoding_key = DecodingKey::from_rsa_pem(TEST_DECODING_KEY.as_bytes()).unwrap();
+        let tasks = create_test_tasks(&database).await;
+
+        for task in &tasks {
+            for intruder in INTRUDERS {
+                let mut headers = HeaderMap::new();
+                match intruder {
+                    Intruder::OtherUser => {
+                        let token = handler.issue_token(&UserId::from_string(Ulid::new().to_string()), "other@example.com", "Other User");
+                        headers.insert("x-access-token", token.parse().unwrap());
+                    },
+                    Intruder::Anonymous => {},
+                    Intruder::WrongTaskToken => {
+                        headers.insert(TASK_TOKEN_HEADER, "wrong-task-token".parse().unwrap());
+                    },
+                }
+
+                let result = authorize_asset_request(&database, &decoding_key, &task.asset_id, &headers).await;
+                match task.visibility {
+                    TaskVisibility::Private => assert_eq!(Some(StatusCode::NOT_FOUND), result.err().map(|v| v.status()), "asset of private task as {:?}", intruder),
+                    TaskVisibility::Unlisted | TaskVisibility::Public => assert!(result.is_ok(), "asset of {:?} task as {:?}", task.visibility, intruder),
+                }
+            }
+        }
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn owner_can_modify_task(pool: PgPool) {
+        let database = Arc::new(Database::for_tests(pool).await.unwrap());
+        let handler = test_handler(database.clone()).await;
+
+        for task in create_test_tasks(&database).await {
+            let mut req = Request::new(rpc::SetTaskVisibilityRequest {
+                task_id: Some(task.id.clone().into()),
+                visibility: rpc::TaskVisibility::from(task.visibility).into(),
+            });
+            req.extensions_mut().insert(match &task.owner_user_id {
+                Some(id) => Principal::User { id: id.clone(), credential: Credential::Session },
+                None => Principal::Anonymous,
+            });
+            if let Some(token) = &task.task_token {
+                req.metadata_mut().insert(TASK_TOKEN_HEADER, token.parse().unwrap());
+            }
+
+            assert!(handler.set_task_visibility(req).await.is_ok(), "owner of {:?} task", task.visibility);
+        }
+    }
+
+    async fn test_handler(database: Arc<Database>) -> SandboxServiceHandler {
+        let auth_providers = AuthProviders::from_provider_configs(vec![], LocalAccountsConfig {
+            enabled: false,
+            registration_enabled: false,
+        }).await.unwrap();
+
+        SandboxServiceHandler::new(
+            database,
+            EncodingKey::from_rsa_pem(TEST_ENCODING_KEY.as_bytes()).unwrap(),
+            Arc::new(auth_providers),
+            Arc::new(Quotas::from_config(&Config::default())),
+            Duration::from_secs(60),
+        ).await.unwrap()
+    }
+
+    // every visibility for a task of a user, and unlisted and public for tasks created without an account.
+    async fn create_test_tasks(database: &Database) -> Vec<TestTask> {
+        let (task_token, task_token_hash) = generate_task_token();
+        let owners = [(Some(Ulid::new().to_string()), None), (None, Some(task_token))];
+
+        let mut tasks = Vec::new();
+        for (user_id, task_token) in owners {
+            for visibility in [TaskVisibility::Private, TaskVisibility::Unlisted, TaskVisibility::Public] {
+                if visibility == TaskVisibility::Private && user_id.is_none() {
+                    continue;
+                }
+
+                let id = super::generate_task_id();
+                let params = TaskParams::ImageGenerationParams {
+                    prompt: "a lighthouse".to_owned(),
+                    iterations: 10,
+                    number_of_images: 1,
+                };
+
+                let mut usage = database.begin_usage_transaction("test").await.unwrap();
+                database.new_task(&mut usage, user_id.clone(), task_token.as_ref().map(|_| task_token_hash.clone()), &id, &params, visibility, 0, vec![]).await.unwrap();
+                usage.commit().await.unwrap();
+
+                let asset_id = AssetId::parse(&Ulid::new().to_string()).unwrap();
+                database.add_test_task_asset(&id, &asset_id).await.unwrap();
+
+                tasks.push(TestTask {
+                    id,
+                    asset_id,
+                    visibility,
+                    owner_user_id: user_id.clone(),
+                    task_token: task_token.clone(),
+                });
+            }
+        }
+
+        tasks
+    }
+
+    async fn call_task_rpc(handler: &SandboxServiceHandler, rpc: &str, task: &TestTask, intruder: Intruder) -> Result<(), Status> {
+        let task_id = Some(rpc::TaskId::from(task.id.clone()));
+
+        match rpc {
+            "GetTask" => handler.get_task(intruder_request(rpc::GetTaskRequest { id: task_id }, intruder)).await.map(|_| ()),
+            "WatchTask" => handler.watch_task(intruder_request(rpc::WatchTaskRequest { id: task_id }, intruder)).await.map(|_| ()),
+            "GetTaskEvents" => handler.get_task_events(intruder_request(rpc::GetTaskEventsRequest { task_id }, intruder)).await.map(|_| ()),
+            "AddChatUserMessage" => handler.add_chat_user_message(intruder_request(rpc::AddChatUserMessageRequest {
+                task_id,
+                content: "hello".to_owned(),
+            }, intruder)).await.map(|_| ()),
+            "CancelTask" => handler.cancel_task(intruder_request(rpc::CancelTaskRequest { task_id }, intruder)).await.map(|_| ()),
+            "ExtendTask" => handler.extend_task(intruder_request(rpc::ExtendTaskRequest {
+                task_id,
+                number_of_images: 1,
+            }, intruder)).await.map(|_| ()),
+            "RetryTask" => handler.retry_task(intruder_request(rpc::RetryTaskRequest { task_id }, intruder)).await.map(|_| ()),
+            "DeleteTask" => handler.delete_task(intruder_request(rpc::DeleteTaskRequest { task_id }, intruder)).await.map(|_| ()),
+            "DeleteTaskAsset" => handler.delete_task_asset(intruder_request(rpc::DeleteTaskAssetRequest {
+                asset_id: task.asset_id.to_string(),
+            }, intruder)).await.map(|_| ()),
+            "SetTaskVisibility" => handler.set_task_visibility(intruder_request(rpc::SetTaskVisibilityRequest {
+                task_id,
+                visibility: rpc::TaskVisibility::Public.into(),
+            }, intruder)).await.map(|_| ()),
+            other => panic!("no test call for task rpc {}", other),
+        }
+    }
+
+    fn intruder_request<T>(message: T, intruder: Intruder) -> Request<T> {
+        let mut req = Request::new(message);
+
+        let principal = match intruder {
+            Intruder::OtherUser => Principal::User {
+                id: Ulid::new().to_string(),
+                credential: Credential::Session,
+            },
+            Intruder::Anonymous | Intruder::WrongTaskToken => Principal::Anonymous,
+        };
+        req.extensions_mut().insert(principal);
+
+        if let Intruder::WrongTaskToken = intruder {
+            req.metadata_mut().insert(TASK_TOKEN_HEADER, "wrong-task-token".parse().unwrap());
+        }
+
+        req
+    }
+
+    fn assert_code(result: Result<(), Status>, code: Code, rpc: &str, task: &TestTask, intruder: Intruder) {
+        match result {
+            Err(status) => assert_eq!(code, status.code(), "{} on {:?} task as {:?}: {:?}", rpc, task.visibility, intruder, status),
+            Ok(()) => panic!("{} on {:?} task as {:?} is allowed", rpc, task.visibility, intruder),
+        }
+    }
+}
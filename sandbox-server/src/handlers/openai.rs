@@ -0,0 +1,513 @@
+use {
+    std::{sync::Arc, time::Duration, convert::Infallible},
+    serde::Deserialize,
+    serde_json::json,
+    tracing::error,
+    tokio::{sync::{mpsc, broadcast::{self, error::RecvError}}, time::{Instant, timeout_at}},
+    chrono::Utc,
+    base64::{Engine, engine::general_purpose::STANDARD as BASE64},
+    axum::{
+        Extension,
+        Json,
+        response::{Response, IntoResponse, sse::{Sse, Event, KeepAlive}},
+        http::{StatusCode, HeaderMap, header::{AUTHORIZATION, RETRY_AFTER, HeaderValue}},
+    },
+    jsonwebtoken::{EncodingKey, DecodingKey},
+    crate::{
+        entities::{Task, TaskId, TaskStatus, TaskParams, TaskVisibility, ChatMessageRole, ApiKeyScope},
+        state::database::{Database, DatabaseError, DatabaseResult, UsageTransaction},
+        quotas::{Quotas, QuotaError, Requester},
+    },
+    super::{resolve_access_token, generate_task_id, TokenDecodeResult, rest::signed_asset_path},
+};
+
+// requests which take longer than this are cancelled, so that clients do not hang forever when there are no workers.
+const TASK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const IMAGE_GENERATION_ITERATIONS: u32 = 20;
+const MAX_IMAGES_PER_REQUEST: u32 = 10;
+const IMAGE_SIZE: &str = "512x512";
+
+/// Base url of the server as seen by clients, e.g. "https://sandbox.example.com". Image urls are built with it.
+#[derive(Clone)]
+pub struct PublicUrl(pub String);
+
+// cancels the task when request is dropped before the task is done, i.e. when client disconnects.
+struct CancelOnDrop {
+    database: Arc<Database>,
+    task_id: Option<TaskId>,
+}
+
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    model: String,
+    messages: Vec<ChatCompletionMessage>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ChatCompletionMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+pub struct ImageGenerationRequest {
+    prompt: String,
+    n: Option<u32>,
+    size: Option<String>,
+    response_format: Option<String>,
+}
+
+impl CancelOnDrop {
+    fn new(database: Arc<Database>, task_id: TaskId) -> Self {
+        Self {
+            database,
+            task_id: Some(task_id),
+        }
+    }
+
+    fn disarm(mut self) {
+        self.task_id = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(task_id) = self.task_id.take() {
+            let database = self.database.clone();
+            tokio::spawn(async move {
+                cancel_task(&database, &task_id).await;
+            });
+        }
+    }
+}
+
+pub async fn chat_completions(
+    Extension(database): Extension<Arc<Database>>,
+    Extension(quotas): Extension<Arc<Quotas>>,
+    Extension(decoding_key): Extension<DecodingKey>,
+    headers: HeaderMap,
+    Json(req): Json<ChatCompletionRequest>,
+) -> Response {
+    let user_id = match user_id_from_headers(&database, &decoding_key, &headers).await {
+        Ok(v) => v,
+        Err(err) => return err,
+    };
+
+    let messages = match chat_messages_from_request(req.messages) {
+        Ok(v) => v,
+        Err(err) => return err,
+    };
+    let prompt_length = messages.len() as u32;
+
+    let requester = Requester::User(user_id.clone());
+    let params = TaskParams::ChatMessageGenerationParams {};
+    let mut usage = match database.begin_usage_transaction(&requester.key()).await {
+        Ok(v) => v,
+        Err(err) => return database_error(err),
+    };
+    let tokens = match quotas.check_new_task(&mut usage, &requester, &params).await {
+        Ok(v) => v,
+        Err(err) => return quota_exceeded(err),
+    };
+
+    let task_id = generate_task_id();
+    // subscribe before the task is created, so that no update is lost.
+    let mut updates = database.subscribe_to_task_updates();
+    if let Err(err) = create_task(&database, usage, user_id, &task_id, &params, messages, tokens).await {
+        return database_error(err);
+    }
+
+    let completion_id = format!("chatcmpl-{}", task_id.as_str());
+    let created = Utc::now().timestamp();
+
+    if req.stream {
+        return stream_chat_completion(database, task_id, updates, prompt_length, completion_id, created, req.model);
+    }
+
+    let cancel_on_drop = CancelOnDrop::new(database.clone(), task_id.clone());
+    let task = wait_for_task(&database, &task_id, &mut updates).await;
+    cancel_on_drop.disarm();
+
+    let task = match task {
+        Ok(Some(v)) => v,
+        Ok(None) => return task_timed_out(&database, &task_id).await,
+        Err(err) => return database_error(err),
+    };
+
+    if task.status == TaskStatus::Cancelled {
+        return api_error(StatusCode::INTERNAL_SERVER_ERROR, "task was cancelled", "server_error");
+    }
+    if let TaskStatus::Failed { reason, .. } = &task.status {
+        return api_error(StatusCode::INTERNAL_SERVER_ERROR, &format!("task failed: {}", reason), "server_error");
+    }
+
+    let content = match chat_reply(&database, &task_id, prompt_length).await {
+        Ok(v) => v.unwrap_or_default(),
+        Err(err) => return database_error(err),
+    };
+
+    Json(json!({
+        "id": completion_id,
+        "object": "chat.completion",
+        "created": created,
+        "model": req.model,
+        "choices": [{
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": content,
+            },
+            "finish_reason": "stop",
+        }],
+    })).into_response()
+}
+
+fn stream_chat_completion(
+    database: Arc<Database>,
+    task_id: TaskId,
+    mut updates: broadcast::Receiver<TaskId>,
+    prompt_length: u32,
+    completion_id: String,
+    created: i64,
+    model: String,
+) -> Response {
+    let (tx, rx) = mpsc::channel::<Event>(16);
+
+    tokio::spawn(async move {
+        let chunk = |delta: serde_json::Value, finish_reason: Option<&str>| Event::default().data(json!({
+            "id": completion_id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{
+                "index": 0,
+                "delta": delta,
+                "finish_reason": finish_reason,
+            }],
+        }).to_string());
+
+        if tx.send(chunk(json!({ "role": "assistant" }), None)).await.is_err() {
+            cancel_task(&database, &task_id).await;
+            return;
+        }
+
+        let deadline = Instant::now() + TASK_TIMEOUT;
+        let mut sent_content = String::new();
+
+        loop {
+            // stream is closed without the final chunk if task cannot be loaded, so that client knows the reply is incomplete.
+            let (task, reply) = match load_chat_reply(&database, &task_id, prompt_length).await {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("failed to load reply for task {}: {}", task_id.as_str(), err);
+                    return;
+                }
+            };
+
+            // reply is updated in place while it is generated, so only the new part is sent.
+            if let Some(content) = reply {
+                if content.len() > sent_content.len() && content.starts_with(&sent_content) {
+                    let delta = content[sent_content.len()..].to_owned();
+                    if tx.send(chunk(json!({ "content": delta }), None)).await.is_err() {
+                        cancel_task(&database, &task_id).await;
+                        return;
+                    }
+                    sent_content = content;
+                }
+            }
+
+            if let TaskStatus::Failed { reason, .. } = &task.status {
+                error!("task {} failed while streaming reply: {}", task_id.as_str(), reason);
+                return;
+            }
+
+            if !task.status.is_active() {
+                // cancelled reply is reported as a regular one, openai api has no finish reason for it.
+                let _ = tx.send(chunk(json!({}), Some("stop"))).await;
+                let _ = tx.send(Event::default().data("[DONE]")).await;
+                return;
+            }
+
+            let is_updated = tokio::select! {
+                _ = tx.closed() => false,
+                res = timeout_at(deadline, task_updated(&mut updates, &task_id)) => res.is_ok(),
+            };
+
+            if !is_updated {
+                // either client went away or generation took too long.
+                cancel_task(&database, &task_id).await;
+                return;
+            }
+        }
+    });
+
+    let stream = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|v| (Ok::<_, Infallible>(v), rx))
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
+
+pub async fn image_generations(
+    Extension(database): Extension<Arc<Database>>,
+    Extension(quotas): Extension<Arc<Quotas>>,
+    Extension(encoding_key): Extension<EncodingKey>,
+    Extension(decoding_key): Extension<DecodingKey>,
+    Extension(public_url): Extension<PublicUrl>,
+    headers: HeaderMap,
+    Json(req): Json<ImageGenerationRequest>,
+) -> Response {
+    let user_id = match user_id_from_headers(&database, &decoding_key, &headers).await {
+        Ok(v) => v,
+        Err(err) => return err,
+    };
+
+    if req.prompt.trim().is_empty() {
+        return api_error(StatusCode::BAD_REQUEST, "prompt should not be empty", "invalid_request_error");
+    }
+
+    let number_of_images = req.n.unwrap_or(1);
+    if number_of_images == 0 || number_of_images > MAX_IMAGES_PER_REQUEST {
+        return api_error(StatusCode::BAD_REQUEST, &format!("n should be between 1 and {}", MAX_IMAGES_PER_REQUEST), "invalid_request_error");
+    }
+
+    if req.size.as_ref().map(|v| v != IMAGE_SIZE).unwrap_or(false) {
+        return api_error(StatusCode::BAD_REQUEST, &format!("only {} images are supported", IMAGE_SIZE), "invalid_request_error");
+    }
+
+    let return_base64 = match req.response_format.as_deref() {
+        None | Some("url") => false,
+        Some("b64_json") => true,
+        Some(_) => return api_error(StatusCode::BAD_REQUEST, "response_format should be either url or b64_json", "invalid_request_error"),
+    };
+
+    let task_id = generate_task_id();
+    let params = TaskParams::ImageGenerationParams {
+        prompt: req.prompt,
+        iterations: IMAGE_GENERATION_ITERATIONS,
+        number_of_images,
+    };
+
+    let requester = Requester::User(user_id.clone());
+    let mut usage = match database.begin_usage_transaction(&requester.key()).await {
+        Ok(v) => v,
+        Err(err) => return database_error(err),
+    };
+    let tokens = match quotas.check_new_task(&mut usage, &requester, &params).await {
+        Ok(v) => v,
+        Err(err) => return quota_exceeded(err),
+    };
+
+    let mut updates = database.subscribe_to_task_updates();
+    if let Err(err) = create_task(&database, usage, user_id, &task_id, &params, vec![], tokens).await {
+        return database_error(err);
+    }
+    let created = Utc::now().timestamp();
+
+    let cancel_on_drop = CancelOnDrop::new(database.clone(), task_id.clone());
+    let task = wait_for_task(&database, &task_id, &mut updates).await;
+    cancel_on_drop.disarm();
+
+    let task = match task {
+        Ok(Some(v)) => v,
+        Ok(None) => return task_timed_out(&database, &task_id).await,
+        Err(err) => return database_error(err),
+    };
+
+    if task.status == TaskStatus::Cancelled {
+        return api_error(StatusCode::INTERNAL_SERVER_ERROR, "task was cancelled", "server_error");
+    }
+    if let TaskStatus::Failed { reason, .. } = &task.status {
+        return api_error(StatusCode::INTERNAL_SERVER_ERROR, &format!("task failed: {}", reason), "server_error");
+    }
+
+    let assets = match database.get_task_assets(&task_id).await {
+        Ok(v) => v,
+        Err(err) => return database_error(err),
+    };
+
+    let mut data = Vec::new();
+    for asset_id in assets {
+        if return_base64 {
+            let image = match database.get_generated_image(&TaskId::new(asset_id.to_string())).await {
+                Ok(Some(v)) => v,
+                Ok(None) => continue,
+                Err(err) => return database_error(err),
+            };
+            data.push(json!({ "b64_json": BASE64.encode(image) }));
+        } else {
+            // signed url, so that it can be opened without credentials, like urls returned by openai.
+            data.push(json!({ "url": format!("{}{}", public_url.0.trim_end_matches('/'), signed_asset_path(&encoding_key, &asset_id)) }));
+        }
+    }
+
+    Json(json!({
+        "created": created,
+        "data": data,
+    })).into_response()
+}
+
+// system prompt is not supported by chat model, so it is prepended to the first user message instead.
+fn chat_messages_from_request(messages: Vec<ChatCompletionMessage>) -> Result<Vec<(ChatMessageRole, String)>, Response> {
+    let mut system_prompt: Vec<String> = Vec::new();
+    let mut result: Vec<(ChatMessageRole, String)> = Vec::new();
+
+    for message in messages {
+        let role = match message.role.as_str() {
+            "system" => {
+                system_prompt.push(message.content);
+                continue;
+            },
+            "user" => ChatMessageRole::User,
+            "assistant" => ChatMessageRole::Assistant,
+            other => return Err(api_error(StatusCode::BAD_REQUEST, &format!("unsupported message role: {}", other), "invalid_request_error")),
+        };
+
+        let expected_user = result.len() % 2 == 0;
+        if expected_user != matches!(role, ChatMessageRole::User) {
+            return Err(api_error(StatusCode::BAD_REQUEST, "messages should alternate between user and assistant, starting with user", "invalid_request_error"));
+        }
+
+        result.push((role, message.content));
+    }
+
+    if result.len() % 2 == 0 {
+        return Err(api_error(StatusCode::BAD_REQUEST, "last message should be from user", "invalid_request_error"));
+    }
+
+    if !system_prompt.is_empty() {
+        system_prompt.push(result[0].1.clone());
+        result[0].1 = system_prompt.join("\n\n");
+    }
+
+    Ok(result)
+}
+
+async fn create_task(
+    database: &Database,
+    mut usage: UsageTransaction,
+    user_id: String,
+    task_id: &TaskId,
+    params: &TaskParams,
+    messages: Vec<(ChatMessageRole, String)>,
+    tokens: u64,
+) -> DatabaseResult<()> {
+    database.new_task(&mut usage, Some(user_id), None, task_id, params, TaskVisibility::Unlisted, 0, messages).await?;
+    usage.record_usage(task_id, tokens).await?;
+    usage.commit().await
+}
+
+async fn chat_reply(database: &Database, task_id: &TaskId, prompt_length: u32) -> DatabaseResult<Option<String>> {
+    Ok(database.get_chat_messages(task_id).await?
+        .into_iter()
+        .filter(|v| v.index >= prompt_length && matches!(v.role, ChatMessageRole::Assistant))
+        .min_by_key(|v| v.index)
+        .map(|v| v.content))
+}
+
+async fn load_chat_reply(database: &Database, task_id: &TaskId, prompt_length: u32) -> DatabaseResult<(Task, Option<String>)> {
+    let task = database.find_task(task_id).await?.ok_or(DatabaseError::NotFound)?;
+    let reply = chat_reply(database, task_id, prompt_length).await?;
+
+    Ok((task, reply))
+}
+
+// returns task once it is not running anymore, or None if it is taking too long.
+async fn wait_for_task(database: &Database, task_id: &TaskId, updates: &mut broadcast::Receiver<TaskId>) -> DatabaseResult<Option<Task>> {
+    let deadline = Instant::now() + TASK_TIMEOUT;
+
+    loop {
+        let task = database.find_task(task_id).await?.ok_or(DatabaseError::NotFound)?;
+        if !task.status.is_active() {
+            return Ok(Some(task));
+        }
+
+        if timeout_at(deadline, task_updated(updates, task_id)).await.is_err() {
+            return Ok(None);
+        }
+    }
+}
+
+async fn task_updated(updates: &mut broadcast::Receiver<TaskId>, task_id: &TaskId) {
+    loop {
+        match updates.recv().await {
+            Ok(id) if id == *task_id => return,
+            Ok(_) => continue,
+            // some notifications were dropped, so task is checked again just in case.
+            Err(RecvError::Lagged(_)) => return,
+            Err(RecvError::Closed) => std::future::pending::<()>().await,
+        }
+    }
+}
+
+async fn task_timed_out(database: &Database, task_id: &TaskId) -> Response {
+    cancel_task(database, task_id).await;
+    api_error(StatusCode::GATEWAY_TIMEOUT, "task took too long to complete", "server_error")
+}
+
+async fn user_id_from_headers(database: &Database, decoding_key: &DecodingKey, headers: &HeaderMap) -> Result<String, Response> {
+    // openai clients send token (usually an api key) as bearer, but x-access-token works as well for consistency with the rest of the api.
+    let token = headers.get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-access-token").and_then(|v| v.to_str().ok()));
+
+    let token = match token {
+        Some(v) => v,
+        None => return Err(api_error(StatusCode::UNAUTHORIZED, "missing access token", "invalid_request_error")),
+    };
+
+    match resolve_access_token(database, decoding_key, token, ApiKeyScope::CreateTasks).await {
+        Ok(TokenDecodeResult::Token(v)) => Ok(v),
+        Ok(TokenDecodeResult::TokenExpired) => Err(api_error(StatusCode::UNAUTHORIZED, "token expired", "invalid_request_error")),
+        Ok(TokenDecodeResult::InvalidApiKey) => Err(api_error(StatusCode::UNAUTHORIZED, "invalid api key", "invalid_request_error")),
+        Ok(TokenDecodeResult::InsufficientScope) => Err(api_error(StatusCode::FORBIDDEN, "api key is not allowed to create tasks", "invalid_request_error")),
+        Ok(TokenDecodeResult::DecodeError(err)) => {
+            error!("error while decoding token: {:?}", err);
+            Err(api_error(StatusCode::UNAUTHORIZED, "invalid token", "invalid_request_error"))
+        },
+        Err(err) => Err(database_error(err)),
+    }
+}
+
+// cancellation is best effort, lease of the task expires eventually anyway.
+async fn cancel_task(database: &Database, task_id: &TaskId) {
+    if let Err(err) = database.cancel_task(task_id).await {
+        error!("failed to cancel task {}: {}", task_id.as_str(), err);
+    }
+}
+
+fn quota_exceeded(err: QuotaError) -> Response {
+    if let QuotaError::Database(err) = err {
+        return database_error(err);
+    }
+
+    match err.retry_after() {
+        None => api_error(StatusCode::BAD_REQUEST, err.reason(), "invalid_request_error"),
+        Some(retry_after) => {
+            let mut res = api_error(StatusCode::TOO_MANY_REQUESTS, err.reason(), "rate_limit_exceeded");
+            res.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs()));
+            res
+        }
+    }
+}
+
+fn database_error(err: DatabaseError) -> Response {
+    error!("database error: {}", err);
+
+    match err {
+        DatabaseError::NotFound => api_error(StatusCode::NOT_FOUND, "task not found", "invalid_request_error"),
+        _ => api_error(StatusCode::SERVICE_UNAVAILABLE, "service is temporarily unavailable", "server_error"),
+    }
+}
+
+fn api_error(status: StatusCode, message: &str, error_type: &str) -> Response {
+    (status, Json(json!({
+        "error": {
+            "message": message,
+            "type": error_type,
+        },
+    }))).into_response()
+}
@@ -1,20 +1,26 @@
 use {
     std::sync::Arc,
-    serde::Deserialize,
+    serde::{Serialize, Deserialize},
+    chrono::Utc,
+    tracing::error,
     axum::{
         Router, 
         Extension,
         response::Response,
-        extract::Path,
-        routing::get, 
-        http::{StatusCode, header::{CONTENT_TYPE, HeaderValue}}, 
+        extract::{Path, Query},
+        routing::{get, post},
+        http::{StatusCode, HeaderMap, header::{CONTENT_TYPE, HeaderValue}}, 
         body::Body,
     },
+    jsonwebtoken::{EncodingKey, DecodingKey, Algorithm, Validation},
     prometheus::{Registry, TextEncoder},
     crate::{
-        entities::TaskId,
-        state::database::Database,
+        access::{Caller, TaskOperation, TASK_TOKEN_HEADER, authorize},
+        entities::{TaskId, AssetId, ApiKeyScope},
+        state::database::{Database, DatabaseError},
+        quotas::Quotas,
     },
+    super::{resolve_access_token, TokenDecodeResult, openai::{self, PublicUrl}},
 };
 
 #[derive(Deserialize, Debug)]
@@ -22,13 +28,34 @@ pub struct AssetID {
     pub asset_id: String,
 }
 
-pub fn rest_router(metrics: Registry, database: Arc<Database>, encoding_key: jsonwebtoken::EncodingKey) -> Router {
+#[derive(Deserialize, Debug)]
+pub struct AssetQuery {
+    // <img> tags cannot set headers, so assets are requested with signed urls instead of access tokens.
+    pub signature: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AssetUrlClaims {
+    exp: usize,
+    asset_id: String,
+}
+
+// signed urls are valid for one to two hours. Expiry is rounded, so that url of an asset stays the same for an hour
+// and browser can cache the image.
+const ASSET_URL_VALIDITY_SECONDS: usize = 60 * 60;
+
+pub fn rest_router(metrics: Registry, database: Arc<Database>, quotas: Arc<Quotas>, encoding_key: EncodingKey, decoding_key: DecodingKey, public_url: PublicUrl) -> Router {
     Router::new()
         .route("/v1/storage/:asset_id", get(serve_asset))
+        .route("/v1/chat/completions", post(openai::chat_completions))
+        .route("/v1/images/generations", post(openai::image_generations))
         .route("/metrics", get(prometheus_metrics))
         .layer(Extension(database))
+        .layer(Extension(quotas))
         .layer(Extension(metrics))
         .layer(Extension(encoding_key))
+        .layer(Extension(decoding_key))
+        .layer(Extension(public_url))
 }
 
 async fn prometheus_metrics(Extension(metrics): Extension<Registry>) -> String {
@@ -37,17 +64,106 @@ async fn prometheus_metrics(Extension(metrics): Extension<Registry>) -> String {
     encoder.encode_to_string(&metric_families).unwrap()
 }
 
-async fn serve_asset(Extension(database): Extension<Arc<Database>>, Path(asset_id): Path<AssetID>) -> Response<Body> {
-    let body = match database.get_generated_image(&TaskId::new(asset_id.asset_id)).await {
+async fn serve_asset(
+    Extension(database): Extension<Arc<Database>>,
+    Extension(decoding_key): Extension<DecodingKey>,
+    Path(asset_id): Path<AssetID>,
+    Query(query): Query<AssetQuery>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let parsed_asset_id = match AssetId::parse(&asset_id.asset_id) {
         Some(v) => v,
-        None => {
-            let mut res = Response::new(Body::from("not_found"));
-            *res.status_mut() = StatusCode::NOT_FOUND;
+        None => return not_found(),
+    };
+
+    // signed url is only issued to those who can view the task, so there is no need to check access again.
+    let is_signed = query.signature
+        .map(|v| verify_asset_url_signature(&decoding_key, &parsed_asset_id, &v))
+        .unwrap_or(false);
+    if !is_signed {
+        if let Err(res) = authorize_asset_request(&database, &decoding_key, &parsed_asset_id, &headers).await {
             return res;
         }
+    }
+
+    let body = match database.get_generated_image(&TaskId::new(asset_id.asset_id)).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return not_found(),
+        Err(err) => return database_error(err),
     };
 
     let mut res = Response::new(Body::from(body));
     res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
     res
 }
+
+// requests without signature (e.g. from api clients) are authorized with access token and task token headers.
+pub(super) async fn authorize_asset_request(database: &Database, decoding_key: &DecodingKey, asset_id: &AssetId, headers: &HeaderMap) -> Result<(), Response<Body>> {
+    let token = headers.get("x-access-token")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned());
+
+    let user_id = match token {
+        Some(token) => match resolve_access_token(database, decoding_key, &token, ApiKeyScope::ReadOnly).await {
+            Ok(TokenDecodeResult::Token(v)) => Some(v),
+            Ok(TokenDecodeResult::TokenExpired | TokenDecodeResult::InvalidApiKey | TokenDecodeResult::InsufficientScope) => None,
+            Ok(TokenDecodeResult::DecodeError(err)) => {
+                error!("error while decoding token: {:?}", err);
+                None
+            },
+            Err(err) => return Err(database_error(err)),
+        },
+        None => None,
+    };
+
+    let task_id = match database.get_asset_task_id(asset_id).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return Err(not_found()),
+        Err(err) => return Err(database_error(err)),
+    };
+
+    let caller = Caller::new(user_id, headers.get(TASK_TOKEN_HEADER).and_then(|v| v.to_str().ok()).map(|v| v.to_owned()));
+
+    // private assets are reported as missing, so that their existence is not revealed.
+    match database.find_task(&task_id).await {
+        Ok(Some(task)) if authorize(&task, &caller, TaskOperation::GetAsset).is_ok() => Ok(()),
+        Ok(_) => Err(not_found()),
+        Err(err) => Err(database_error(err)),
+    }
+}
+
+/// Path of an asset with signature, which gives access to this asset only and expires within two hours.
+pub(crate) fn signed_asset_path(encoding_key: &EncodingKey, asset_id: &AssetId) -> String {
+    let now = Utc::now().timestamp() as usize;
+    let signature = jsonwebtoken::encode(
+        &jsonwebtoken::Header::new(Algorithm::RS384),
+        &AssetUrlClaims {
+            exp: (now / ASSET_URL_VALIDITY_SECONDS + 2) * ASSET_URL_VALIDITY_SECONDS,
+            asset_id: asset_id.to_string(),
+        },
+        encoding_key,
+    ).unwrap();
+
+    format!("/v1/storage/{}?signature={}", asset_id.to_string(), signature)
+}
+
+fn verify_asset_url_signature(decoding_key: &DecodingKey, asset_id: &AssetId, signature: &str) -> bool {
+    match jsonwebtoken::decode::<AssetUrlClaims>(signature, decoding_key, &Validation::new(Algorithm::RS384)) {
+        Ok(v) => v.claims.asset_id == asset_id.to_string(),
+        Err(_) => false,
+    }
+}
+
+fn not_found() -> Response<Body> {
+    let mut res = Response::new(Body::from("not_found"));
+    *res.status_mut() = StatusCode::NOT_FOUND;
+    res
+}
+
+fn database_error(err: DatabaseError) -> Response<Body> {
+    error!("failed to serve asset: {}", err);
+
+    let mut res = Response::new(Body::from("unavailable"));
+    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    res
+}
@@ -0,0 +1,375 @@
+use {
+    std::{sync::Arc, time::Duration},
+    tracing::{info, warn, error},
+    tokio::{sync::{mpsc, broadcast::error::RecvError}, time::{interval, MissedTickBehavior}},
+    tonic::{Status, Streaming},
+    rpc::{
+        self,
+        WorkerMessage,
+        ServerMessage,
+        worker_message,
+        server_message,
+    },
+    crate::{
+        entities::{TaskId, TaskStatus, TaskParams, TaskKind, ChatMessageRole, MessageId},
+        state::database::{Database, DatabaseResult},
+    },
+};
+
+// pending tasks are also checked periodically, in case a notification was missed.
+const PENDING_TASKS_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct WorkerSession {
+    database: Arc<Database>,
+    worker_id: String,
+    task_kinds: Vec<TaskKind>,
+    lease_duration: Duration,
+
+    outbound: mpsc::Sender<Result<ServerMessage, Status>>,
+    current_task: Option<TaskId>,
+    is_cancellation_sent: bool,
+    // worker keeps running the task until it receives cancellation, so the session stays busy until it reports the end.
+    is_lease_lost: bool,
+    // assistant message which is being generated for the current task right now.
+    partial_message: Option<MessageId>,
+}
+
+impl WorkerSession {
+    pub fn new(
+        database: Arc<Database>,
+        worker_id: String,
+        task_kinds: Vec<TaskKind>,
+        lease_duration: Duration,
+        outbound: mpsc::Sender<Result<ServerMessage, Status>>,
+    ) -> Self {
+        Self {
+            database,
+            worker_id,
+            task_kinds,
+            lease_duration,
+            outbound,
+            current_task: None,
+            is_cancellation_sent: false,
+            is_lease_lost: false,
+            partial_message: None,
+        }
+    }
+
+    pub async fn run(mut self, mut inbound: Streaming<WorkerMessage>) {
+        let mut task_updates = self.database.subscribe_to_task_updates();
+        let mut pending_tasks = self.database.subscribe_to_pending_tasks();
+
+        let mut lease_renewal = interval(self.lease_duration / 3);
+        lease_renewal.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut pending_tasks_check = interval(PENDING_TASKS_CHECK_INTERVAL);
+        pending_tasks_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            let res = tokio::select! {
+                _ = self.outbound.closed() => Ok(false),
+                message = inbound.message() => match message {
+                    Ok(Some(message)) => self.handle_worker_message(message).await,
+                    Ok(None) => Ok(false),
+                    Err(err) => {
+                        warn!("worker session for {} failed: {:?}", self.worker_id, err);
+                        Ok(false)
+                    },
+                },
+                update = task_updates.recv() => match update {
+                    Ok(task_id) => self.handle_task_update(&task_id).await,
+                    Err(RecvError::Lagged(_)) => match self.current_task.clone() {
+                        Some(task_id) => self.handle_task_update(&task_id).await,
+                        None => Ok(true),
+                    },
+                    Err(RecvError::Closed) => Ok(false),
+                },
+                pending_task = pending_tasks.recv() => match pending_task {
+                    Ok(_) | Err(RecvError::Lagged(_)) => self.assign_task_if_idle().await,
+                    Err(RecvError::Closed) => Ok(false),
+                },
+                _ = pending_tasks_check.tick() => self.assign_task_if_idle().await,
+                _ = lease_renewal.tick() => self.renew_lease().await,
+            };
+
+            // session is closed on database errors, worker reconnects and its task is requeued below.
+            let is_connected = match res {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("worker session for {} failed because of database error: {}", self.worker_id, err);
+                    let _ = self.outbound.send(Err(err.into())).await;
+                    false
+                }
+            };
+
+            if !is_connected {
+                break;
+            }
+        }
+
+        info!("worker {} disconnected", self.worker_id);
+
+        // no need to wait for lease to expire, because worker is known to be gone.
+        if let Some(task_id) = self.current_task.take() {
+            if let Err(err) = self.requeue_task(&task_id).await {
+                error!("failed to requeue task {} of disconnected worker {}: {}", task_id.as_str(), self.worker_id, err);
+            }
+        }
+    }
+
+    async fn requeue_task(&mut self, task_id: &TaskId) -> DatabaseResult<()> {
+        if self.database.requeue_task(task_id, &self.worker_id).await? {
+            warn!("task {} is returned to the queue because worker {} disconnected", task_id.as_str(), self.worker_id);
+
+            // reply will be generated from scratch by the next worker.
+            if let Some(message_id) = self.partial_message.take() {
+                self.database.delete_chat_message(&message_id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_task_failure(&mut self, task_id: &TaskId, reason: &str, retryable: bool) -> DatabaseResult<bool> {
+        if self.database.fail_task(task_id, &self.worker_id, reason, retryable).await? {
+            warn!("task {} failed on worker {} ({}), returning it to the queue", task_id.as_str(), self.worker_id, reason);
+        } else {
+            warn!("task {} failed on worker {}: {}", task_id.as_str(), self.worker_id, reason);
+        }
+
+        // reply will be generated from scratch on the next attempt.
+        if let Some(message_id) = self.partial_message.take() {
+            self.database.delete_chat_message(&message_id).await?;
+        }
+
+        self.current_task = None;
+        self.is_cancellation_sent = false;
+
+        self.assign_task_if_idle().await
+    }
+
+    async fn handle_worker_message(&mut self, message: WorkerMessage) -> DatabaseResult<bool> {
+        let message = match message.message {
+            Some(v) => v,
+            None => return Ok(true),
+        };
+
+        match message {
+            worker_message::Message::Register(_) => {
+                warn!("worker {} sent registration message again, ignoring", self.worker_id);
+            },
+            worker_message::Message::TaskStatus(status) => {
+                let task_id = match self.current_task_id(status.id) {
+                    Some(v) => v,
+                    None => return Ok(true),
+                };
+
+                let task_status = match status.task_status {
+                    Some(v) => v,
+                    None => {
+                        warn!("worker {} sent status update without status, ignoring", self.worker_id);
+                        return Ok(true);
+                    },
+                };
+
+                // task may already run on another worker, so nothing is saved, session only waits for this worker to stop.
+                if !self.is_lease_held(&task_id).await? {
+                    return match task_status {
+                        rpc::update_task_status_request::TaskStatus::InProgress(_) => Ok(true),
+                        rpc::update_task_status_request::TaskStatus::Finished(_) | rpc::update_task_status_request::TaskStatus::Failed(_) => {
+                            info!("worker {} stopped working on task {} after losing its lease", self.worker_id, task_id.as_str());
+                            self.current_task = None;
+                            self.assign_task_if_idle().await
+                        },
+                    };
+                }
+                let task_status = match task_status {
+                    rpc::update_task_status_request::TaskStatus::InProgress(in_progress) => TaskStatus::InProgress {
+                        current_image: in_progress.current_image,
+                        current_step: in_progress.current_step,
+                        total_steps: in_progress.total_steps,
+                    },
+                    rpc::update_task_status_request::TaskStatus::Finished(_) => TaskStatus::Finished,
+                    rpc::update_task_status_request::TaskStatus::Failed(failed) => return self.handle_task_failure(&task_id, &failed.reason, failed.retryable).await,
+                };
+
+                let is_finished = task_status == TaskStatus::Finished;
+                let is_cancelled = !self.database.save_task_status_unless_cancelled(&task_id, &self.worker_id, &task_status).await?;
+
+                if is_finished {
+                    if is_cancelled {
+                        self.database.release_task_lease(&task_id, &self.worker_id).await?;
+                    }
+
+                    info!("task {} is finished by worker {}", task_id.as_str(), self.worker_id);
+                    self.current_task = None;
+                    self.is_cancellation_sent = false;
+                    self.partial_message = None;
+
+                    return self.assign_task_if_idle().await;
+                } else if is_cancelled && !self.is_cancellation_sent {
+                    return Ok(self.send_cancellation(&task_id).await);
+                }
+            },
+            worker_message::Message::TaskAsset(asset) => {
+                if let Some(task_id) = self.leased_task_id(asset.task_id).await? {
+                    self.database.create_task_asset(&task_id, asset.image).await?;
+                }
+            },
+            worker_message::Message::ChatAssistantMessageProgress(progress) => {
+                if let Some(task_id) = self.leased_task_id(progress.task_id).await? {
+                    match self.partial_message.as_ref() {
+                        Some(message_id) => self.database.update_chat_message_content(message_id, progress.content).await?,
+                        None => self.partial_message = Some(self.database.append_chat_message(&task_id, progress.content, ChatMessageRole::Assistant).await?),
+                    }
+                }
+            },
+            worker_message::Message::ChatAssistantMessage(message) => {
+                if let Some(task_id) = self.leased_task_id(message.task_id).await? {
+                    match self.partial_message.take() {
+                        Some(message_id) => self.database.update_chat_message_content(&message_id, message.content).await?,
+                        None => { self.database.append_chat_message(&task_id, message.content, ChatMessageRole::Assistant).await?; },
+                    }
+                }
+            },
+        };
+
+        Ok(true)
+    }
+
+    async fn handle_task_update(&mut self, task_id: &TaskId) -> DatabaseResult<bool> {
+        if self.current_task.as_ref() != Some(task_id) || self.is_cancellation_sent || self.is_lease_lost {
+            return Ok(true);
+        }
+
+        if self.database.is_task_cancelled(task_id).await? {
+            return Ok(self.send_cancellation(task_id).await);
+        }
+
+        Ok(true)
+    }
+
+    async fn assign_task_if_idle(&mut self) -> DatabaseResult<bool> {
+        if self.current_task.is_some() {
+            return Ok(true);
+        }
+
+        let task = match self.database.claim_new_task(&self.worker_id, &self.task_kinds, self.lease_duration).await? {
+            Some(v) => v,
+            None => return Ok(true),
+        };
+        info!("task {} is assigned to worker {}", task.id.as_str(), self.worker_id);
+
+        let chat_messages = match task.params {
+            TaskParams::ChatMessageGenerationParams {} => self.database.get_chat_messages(&task.id).await?
+                .into_iter()
+                .map(|v| rpc::get_chat_messages_response::ChatMessage {
+                    message_id: Some(rpc::MessageId::from(v.message_id)),
+                    content: v.content,
+                    role: rpc::ChatMessageRole::from(v.role).into(),
+                    message_index: v.index,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        let first_image = self.database.images_generated(&task.id).await?;
+
+        self.current_task = Some(task.id.clone());
+        self.is_cancellation_sent = false;
+        self.is_lease_lost = false;
+        self.partial_message = None;
+
+        Ok(self.send(server_message::Message::AssignTask(rpc::TaskAssignment {
+            id: Some(rpc::TaskId::from(task.id)),
+            params: Some(rpc::TaskParams {
+                params: Some(rpc::task_params::Params::from(task.params)),
+                visibility: rpc::TaskVisibility::from(task.visibility).into(),
+            }),
+            chat_messages,
+            first_image,
+        })).await)
+    }
+
+    async fn renew_lease(&mut self) -> DatabaseResult<bool> {
+        self.database.update_worker_last_ping_time(&self.worker_id).await?;
+
+        let task_id = match self.current_task.clone() {
+            Some(v) if !self.is_lease_lost => v,
+            _ => return Ok(true),
+        };
+
+        if !self.database.extend_task_lease(&task_id, &self.worker_id, self.lease_duration).await? {
+            return self.handle_lost_lease(&task_id).await;
+        }
+
+        Ok(true)
+    }
+
+    // should not normally happen while worker is connected, but if it does the task was already handed to someone else.
+    async fn handle_lost_lease(&mut self, task_id: &TaskId) -> DatabaseResult<bool> {
+        error!("worker {} lost lease for task {}", self.worker_id, task_id.as_str());
+        self.is_lease_lost = true;
+        // partial reply belongs to the lost attempt, next worker generates its own.
+        if let Some(message_id) = self.partial_message.take() {
+            self.database.delete_chat_message(&message_id).await?;
+        }
+        Ok(self.send_cancellation(task_id).await)
+    }
+
+    // lease is checked on every update (not only on renewal), because the task can be requeued and claimed by another worker in between.
+    async fn is_lease_held(&mut self, task_id: &TaskId) -> DatabaseResult<bool> {
+        if self.is_lease_lost {
+            return Ok(false);
+        }
+
+        if !self.database.extend_task_lease(task_id, &self.worker_id, self.lease_duration).await? {
+            // closed outbound channel is noticed by the session loop.
+            self.handle_lost_lease(task_id).await?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    async fn send_cancellation(&mut self, task_id: &TaskId) -> bool {
+        self.is_cancellation_sent = true;
+
+        self.send(server_message::Message::CancelTask(rpc::TaskCancellation {
+            task_id: Some(rpc::TaskId::from(task_id.clone())),
+        })).await
+    }
+
+    async fn send(&self, message: server_message::Message) -> bool {
+        self.outbound.send(Ok(ServerMessage { message: Some(message) })).await.is_ok()
+    }
+
+    // updates of a task with lost lease are not saved, because the task may be already running on another worker.
+    async fn leased_task_id(&mut self, task_id: Option<rpc::TaskId>) -> DatabaseResult<Option<TaskId>> {
+        let task_id = match self.current_task_id(task_id) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        Ok(if self.is_lease_held(&task_id).await? {
+            Some(task_id)
+        } else {
+            None
+        })
+    }
+
+    fn current_task_id(&self, task_id: Option<rpc::TaskId>) -> Option<TaskId> {
+        let task_id = match task_id {
+            Some(v) => TaskId::from(v),
+            None => {
+                warn!("worker {} reported update without task id, ignoring", self.worker_id);
+                return None;
+            }
+        };
+
+        if self.current_task.as_ref() != Some(&task_id) {
+            warn!("worker {} reported update for task {} which is not assigned to it, ignoring", self.worker_id, task_id.as_str());
+            return None;
+        }
+
+        Some(task_id)
+    }
+}
@@ -1,17 +1,22 @@
 use {
     tracing::info,
     crate::{
+        auth::local_accounts::run_create_user_command,
         server::run_server,
+        state::benchmark::run_task_listing_benchmark_command,
         worker::run_worker,
         utils::{init_logging, load_config},
     },
 };
 
+pub mod access;
+pub mod auth;
 pub mod entities;
 pub mod handlers;
 pub mod state;
 pub mod worker;
 pub mod server;
+pub mod quotas;
 pub mod utils;
 
 #[tokio::main]
@@ -20,6 +25,17 @@ async fn main() -> std::io::Result<()> {
 
     let config = load_config();
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|v| v.as_str()) == Some("create-user") {
+        run_create_user_command(&config, args.get(2).map(|v| v.as_str())).await;
+        return Ok(());
+    }
+
+    if args.get(1).map(|v| v.as_str()) == Some("bench-task-listing") {
+        run_task_listing_benchmark_command(&config, args.get(2).map(|v| v.as_str())).await;
+        return Ok(());
+    }
+
     if config.get_bool("server.enabled").unwrap_or(true) {
         run_server(&config).await;
     }
@@ -0,0 +1,211 @@
+use {
+    std::{net::{IpAddr, SocketAddr}, time::Duration},
+    config::Config,
+    chrono::Utc,
+    tonic::{Status, metadata::MetadataValue},
+    crate::{
+        entities::{TaskParams, Usage},
+        state::database::{UsageTransaction, DatabaseError},
+    },
+};
+
+// active tasks are usually finished within a minute, so there is no point in waiting for the exact moment.
+const ACTIVE_TASKS_RETRY_AFTER: Duration = Duration::from_secs(30);
+const USAGE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct QuotaLimits {
+    pub max_images_per_task: u32,
+    pub max_steps_per_task: u32,
+    pub max_active_tasks: u32,
+    pub daily_tasks: u32,
+    pub daily_tokens: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Quotas {
+    users: QuotaLimits,
+    anonymous: QuotaLimits,
+    // image generation uses (number of images * steps) tokens, chat reply uses fixed amount.
+    chat_reply_tokens: u64,
+    // enable only when running behind a proxy which sets this header, otherwise it can be spoofed by clients.
+    trust_x_forwarded_for: bool,
+}
+
+pub enum Requester {
+    User(String),
+    Anonymous(Option<IpAddr>),
+}
+
+#[derive(Debug)]
+pub enum QuotaError {
+    TooManyImages { max: u32 },
+    TooManySteps { max: u32 },
+    TooManyActiveTasks,
+    DailyTasksExceeded { retry_after: Duration },
+    DailyTokensExceeded { retry_after: Duration },
+    Database(DatabaseError),
+}
+
+impl QuotaLimits {
+    fn from_config(config: &Config, section: &str, default: QuotaLimits) -> Self {
+        let get = |name: &str, default: u64| config.get_int(&format!("quotas.{}.{}", section, name)).map(|v| v as u64).unwrap_or(default);
+
+        Self {
+            max_images_per_task: get("max_images_per_task", default.max_images_per_task as u64) as u32,
+            max_steps_per_task: get("max_steps_per_task", default.max_steps_per_task as u64) as u32,
+            max_active_tasks: get("max_active_tasks", default.max_active_tasks as u64) as u32,
+            daily_tasks: get("daily_tasks", default.daily_tasks as u64) as u32,
+            daily_tokens: get("daily_tokens", default.daily_tokens),
+        }
+    }
+}
+
+impl Quotas {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            users: QuotaLimits::from_config(config, "users", QuotaLimits {
+                max_images_per_task: 16,
+                max_steps_per_task: 100,
+                max_active_tasks: 5,
+                daily_tasks: 500,
+                daily_tokens: 200_000,
+            }),
+            anonymous: QuotaLimits::from_config(config, "anonymous", QuotaLimits {
+                max_images_per_task: 4,
+                max_steps_per_task: 50,
+                max_active_tasks: 1,
+                daily_tasks: 50,
+                daily_tokens: 10_000,
+            }),
+            chat_reply_tokens: config.get_int("quotas.chat_reply_tokens").map(|v| v as u64).unwrap_or(500),
+            trust_x_forwarded_for: config.get_bool("quotas.trust_x_forwarded_for").unwrap_or(false),
+        }
+    }
+
+    pub fn requester(&self, user_id: Option<String>, headers: &http::HeaderMap, remote_addr: Option<SocketAddr>) -> Requester {
+        match user_id {
+            Some(v) => Requester::User(v),
+            None => {
+                let forwarded_for = if self.trust_x_forwarded_for {
+                    headers.get("x-forwarded-for")
+                        .and_then(|v| v.to_str().ok())
+                        // first address is the client, the rest are proxies.
+                        .and_then(|v| v.split(',').next())
+                        .and_then(|v| v.trim().parse().ok())
+                } else {
+                    None
+                };
+
+                Requester::Anonymous(forwarded_for.or(remote_addr.map(|v| v.ip())))
+            }
+        }
+    }
+
+    pub fn task_tokens(&self, params: &TaskParams) -> u64 {
+        match params {
+            TaskParams::ImageGenerationParams { iterations, number_of_images, prompt: _ } => *iterations as u64 * *number_of_images as u64,
+            TaskParams::ChatMessageGenerationParams {} => self.chat_reply_tokens,
+        }
+    }
+
+    /// Returns amount of tokens the task uses, so that it can be recorded once the task is created in the same transaction.
+    pub async fn check_new_task(&self, usage: &mut UsageTransaction, requester: &Requester, params: &TaskParams) -> Result<u64, QuotaError> {
+        self.check_task(usage, requester, params, params).await
+    }
+
+    /// Only added images are counted as usage, while limits on task parameters apply to the extended task.
+    pub async fn check_extended_task(&self, usage: &mut UsageTransaction, requester: &Requester, extended: &TaskParams, added: &TaskParams) -> Result<u64, QuotaError> {
+        self.check_task(usage, requester, extended, added).await
+    }
+
+    async fn check_task(&self, usage: &mut UsageTransaction, requester: &Requester, params: &TaskParams, usage_params: &TaskParams) -> Result<u64, QuotaError> {
+        let limits = match requester {
+            Requester::User(_) => &self.users,
+            Requester::Anonymous(_) => &self.anonymous,
+        };
+
+        if let TaskParams::ImageGenerationParams { iterations, number_of_images, prompt: _ } = params {
+            if *number_of_images > limits.max_images_per_task {
+                return Err(QuotaError::TooManyImages { max: limits.max_images_per_task });
+            }
+            if *iterations > limits.max_steps_per_task {
+                return Err(QuotaError::TooManySteps { max: limits.max_steps_per_task });
+            }
+        }
+
+        let tokens = self.task_tokens(usage_params);
+        let usage = usage.get_usage().await.map_err(QuotaError::Database)?;
+
+        if usage.active_tasks >= limits.max_active_tasks {
+            return Err(QuotaError::TooManyActiveTasks);
+        }
+        if usage.daily_tasks >= limits.daily_tasks {
+            return Err(QuotaError::DailyTasksExceeded { retry_after: retry_after(&usage) });
+        }
+        if usage.daily_tokens + tokens > limits.daily_tokens {
+            return Err(QuotaError::DailyTokensExceeded { retry_after: retry_after(&usage) });
+        }
+
+        Ok(tokens)
+    }
+}
+
+impl Requester {
+    pub fn key(&self) -> String {
+        match self {
+            Self::User(id) => format!("user:{}", id),
+            Self::Anonymous(Some(ip)) => format!("ip:{}", ip),
+            // all clients with unknown address share the same quota.
+            Self::Anonymous(None) => "ip:unknown".to_owned(),
+        }
+    }
+}
+
+impl QuotaError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::TooManyImages { .. } => "too_many_images",
+            Self::TooManySteps { .. } => "too_many_steps",
+            Self::TooManyActiveTasks => "too_many_active_tasks",
+            Self::DailyTasksExceeded { .. } => "daily_tasks_exceeded",
+            Self::DailyTokensExceeded { .. } => "daily_tokens_exceeded",
+            Self::Database(_) => "quota_check_failed",
+        }
+    }
+
+    /// Limits on task parameters cannot be fixed by waiting, so there is no retry hint for them.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::TooManyImages { .. } | Self::TooManySteps { .. } | Self::Database(_) => None,
+            Self::TooManyActiveTasks => Some(ACTIVE_TASKS_RETRY_AFTER),
+            Self::DailyTasksExceeded { retry_after } | Self::DailyTokensExceeded { retry_after } => Some(*retry_after),
+        }
+    }
+}
+
+impl From<QuotaError> for Status {
+    fn from(err: QuotaError) -> Self {
+        if let QuotaError::Database(err) = err {
+            return err.into();
+        }
+
+        match err.retry_after() {
+            None => Status::invalid_argument(err.reason()),
+            Some(retry_after) => {
+                let mut status = Status::resource_exhausted(err.reason());
+                status.metadata_mut().insert("retry-after", MetadataValue::from(retry_after.as_secs()));
+                status
+            }
+        }
+    }
+}
+
+// oldest request leaves the usage window first, freeing some of the quota.
+fn retry_after(usage: &Usage) -> Duration {
+    let elapsed = usage.oldest_event_at
+        .and_then(|v| (Utc::now() - v).to_std().ok())
+        .unwrap_or_default();
+
+    USAGE_WINDOW.saturating_sub(elapsed).max(Duration::from_secs(1))
+}
@@ -0,0 +1,28 @@
+use {
+    std::time::Duration,
+    tokio::time::sleep,
+    tracing::{warn, error},
+    crate::state::database::Database,
+};
+
+pub async fn requeue_tasks_with_expired_lease(database: &Database) {
+    loop {
+        sleep(Duration::from_secs(10)).await;
+
+        let task_ids = match database.requeue_tasks_with_expired_lease().await {
+            Ok(v) => v,
+            Err(err) => {
+                error!("failed to requeue tasks with expired lease: {}", err);
+                continue;
+            }
+        };
+
+        for (task_id, is_requeued) in task_ids {
+            if is_requeued {
+                warn!("lease expired for task {}, returning it to the queue", task_id.as_str());
+            } else {
+                warn!("lease expired for task {} which has no attempts left, marking it as failed", task_id.as_str());
+            }
+        }
+    }
+}
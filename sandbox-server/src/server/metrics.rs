@@ -1,11 +1,26 @@
 use {
     std::time::Duration,
     tokio::time::sleep,
+    tracing::error,
+    chrono::Utc,
     config::Config,
-    prometheus::{Registry, TextEncoder, register_int_gauge_vec_with_registry, register_int_gauge_with_registry},
-    crate::state::database::Database,
+    prometheus::{
+        Registry,
+        TextEncoder,
+        register_int_gauge_vec_with_registry,
+        register_int_gauge_with_registry,
+        register_histogram_with_registry,
+        register_histogram_vec_with_registry,
+    },
+    crate::{
+        entities::{TaskPhase, TaskStatusKind},
+        state::database::{Database, DatabaseResult},
+    },
 };
 
+const QUEUE_WAIT_BUCKETS: [f64; 11] = [0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0];
+const RUN_DURATION_BUCKETS: [f64; 12] = [1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0];
+
 pub struct MetricsPushConfig {
     endpoint: String,
     username: String,
@@ -26,17 +41,67 @@ pub async fn collect_metrics(registry: Registry, database: &Database) {
     let total_tasks_by_state = register_int_gauge_vec_with_registry!("tasks_state", "total tasks in pending state", &["state"], registry).unwrap();
     let task_pending_time_max = register_int_gauge_with_registry!("task_pending_time_max", "max pending time of all tasks in pending state", registry).unwrap();
     let workers_total_active = register_int_gauge_with_registry!("workers_active_total", "number of active workers", registry).unwrap();
-    
+    let worker_active = register_int_gauge_vec_with_registry!("worker_active", "1 if worker pinged server recently, 0 otherwise", &["worker_id", "hostname", "version"], registry).unwrap();
+    let worker_since_last_ping = register_int_gauge_vec_with_registry!("worker_since_last_ping_seconds", "time since worker last pinged server", &["worker_id", "hostname", "version"], registry).unwrap();
+    let task_queue_wait = register_histogram_with_registry!("task_queue_wait_seconds", "time from task creation or requeue until a worker claims it", QUEUE_WAIT_BUCKETS.to_vec(), registry).unwrap();
+    let task_run_duration = register_histogram_vec_with_registry!("task_run_duration_seconds", "time from task claim until it ends", &["outcome"], RUN_DURATION_BUCKETS.to_vec(), registry).unwrap();
+
+    // histograms only count phases which end after the server starts, earlier ones were reported by previous instance.
+    let mut last_task_event_id = None;
+
     loop {
         sleep(Duration::from_secs(10)).await;
 
-        total_tasks_by_state.with_label_values(&["pending"]).set(database.total_pending_tasks().await as i64);
-        total_tasks_by_state.with_label_values(&["in_progress"]).set(database.total_in_progress_tasks().await as i64);
-        total_tasks_by_state.with_label_values(&["finished"]).set(database.finished_tasks_within_last_day().await as i64);
+        // metrics are skipped for this round if database is not reachable, next attempt is made in a few seconds.
+        let res: DatabaseResult<()> = async {
+            total_tasks_by_state.with_label_values(&["pending"]).set(database.total_pending_tasks().await? as i64);
+            total_tasks_by_state.with_label_values(&["in_progress"]).set(database.total_in_progress_tasks().await? as i64);
+            total_tasks_by_state.with_label_values(&["finished"]).set(database.finished_tasks_within_last_day().await? as i64);
+            total_tasks_by_state.with_label_values(&["failed"]).set(database.failed_tasks_within_last_day().await? as i64);
+
+            task_pending_time_max.set(database.get_max_task_pending_time().await?.map(|v| v.as_secs()).unwrap_or(0) as i64);
+
+            workers_total_active.set(database.total_active_workers().await? as i64);
+
+            let after_event_id = match last_task_event_id {
+                Some(v) => v,
+                None => database.last_task_event_id().await?,
+            };
+            // events committed out of order around the previous collection may be missed, which is fine for metrics.
+            let phases = database.task_phase_durations(after_event_id).await?;
+            last_task_event_id = Some(phases.last().map(|v| v.event_id).unwrap_or(after_event_id));
+            for phase in phases {
+                match phase.phase {
+                    TaskPhase::QueueWait => task_queue_wait.observe(phase.duration.as_secs_f64()),
+                    TaskPhase::Run(outcome) => task_run_duration.with_label_values(&[run_outcome_label(outcome)]).observe(phase.duration.as_secs_f64()),
+                }
+            }
 
-        task_pending_time_max.set(database.get_max_task_pending_time().await.map(|v| v.as_secs()).unwrap_or(0).try_into().unwrap());
+            let workers = database.get_workers().await?;
+            worker_active.reset();
+            worker_since_last_ping.reset();
+            for worker in workers {
+                let labels = [worker.id.as_str(), worker.hostname.as_str(), worker.version.as_str()];
+                worker_active.with_label_values(&labels).set(if worker.is_active() { 1 } else { 0 });
+                worker_since_last_ping.with_label_values(&labels).set((Utc::now() - worker.last_ping_at).num_seconds());
+            }
+
+            Ok(())
+        }.await;
+
+        if let Err(err) = res {
+            error!("failed to collect metrics: {}", err);
+        }
+    }
+}
 
-        workers_total_active.set(database.total_active_workers().await.try_into().unwrap());
+fn run_outcome_label(outcome: TaskStatusKind) -> &'static str {
+    match outcome {
+        TaskStatusKind::Pending => "pending",
+        TaskStatusKind::InProgress => "in_progress",
+        TaskStatusKind::Finished => "finished",
+        TaskStatusKind::Cancelled => "cancelled",
+        TaskStatusKind::Failed => "failed",
     }
 }
 
@@ -1,12 +1,14 @@
 use {
-    std::sync::Arc,
+    std::{sync::Arc, time::Duration, convert::Infallible},
     tracing::info,
     config::Config,
-    axum::Router,
+    axum::{Router, Extension, extract::ConnectInfo},
+    hyper::{server::conn::AddrStream, service::make_service_fn},
     axum_tonic::{NestTonic, RestGrpcService},
     anyhow::Result,
     futures::join,
     tonic::transport::Server,
+    tower::Layer,
     jsonwebtoken::{EncodingKey, DecodingKey},
     prometheus::Registry,
     futures::FutureExt,
@@ -15,12 +17,18 @@ use {
         FILE_DESCRIPTOR_SET,
     },
     crate::{
-        handlers::{SandboxServiceHandler, rest::rest_router},
+        auth::{AuthProviders, middleware::{Authenticator, AuthLayer}},
+        quotas::Quotas,
+        handlers::{SandboxServiceHandler, rest::rest_router, openai::PublicUrl},
         state::database::Database,
     },
-    self::metrics::{MetricsPushConfig, collect_metrics, push_metrics},
+    self::{
+        metrics::{MetricsPushConfig, collect_metrics, push_metrics},
+        leases::requeue_tasks_with_expired_lease,
+    },
 };
 
+pub mod leases;
 pub mod metrics;
 
 pub async fn run_server(config: &Config) {
@@ -30,10 +38,15 @@ pub async fn run_server(config: &Config) {
     let encoding_key = jsonwebtoken::EncodingKey::from_rsa_pem(config.get_string("auth.encoding_key").unwrap().as_bytes()).unwrap();
     let decoding_key = DecodingKey::from_rsa_pem(&config.get_string("token.decoding_key").unwrap().as_bytes()).unwrap();
     let worker_token = config.get_string("token.worker_token").unwrap();
-    let oauth_secret = config.get("auth.oauth_client_secret").unwrap();
+    let admins = config.get::<Vec<String>>("auth.admins").unwrap_or_default();
+    let authenticator = Arc::new(Authenticator::new(database.clone(), decoding_key.clone(), worker_token, admins));
+    let auth_providers = Arc::new(AuthProviders::from_config(config).await.unwrap());
+    let quotas = Arc::new(Quotas::from_config(config));
+    let task_lease_duration = Duration::from_secs(config.get_int("tasks.lease_duration_seconds").unwrap_or(60) as u64);
     
-    let axum_server = run_axum_server(config, metrics.clone(), database.clone(), encoding_key.clone(), decoding_key.clone(), worker_token.clone());
-    let grpc_server = run_grpc_server(config, database.clone(), encoding_key, decoding_key, worker_token, oauth_secret);
+    let axum_server = run_axum_server(config, metrics.clone(), database.clone(), encoding_key.clone(), decoding_key, authenticator.clone(), auth_providers.clone(), quotas.clone(), task_lease_duration);
+    let grpc_server = run_grpc_server(config, database.clone(), encoding_key, authenticator, auth_providers, quotas, task_lease_duration);
+    let lease_reaper = requeue_tasks_with_expired_lease(&database);
     
     let metrics_collector = collect_metrics(metrics.clone(), &database);
     let metrics_pusher = if config.get_bool("metrics_push.enabled").unwrap_or(false) {
@@ -42,32 +55,41 @@ pub async fn run_server(config: &Config) {
         do_nothing().boxed()
     };
 
-    join!(axum_server, grpc_server, metrics_collector, metrics_pusher);
+    join!(axum_server, grpc_server, lease_reaper, metrics_collector, metrics_pusher);
 }
 
-pub async fn run_axum_server(config: &Config, metrics: Registry, database: Arc<Database>, encoding_key: EncodingKey, decoding_key: DecodingKey, worker_token: String) {
+pub async fn run_axum_server(config: &Config, metrics: Registry, database: Arc<Database>, encoding_key: EncodingKey, decoding_key: DecodingKey, authenticator: Arc<Authenticator>, auth_providers: Arc<AuthProviders>, quotas: Arc<Quotas>, task_lease_duration: Duration) {
     let host = config.get_string("server.host").unwrap_or("0.0.0.0".to_owned());
     let port = config.get_int("server.port").unwrap_or(8081);
     let addr = format!("{}:{}", host, port).parse().unwrap();
-    
-    let oauth_client_secret = config.get("auth.oauth_client_secret").unwrap();
+    let public_url = PublicUrl(config.get_string("server.public_url").unwrap_or(format!("http://localhost:{}", port)));
 
     info!("starting axum server on {:?}", addr);
-    
+
+    let service = service(metrics, database, authenticator, auth_providers, quotas, encoding_key, decoding_key, public_url, task_lease_duration).await.unwrap();
+    // tonic does not see client address of grpc-web requests, so it is passed in connect info (used for anonymous quotas).
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let service = Extension(ConnectInfo(conn.remote_addr())).layer(service.clone());
+        async move { Ok::<_, Infallible>(service) }
+    });
+
     axum::Server::bind(&addr)
-        .serve(service(metrics, database, worker_token, oauth_client_secret, encoding_key, decoding_key).await.unwrap().into_make_service())
+        .serve(make_service)
         .await
         .unwrap();
 }
 
-pub async fn run_grpc_server(config: &Config, database: Arc<Database>, encoding_key: EncodingKey, decoding_key: DecodingKey, worker_token: String, oauth_secret: String) {
+pub async fn run_grpc_server(config: &Config, database: Arc<Database>, encoding_key: EncodingKey, authenticator: Arc<Authenticator>, auth_providers: Arc<AuthProviders>, quotas: Arc<Quotas>, task_lease_duration: Duration) {
     let port = config.get_int("server.grpc_port").unwrap_or(8082);
     let addr = format!("0.0.0.0:{}", port).parse().unwrap();
 
     info!("starting grpc server on port {:?}", addr);
 
     Server::builder()
-        .add_service(SandboxServiceServer::new(SandboxServiceHandler::new(database, encoding_key, decoding_key, worker_token, oauth_secret).await.unwrap()))
+        // worker sessions are long-lived, keepalive pings detect workers which disappeared without closing the connection.
+        .http2_keepalive_interval(Some(Duration::from_secs(10)))
+        .http2_keepalive_timeout(Some(Duration::from_secs(20)))
+        .add_service(AuthLayer::new(authenticator).layer(SandboxServiceServer::new(SandboxServiceHandler::new(database, encoding_key, auth_providers, quotas, task_lease_duration).await.unwrap())))
         .serve(addr)
         .await
         .unwrap();
@@ -76,17 +98,20 @@ pub async fn run_grpc_server(config: &Config, database: Arc<Database>, encoding_
 pub async fn service(
     metrics: Registry,
     database: Arc<Database>, 
-    worker_token: String,
-    oauth_client_secret: String,
+    authenticator: Arc<Authenticator>,
+    auth_providers: Arc<AuthProviders>,
+    quotas: Arc<Quotas>,
     encoding_key: EncodingKey,
     decoding_key: DecodingKey,
+    public_url: PublicUrl,
+    task_lease_duration: Duration,
 ) -> Result<RestGrpcService> {
-    let grpc = Router::new().nest("/v1/rpc", grpc_router(database.clone(), encoding_key.clone(), decoding_key, worker_token, oauth_client_secret).await?);
-    let rest = rest_router(metrics, database, encoding_key);
+    let grpc = Router::new().nest("/v1/rpc", grpc_router(database.clone(), encoding_key.clone(), authenticator, auth_providers, quotas.clone(), task_lease_duration).await?);
+    let rest = rest_router(metrics, database, quotas, encoding_key, decoding_key, public_url);
     Ok(RestGrpcService::new(rest, grpc))
 }
 
-async fn grpc_router(database: Arc<Database>, encoding_key: EncodingKey, decoding_key: DecodingKey, worker_token: String, oauth_secret: String) -> Result<Router> {
+async fn grpc_router(database: Arc<Database>, encoding_key: EncodingKey, authenticator: Arc<Authenticator>, auth_providers: Arc<AuthProviders>, quotas: Arc<Quotas>, task_lease_duration: Duration) -> Result<Router> {
     Ok(Router::new()
         .nest_tonic(
             tonic_reflection::server::Builder::configure()
@@ -94,7 +119,7 @@ async fn grpc_router(database: Arc<Database>, encoding_key: EncodingKey, decodin
                 .build()
                 .unwrap()
         )
-        .nest_tonic(tonic_web::enable(SandboxServiceServer::new(SandboxServiceHandler::new(database, encoding_key, decoding_key, worker_token, oauth_secret).await?))))
+        .nest_tonic(tonic_web::enable(AuthLayer::new(authenticator).layer(SandboxServiceServer::new(SandboxServiceHandler::new(database, encoding_key, auth_providers, quotas, task_lease_duration).await?)))))
 }
 
 async fn do_nothing() {
@@ -0,0 +1,113 @@
+use {
+    std::time::{Duration, Instant},
+    tracing::{info, error},
+    config::Config,
+    ulid::Ulid,
+    crate::{
+        entities::{TaskFilter, TaskStatusKind, TaskCursor},
+        state::database::{Database, DatabaseResult},
+    },
+};
+
+const DEFAULT_NUMBER_OF_TASKS: usize = 10_000;
+const PAGE_SIZE: u32 = 50;
+const RUNS: usize = 20;
+// the benchmark also measures a page far from the start, to check that cursor pagination does not scan skipped tasks.
+const DEEP_PAGE: usize = 100;
+
+const SUBJECTS: [&str; 8] = ["cat", "lighthouse", "mountain lake", "robot", "forest", "city at night", "sailing ship", "dragon"];
+const STYLES: [&str; 5] = ["watercolor", "oil painting", "pixel art", "photograph", "pencil sketch"];
+
+// admin command, creates tasks for a new user, measures task history queries and removes the tasks afterwards.
+pub async fn run_task_listing_benchmark_command(config: &Config, number_of_tasks: Option<&str>) {
+    let number_of_tasks = match number_of_tasks.map(|v| v.parse::<usize>()) {
+        None => DEFAULT_NUMBER_OF_TASKS,
+        Some(Ok(v)) if v > 0 => v,
+        Some(_) => {
+            error!("usage: sandbox-server bench-task-listing [number of tasks]");
+            return;
+        }
+    };
+
+    let database = Database::new(config, &config.get_string("database.connection_string").unwrap()).await.unwrap();
+    let user_id = Ulid::new().to_string();
+
+    info!("creating {} tasks for benchmark user {}", number_of_tasks, user_id);
+    let prompts: Vec<String> = (0..number_of_tasks)
+        .map(|i| format!("{}, {}", SUBJECTS[i % SUBJECTS.len()], STYLES[(i / SUBJECTS.len()) % STYLES.len()]))
+        .collect();
+    if let Err(err) = database.create_benchmark_tasks(&user_id, &prompts).await {
+        error!("failed to create benchmark tasks: {}", err);
+        return;
+    }
+
+    if let Err(err) = run_benchmarks(&database, &user_id).await {
+        error!("benchmark failed: {}", err);
+    }
+
+    if let Err(err) = database.delete_benchmark_tasks(&user_id).await {
+        error!("failed to delete benchmark tasks of user {}: {}", user_id, err);
+    }
+}
+
+async fn run_benchmarks(database: &Database, user_id: &str) -> DatabaseResult<()> {
+    let all_tasks = &TaskFilter::default();
+    measure("first page", move || database.get_user_tasks(user_id, all_tasks, None, PAGE_SIZE)).await?;
+
+    let mut cursor = None::<TaskCursor>;
+    for _ in 0..DEEP_PAGE {
+        cursor = database.get_user_tasks(user_id, all_tasks, cursor.as_ref(), PAGE_SIZE).await?.1;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    match &cursor {
+        Some(cursor) => measure(&format!("page {}", DEEP_PAGE + 1), move || database.get_user_tasks(user_id, all_tasks, Some(cursor), PAGE_SIZE)).await?,
+        None => info!("page {}: skipped, there are less than {} tasks", DEEP_PAGE + 1, DEEP_PAGE * PAGE_SIZE as usize),
+    }
+
+    let finished = &TaskFilter {
+        statuses: vec![TaskStatusKind::Finished],
+        ..TaskFilter::default()
+    };
+    measure("status filter", move || database.get_user_tasks(user_id, finished, None, PAGE_SIZE)).await?;
+
+    let search = &TaskFilter {
+        query: Some("lighthouse watercolor".to_owned()),
+        ..TaskFilter::default()
+    };
+    measure("search", move || database.get_user_tasks(user_id, search, None, PAGE_SIZE)).await?;
+
+    Ok(())
+}
+
+async fn measure<F, Fut, T>(name: &str, query: F) -> DatabaseResult<()>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = DatabaseResult<T>>,
+{
+    let mut timings = Vec::with_capacity(RUNS);
+    for _ in 0..RUNS {
+        let started_at = Instant::now();
+        query().await?;
+        timings.push(started_at.elapsed());
+    }
+    timings.sort();
+
+    info!(
+        "{}: p50 {}, p95 {}, max {}",
+        name,
+        format_duration(percentile(&timings, 0.5)),
+        format_duration(percentile(&timings, 0.95)),
+        format_duration(timings[timings.len() - 1]),
+    );
+    Ok(())
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
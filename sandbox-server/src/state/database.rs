@@ -1,12 +1,15 @@
 use {
-    std::time::Duration,
+    std::{fmt, time::Duration},
+    tracing::error,
     anyhow::Result,
-    sqlx::{postgres::PgPoolOptions,types::time::OffsetDateTime},
+    tokio::sync::broadcast,
+    sqlx::{Postgres, Transaction, postgres::{PgPool, PgPoolOptions, PgListener, PgConnection, PgHasArrayType, PgTypeInfo}, types::time::OffsetDateTime},
     config::Config,
     serde::{Serialize, Deserialize},
     s3::{Bucket, creds::Credentials, region::Region, error::S3Error},
     ulid::Ulid,
     chrono::{NaiveDateTime, DateTime, Utc},
+    crate::auth::LOCAL_AUTH_PROVIDER,
     crate::entities::{
         TaskId, 
         TaskStatus, 
@@ -17,16 +20,52 @@ use {
         ChatMessage, 
         MessageId,
         ChatMessageRole,
+        TaskKind,
+        TaskStatusKind,
+        TaskFilter,
+        TaskCursor,
+        TaskEvent,
+        TaskEventKind,
+        TaskPhase,
+        TaskPhaseDuration,
+        TaskVisibility,
+        Worker,
+        ApiKey,
+        ApiKeyScope,
+        Usage,
     },
 };
 
 struct PersistedTask {
     id: String,
-    status: sqlx::types::JsonValue,
+    user_id: Option<String>,
+    capability_token_hash: Option<String>,
+    status_kind: PersistedTaskStatusKind,
+    progress: Option<sqlx::types::JsonValue>,
+    failure_reason: Option<String>,
+    failure_retryable: Option<bool>,
     created_at: OffsetDateTime,
     params: Option<sqlx::types::JsonValue>,
+    visibility: PersistedTaskVisibility,
 }
 
+// task history entry, assets are aggregated in the same query.
+struct PersistedTaskWithAssets {
+    id: String,
+    user_id: Option<String>,
+    capability_token_hash: Option<String>,
+    status_kind: PersistedTaskStatusKind,
+    progress: Option<sqlx::types::JsonValue>,
+    failure_reason: Option<String>,
+    failure_retryable: Option<bool>,
+    created_at: OffsetDateTime,
+    params: Option<sqlx::types::JsonValue>,
+    visibility: PersistedTaskVisibility,
+    asset_ids: Vec<String>,
+}
+
+// written to jsonb status column, which is what older server versions read. Columns derived from it are read instead,
+// see normalize-task-status migration.
 #[derive(Serialize, Deserialize, Debug)]
 enum PersistedTaskStatus {
     Pending,
@@ -36,6 +75,11 @@ enum PersistedTaskStatus {
         current_image: Option<u32>,
     },
     Finished,
+    Cancelled,
+    Failed {
+        reason: String,
+        retryable: bool,
+    },
 }
 
 struct PersistedUserId {
@@ -46,6 +90,48 @@ struct PersistedAssetId {
     id: String,
 }
 
+struct PersistedTaskEvent {
+    kind: PersistedTaskEventKind,
+    worker_id: Option<String>,
+    details: Option<sqlx::types::JsonValue>,
+    created_at: OffsetDateTime,
+}
+
+#[derive(sqlx::Type)]
+#[sqlx(type_name = "task_event_kind", rename_all = "snake_case")]
+enum PersistedTaskEventKind {
+    Created,
+    Claimed,
+    Progress,
+    AssetCreated,
+    Requeued,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Deserialize)]
+struct PersistedClaimDetails {
+    attempt: u32,
+}
+
+#[derive(Deserialize)]
+struct PersistedAssetDetails {
+    asset_id: String,
+}
+
+#[derive(Deserialize)]
+struct PersistedFailureDetails {
+    reason: Option<String>,
+    retryable: Option<bool>,
+}
+
+struct PersistedTaskPhaseDuration {
+    event_id: i64,
+    kind: PersistedTaskEventKind,
+    seconds: f64,
+}
+
 #[derive(Serialize, Deserialize)]
 enum PersistedTaskParams {
     ImageGeneration {
@@ -65,6 +151,63 @@ struct PersistedChatMessage {
     message_index: i32,
 }
 
+struct PersistedWorker {
+    worker_id: String,
+    hostname: String,
+    version: String,
+    task_kinds: Vec<String>,
+    models: Vec<String>,
+    registered_at: OffsetDateTime,
+    last_ping_at: OffsetDateTime,
+}
+
+#[derive(sqlx::Type, PartialEq, Eq, Debug)]
+#[sqlx(type_name = "task_status", rename_all = "snake_case")]
+enum PersistedTaskStatusKind {
+    Pending,
+    InProgress,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+impl PgHasArrayType for PersistedTaskStatusKind {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_task_status")
+    }
+}
+
+#[derive(Deserialize)]
+struct PersistedTaskProgress {
+    current_step: u32,
+    total_steps: u32,
+    current_image: Option<u32>,
+}
+
+#[derive(sqlx::Type)]
+#[sqlx(type_name = "task_visibility", rename_all = "lowercase")]
+enum PersistedTaskVisibility {
+    Private,
+    Unlisted,
+    Public,
+}
+
+struct PersistedApiKey {
+    key_id: String,
+    name: String,
+    scope: PersistedApiKeyScope,
+    key_prefix: String,
+    created_at: OffsetDateTime,
+    last_used_at: Option<OffsetDateTime>,
+}
+
+#[derive(sqlx::Type, Debug)]
+#[sqlx(type_name = "api_key_scope", rename_all = "snake_case")]
+enum PersistedApiKeyScope {
+    ReadOnly,
+    CreateTasks,
+}
+
 #[derive(sqlx::Type)]
 #[sqlx(type_name = "chat_role", rename_all = "lowercase")]
 enum PersistedChatMessageRole {
@@ -73,17 +216,70 @@ enum PersistedChatMessageRole {
     Assistant,
 }
 
+#[derive(Debug)]
+pub enum DatabaseError {
+    NotFound,
+    // connection to the database could not be established, request can be retried later.
+    Unavailable(sqlx::Error),
+    Query(sqlx::Error),
+    Storage(S3Error),
+    Serialization(serde_json::Error),
+}
+
+pub type DatabaseResult<T> = std::result::Result<T, DatabaseError>;
+
+impl From<sqlx::Error> for DatabaseError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => Self::NotFound,
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => Self::Unavailable(err),
+            other => Self::Query(other),
+        }
+    }
+}
+
+impl From<S3Error> for DatabaseError {
+    fn from(err: S3Error) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl From<serde_json::Error> for DatabaseError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Serialization(err)
+    }
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "not found"),
+            Self::Unavailable(err) => write!(f, "database is unavailable: {}", err),
+            Self::Query(err) => write!(f, "query failed: {}", err),
+            Self::Storage(err) => write!(f, "object storage request failed: {}", err),
+            Self::Serialization(err) => write!(f, "failed to (de)serialize stored value: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
 pub struct Database {
     pool: sqlx::postgres::PgPool,
     bucket: s3::Bucket,
+    task_updates: broadcast::Sender<TaskId>,
+    pending_tasks: broadcast::Sender<TaskId>,
+    // task which failed this many times (or whose worker disappeared) is not returned to the queue automatically.
+    max_task_attempts: i32,
 }
 
 impl Database {
     pub async fn new(config: &Config, connection_string: &str) -> Result<Self> {
-        let region = config.get_string("object_storage.region").unwrap();
-        let endpoint = config.get_string("object_storage.endpoint").unwrap();
-        let access_key = config.get_string("object_storage.access_key").unwrap();
-        let secret_key = config.get_string("object_storage.secret_key").unwrap();
+        let region = config.get_string("object_storage.region")?;
+        let endpoint = config.get_string("object_storage.endpoint")?;
+        let access_key = config.get_string("object_storage.access_key")?;
+        let secret_key = config.get_string("object_storage.secret_key")?;
+        let max_task_attempts = config.get_int("tasks.max_attempts").unwrap_or(3) as i32;
 
         let bucket = Bucket::new(
             "sandbox",
@@ -91,23 +287,66 @@ impl Database {
                 region,
                 endpoint,
             },
-            Credentials::new(Some(&access_key), Some(&secret_key), None, None, None).unwrap(),
-        ).unwrap().with_path_style();
+            Credentials::new(Some(&access_key), Some(&secret_key), None, None, None)?,
+        )?.with_path_style();
+
+        let pool = PgPoolOptions::new()
+            .connect(&connection_string)
+            .await?;
+
+        Self::from_pool(pool, bucket, max_task_attempts).await
+    }
+
+    // pools created by sqlx::test are migrated already. Object storage is not available in tests.
+    #[cfg(test)]
+    pub(crate) async fn for_tests(pool: PgPool) -> Result<Self> {
+        let bucket = Bucket::new(
+            "sandbox",
+            Region::Custom {
+                region: "test".to_owned(),
+                endpoint: "http://localhost".to_owned(),
+            },
+            Credentials::new(Some("test"), Some("test"), None, None, None)?,
+        )?.with_path_style();
+
+        Self::from_pool(pool, bucket, 3).await
+    }
+
+    async fn from_pool(pool: PgPool, bucket: Bucket, max_task_attempts: i32) -> Result<Self> {
+        let (task_updates, _) = broadcast::channel(1024);
+        let (pending_tasks, _) = broadcast::channel(1024);
+
+        let mut listener = PgListener::connect_with(&pool).await?;
+        listener.listen_all(["sandbox_task_updates", "sandbox_pending_tasks"]).await?;
+        tokio::spawn(listen_for_task_updates(listener, task_updates.clone(), pending_tasks.clone()));
 
         Ok(Self {
-            pool: PgPoolOptions::new()
-                .connect(&connection_string)
-                .await?,
+            pool,
             bucket,
+            task_updates,
+            pending_tasks,
+            max_task_attempts,
         })
     }
 
-    pub async fn new_task(&self, user_id: Option<String>, id: &TaskId, params: &TaskParams) {
+    /// Notifications are sent by triggers on task tables, see `sandbox_notify_task_update` migration.
+    pub fn subscribe_to_task_updates(&self) -> broadcast::Receiver<TaskId> {
+        self.task_updates.subscribe()
+    }
+
+    /// Notified every time a task becomes pending, see `sandbox_notify_pending_task` migration.
+    pub fn subscribe_to_pending_tasks(&self) -> broadcast::Receiver<TaskId> {
+        self.pending_tasks.subscribe()
+    }
+
+    /// Task is created within usage transaction, so that it is counted as active by quota checks of the next requests.
+    pub async fn new_task(&self, usage: &mut UsageTransaction, user_id: Option<String>, capability_token_hash: Option<String>, id: &TaskId, params: &TaskParams, visibility: TaskVisibility, priority: i32, messages: Vec<(ChatMessageRole, String)>) -> DatabaseResult<()> {
         sqlx::query!(
-            "insert into sandbox_tasks (user_id, task_id, is_pending, status, params) values ($1, $2, true, $3, $4)", 
-            user_id, 
+            "insert into sandbox_tasks (user_id, capability_token_hash, task_id, is_pending, status, params, visibility, priority) values ($1, $2, $3, true, $4, $5, $6, $7)",
+            user_id,
+            capability_token_hash,
             id.as_str(),
-            serde_json::to_value(PersistedTaskStatus::Pending).unwrap(),
+            serde_json::to_value(PersistedTaskStatus::Pending)?,
             serde_json::to_value(match params {
                 TaskParams::ImageGenerationParams { iterations, number_of_images, prompt } => PersistedTaskParams::ImageGeneration {
                     iterations: *iterations,
@@ -116,160 +355,573 @@ impl Database {
                 },
                 TaskParams::ChatMessageGenerationParams {} => PersistedTaskParams::ChatMessageGeneration {
                 },
-            }).unwrap(),
+            })?,
+            persisted_task_visibility(visibility) as PersistedTaskVisibility,
+            priority,
+        )
+            .execute(&mut *usage.tx)
+            .await?;
+
+        // initial messages are inserted together with the task, so that worker never picks up a chat task without them.
+        for (role, content) in messages {
+            insert_next_chat_message(&mut usage.tx, id, content, role).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_task_visibility(&self, id: &TaskId, visibility: TaskVisibility) -> DatabaseResult<()> {
+        sqlx::query!(
+            "update sandbox_tasks set visibility = $1 where task_id = $2",
+            persisted_task_visibility(visibility) as PersistedTaskVisibility,
+            id.as_str()
         )
             .execute(&self.pool)
-            .await
-            .unwrap();
+            .await?;
+
+        Ok(())
     }
 
-    pub async fn get_user_tasks(&self, user_id: &str) -> Vec<Task> {
-        let tasks = sqlx::query_as!(PersistedTask, "select task_id as id, status, created_at, params from sandbox_tasks where user_id = $1 order by created_at desc", user_id)
+    /// Returns a page of user tasks with their assets, newest first, and the cursor of the next page if there are more tasks.
+    pub async fn get_user_tasks(&self, user_id: &str, filter: &TaskFilter, cursor: Option<&TaskCursor>, limit: u32) -> DatabaseResult<(Vec<(Task, Vec<AssetId>)>, Option<TaskCursor>)> {
+        let (cursor_created_at, cursor_task_id) = match cursor {
+            Some(cursor) => (Some(offset_date_time_from_micros(cursor.created_at_micros)), cursor.task_id.as_str()),
+            None => (None, ""),
+        };
+        let kinds: Vec<String> = filter.kinds.iter().map(|v| persisted_task_kind(v).to_owned()).collect();
+        let statuses: Vec<PersistedTaskStatusKind> = filter.statuses.iter().map(persisted_task_status_kind).collect();
+
+        // one extra task is fetched to know if there is a next page.
+        let mut tasks = sqlx::query_as!(PersistedTaskWithAssets, r#"
+            select
+                t.task_id as id, t.user_id, t.capability_token_hash, t.status_kind as "status_kind: _", t.progress, t.failure_reason, t.failure_retryable, t.created_at, t.params, t.visibility as "visibility: _",
+                array(select a.asset_id from sandbox_task_assets a where a.task_id = t.task_id order by a.created_at) as "asset_ids!"
+            from sandbox_tasks t
+            where t.user_id = $1
+                and ($2::timestamptz is null or (t.created_at, t.task_id) < ($2, $3))
+                and (cardinality($4::text[]) = 0 or t.kind = any($4))
+                and (cardinality($5::task_status[]) = 0 or t.status_kind = any($5))
+                and ($6::timestamptz is null or t.created_at >= $6)
+                and ($7::timestamptz is null or t.created_at < $7)
+                and ($8::text is null or t.search_vector @@ websearch_to_tsquery('english', $8) or exists (
+                    select 1 from sandbox_chat_messages m
+                    where m.task_id = t.task_id and m.search_vector @@ websearch_to_tsquery('english', $8)
+                ))
+            order by t.created_at desc, t.task_id desc
+            limit $9
+        "#,
+            user_id,
+            cursor_created_at,
+            cursor_task_id,
+            &kinds,
+            &statuses as &[PersistedTaskStatusKind],
+            filter.created_after.map(offset_date_time_from_datetime),
+            filter.created_before.map(offset_date_time_from_datetime),
+            filter.query.as_deref(),
+            limit as i64 + 1
+        )
             .fetch_all(&self.pool)
-            .await
-            .unwrap();
+            .await?;
 
-        let mut result = Vec::new();
+        let next_cursor = if tasks.len() > limit as usize {
+            tasks.truncate(limit as usize);
+            tasks.last().map(|v| TaskCursor {
+                created_at_micros: (v.created_at.unix_timestamp_nanos() / 1000) as i64,
+                task_id: TaskId::new(v.id.clone()),
+            })
+        } else {
+            None
+        };
 
-        for task in tasks {
-            result.push(self.task_from_persisted_task(task).await);
-        }
+        let tasks = tasks.into_iter()
+            .map(|v| {
+                let assets = v.asset_ids.into_iter().map(AssetId::from_string).collect();
+                let task = task_from_persisted_task(PersistedTask {
+                    id: v.id,
+                    user_id: v.user_id,
+                    capability_token_hash: v.capability_token_hash,
+                    status_kind: v.status_kind,
+                    progress: v.progress,
+                    failure_reason: v.failure_reason,
+                    failure_retryable: v.failure_retryable,
+                    created_at: v.created_at,
+                    params: v.params,
+                    visibility: v.visibility,
+                })?;
 
-        result
+                Ok((task, assets))
+            })
+            .collect::<DatabaseResult<Vec<_>>>()?;
+        Ok((tasks, next_cursor))
     }
 
-    pub async fn get_task(&self, id: &TaskId) -> Task {
-        let task = sqlx::query_as!(PersistedTask, "select task_id as id, status, created_at, params from sandbox_tasks where task_id = $1", id.as_str())
-            .fetch_one(&self.pool)
-            .await
-            .unwrap();
+    /// Creates finished image generation tasks with one asset each (without stored images), used by the task listing benchmark.
+    /// Tasks are created one second apart, the first prompt is the newest task.
+    pub async fn create_benchmark_tasks(&self, user_id: &str, prompts: &[String]) -> DatabaseResult<()> {
+        let task_ids: Vec<String> = prompts.iter().map(|_| Ulid::new().to_string()).collect();
+        let asset_ids: Vec<String> = prompts.iter().map(|_| Ulid::new().to_string()).collect();
+        let params = prompts.iter()
+            .map(|prompt| serde_json::to_value(PersistedTaskParams::ImageGeneration {
+                iterations: 20,
+                number_of_images: 1,
+                prompt: prompt.clone(),
+            }))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut tx = self.pool.begin().await?;
 
-        self.task_from_persisted_task(task).await        
+        sqlx::query!(
+            r#"
+                insert into sandbox_tasks (user_id, task_id, is_pending, status, params, created_at, images_generated)
+                select $1, v.task_id, false, $2, v.params, now() - make_interval(secs => v.n::double precision), 1
+                from unnest($3::text[], $4::jsonb[]) with ordinality as v(task_id, params, n)
+            "#,
+            user_id,
+            serde_json::to_value(PersistedTaskStatus::Finished)?,
+            &task_ids,
+            &params
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query!("insert into sandbox_task_assets (task_id, asset_id) select * from unnest($1::text[], $2::text[])", &task_ids, &asset_ids)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Removes all tasks of the user together with their assets and messages, without touching stored images.
+    pub async fn delete_benchmark_tasks(&self, user_id: &str) -> DatabaseResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!("delete from sandbox_task_assets where task_id in (select task_id from sandbox_tasks where user_id = $1)", user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("delete from sandbox_chat_messages where task_id in (select task_id from sandbox_tasks where user_id = $1)", user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("delete from sandbox_tasks where user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
     }
 
-    pub async fn get_any_new_task(&self) -> Option<Task> {
-        let task = sqlx::query_as!(PersistedTask, "select task_id as id, status, created_at, params from sandbox_tasks where is_pending = true limit 1")
+    pub async fn find_task(&self, id: &TaskId) -> DatabaseResult<Option<Task>> {
+        sqlx::query_as!(PersistedTask, r#"select task_id as id, user_id, capability_token_hash, status_kind as "status_kind: _", progress, failure_reason, failure_retryable, created_at, params, visibility as "visibility: _" from sandbox_tasks where task_id = $1"#, id.as_str())
             .fetch_optional(&self.pool)
-            .await
-            .unwrap()?;
+            .await?
+            .map(task_from_persisted_task)
+            .transpose()
+    }
 
-        Some(self.task_from_persisted_task(task).await)
+    pub async fn claim_new_task(&self, worker_id: &str, task_kinds: &[TaskKind], lease_duration: Duration) -> DatabaseResult<Option<Task>> {
+        // task params are stored as externally tagged enum, so top-level key of params is the kind of task.
+        let task_kinds: Vec<String> = task_kinds.iter().map(|v| persisted_task_kind(v).to_owned()).collect();
+
+        sqlx::query_as!(PersistedTask, r#"
+            update sandbox_tasks
+            set is_pending = false, lease_worker_id = $1, lease_expires_at = now() + make_interval(secs => $2), attempts = attempts + 1, worker_id = $1, started_at = now()
+            where task_id = (
+                select t.task_id from sandbox_tasks t
+                join sandbox_task_queue q on q.task_id = t.task_id
+                where q.params ?| $3
+                order by q.priority desc, q.owner_turn, q.is_short desc, q.created_at
+                limit 1
+                for update of t skip locked
+            )
+            returning task_id as id, user_id, capability_token_hash, status_kind as "status_kind: _", progress, failure_reason, failure_retryable, created_at, params, visibility as "visibility: _"
+        "#, worker_id, lease_duration.as_secs_f64(), &task_kinds)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(task_from_persisted_task)
+            .transpose()
     }
 
-    async fn task_from_persisted_task(&self, task: PersistedTask) -> Task {
-        let id = TaskId::new(task.id);
-        let status = match serde_json::from_value::<PersistedTaskStatus>(task.status).unwrap() {
-            PersistedTaskStatus::Pending => TaskStatus::Pending,
-            PersistedTaskStatus::InProgress { current_step, total_steps, current_image } => TaskStatus::InProgress { 
-                current_step, 
-                total_steps, 
-                current_image: current_image.unwrap_or(0),
-            },
-            PersistedTaskStatus::Finished => TaskStatus::Finished,
-        };
+    /// Number of tasks of the same kind which will be picked before this one, None if task is not pending.
+    pub async fn queue_position(&self, id: &TaskId) -> DatabaseResult<Option<u32>> {
+        Ok(sqlx::query!(
+            r#"
+                select position as "position!" from (
+                    select task_id, row_number() over (order by priority desc, owner_turn, is_short desc, created_at) - 1 as position
+                    from sandbox_task_queue
+                    where is_short = (select is_short from sandbox_task_queue where task_id = $1)
+                ) q
+                where task_id = $1
+            "#,
+            id.as_str()
+        )
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|v| v.position as u32))
+    }
 
-        let created_at = NaiveDateTime::from_timestamp_opt(task.created_at.unix_timestamp(), 0).unwrap();
-        let created_at = DateTime::from_utc(created_at, Utc);
-
-        let params = match serde_json::from_value::<PersistedTaskParams>(task.params.unwrap()).unwrap() {
-            PersistedTaskParams::ImageGeneration { 
-                iterations, 
-                number_of_images, 
-                prompt
-            } => TaskParams::ImageGenerationParams {
-                prompt, 
-                iterations, 
-                number_of_images
-            },
-            PersistedTaskParams::ChatMessageGeneration {} => TaskParams::ChatMessageGenerationParams {
-            },
-        };
+    pub async fn extend_task_lease(&self, id: &TaskId, worker_id: &str, lease_duration: Duration) -> DatabaseResult<bool> {
+        Ok(sqlx::query!(
+            "update sandbox_tasks set lease_expires_at = now() + make_interval(secs => $3) where task_id = $1 and lease_worker_id = $2",
+            id.as_str(),
+            worker_id,
+            lease_duration.as_secs_f64()
+        )
+            .execute(&self.pool)
+            .await?
+            .rows_affected() > 0)
+    }
+
+    /// Returns tasks whose lease expired, and whether each of them was returned to the queue (otherwise it failed).
+    pub async fn requeue_tasks_with_expired_lease(&self) -> DatabaseResult<Vec<(TaskId, bool)>> {
+        // cancelled tasks are not returned to the queue, their lease is just released.
+        Ok(sqlx::query!(
+            r#"
+                update sandbox_tasks
+                set
+                    is_pending = status_kind <> 'cancelled' and attempts < $3,
+                    status = case when status_kind = 'cancelled' then status when attempts < $3 then $1::jsonb else $2::jsonb end,
+                    lease_worker_id = null,
+                    lease_expires_at = null
+                where is_pending = false and lease_expires_at < now()
+                returning task_id, is_pending, status_kind = 'cancelled' as "is_cancelled!"
+            "#,
+            serde_json::to_value(PersistedTaskStatus::Pending)?,
+            serde_json::to_value(worker_lost())?,
+            self.max_task_attempts
+        )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .filter(|v| !v.is_cancelled)
+            .map(|v| (TaskId::new(v.task_id), v.is_pending))
+            .collect())
+    }
+
+    /// Used when worker holding the lease is known to be gone, so there is no need to wait for lease to expire.
+    /// Task which used all of its attempts fails instead of going back to the queue.
+    pub async fn requeue_task(&self, id: &TaskId, worker_id: &str) -> DatabaseResult<bool> {
+        Ok(sqlx::query!(
+            r#"
+                update sandbox_tasks
+                set
+                    is_pending = status_kind <> 'cancelled' and attempts < $5,
+                    status = case when status_kind = 'cancelled' then status when attempts < $5 then $1::jsonb else $4::jsonb end,
+                    lease_worker_id = null,
+                    lease_expires_at = null
+                where task_id = $2 and lease_worker_id = $3 and is_pending = false
+                returning is_pending
+            "#,
+            serde_json::to_value(PersistedTaskStatus::Pending)?,
+            id.as_str(),
+            worker_id,
+            serde_json::to_value(worker_lost())?,
+            self.max_task_attempts
+        )
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|v| v.is_pending)
+            .unwrap_or(false))
+    }
 
-        Task {
-            id,
-            status,
-            created_at,
-            params,
+    /// Failure reported by the worker. Retryable failures go back to the queue while there are attempts left,
+    /// returns true in that case. Cancelled task stays cancelled. Reports of workers which lost the lease are ignored.
+    pub async fn fail_task(&self, id: &TaskId, worker_id: &str, reason: &str, retryable: bool) -> DatabaseResult<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        // failure which returns the task to the queue does not change its status to failed, so it is not recorded by the
+        // trigger. It is recorded before the update, so that it ends the run before requeue starts the next queue wait.
+        sqlx::query!(
+            r#"
+                insert into sandbox_task_events (task_id, kind, worker_id, details)
+                select task_id, 'failed', lease_worker_id, jsonb_build_object('reason', $1::text, 'retryable', true)
+                from sandbox_tasks
+                where task_id = $2 and lease_worker_id = $3 and status_kind <> 'cancelled' and $4 and attempts < $5
+                for update
+            "#,
+            reason,
+            id.as_str(),
+            worker_id,
+            retryable,
+            self.max_task_attempts
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        let is_requeued = sqlx::query!(
+            r#"
+                update sandbox_tasks
+                set
+                    is_pending = status_kind <> 'cancelled' and $3 and attempts < $4,
+                    status = case when status_kind = 'cancelled' then status when $3 and attempts < $4 then $1::jsonb else $2::jsonb end,
+                    lease_worker_id = null,
+                    lease_expires_at = null
+                where task_id = $5 and lease_worker_id = $6
+                returning is_pending
+            "#,
+            serde_json::to_value(PersistedTaskStatus::Pending)?,
+            serde_json::to_value(PersistedTaskStatus::Failed { reason: reason.to_owned(), retryable })?,
+            retryable,
+            self.max_task_attempts,
+            id.as_str(),
+            worker_id
+        )
+            .fetch_optional(&mut *tx)
+            .await?
+            .map(|v| v.is_pending)
+            .unwrap_or(false);
+
+        tx.commit().await?;
+        Ok(is_requeued)
+    }
+
+    /// Queues failed task again with all attempts available. Returns false if task has not failed.
+    pub async fn retry_task(&self, usage: &mut UsageTransaction, id: &TaskId) -> DatabaseResult<bool> {
+        if lock_task(&mut usage.tx, id).await? != PersistedTaskStatusKind::Failed {
+            return Ok(false);
         }
+
+        sqlx::query!(
+            "update sandbox_tasks set status = $1::jsonb, is_pending = true, attempts = 0, lease_worker_id = null, lease_expires_at = null where task_id = $2",
+            serde_json::to_value(PersistedTaskStatus::Pending)?,
+            id.as_str()
+        )
+            .execute(&mut *usage.tx)
+            .await?;
+
+        Ok(true)
+    }
+
+    pub async fn release_task_lease(&self, id: &TaskId, worker_id: &str) -> DatabaseResult<()> {
+        sqlx::query!("update sandbox_tasks set lease_worker_id = null, lease_expires_at = null where task_id = $1 and lease_worker_id = $2", id.as_str(), worker_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
     }
 
-    pub async fn save_task_status(&self, id: &TaskId, status: &TaskStatus) {
+    pub async fn cancel_task(&self, id: &TaskId) -> DatabaseResult<bool> {
+        Ok(sqlx::query!(
+            "update sandbox_tasks set status = $1::jsonb, is_pending = false where task_id = $2 and status_kind not in ('cancelled', 'finished')",
+            serde_json::to_value(PersistedTaskStatus::Cancelled)?,
+            id.as_str()
+        )
+            .execute(&self.pool)
+            .await?
+            .rows_affected() > 0)
+    }
+
+    pub async fn is_task_cancelled(&self, id: &TaskId) -> DatabaseResult<bool> {
+        Ok(sqlx::query!(
+            r#"select status_kind = 'cancelled' as "is_cancelled!" from sandbox_tasks where task_id = $1"#,
+            id.as_str()
+        )
+            .fetch_one(&self.pool)
+            .await?
+            .is_cancelled)
+    }
+
+    /// Used for status reports from workers, which should not bring cancelled task back to life. Status is saved only if the worker still holds the lease.
+    pub async fn save_task_status_unless_cancelled(&self, id: &TaskId, worker_id: &str, status: &TaskStatus) -> DatabaseResult<bool> {
         let persisted_status = match status {
             TaskStatus::Pending => PersistedTaskStatus::Pending,
-            TaskStatus::InProgress { current_step, total_steps, current_image } => PersistedTaskStatus::InProgress { 
-                current_step: *current_step, 
+            TaskStatus::InProgress { current_step, total_steps, current_image } => PersistedTaskStatus::InProgress {
+                current_step: *current_step,
                 total_steps: *total_steps,
                 current_image: Some(*current_image),
             },
             TaskStatus::Finished => PersistedTaskStatus::Finished,
+            TaskStatus::Cancelled => PersistedTaskStatus::Cancelled,
+            TaskStatus::Failed { reason, retryable } => PersistedTaskStatus::Failed {
+                reason: reason.clone(),
+                retryable: *retryable,
+            },
         };
 
         let is_pending = TaskStatus::Pending == *status;
+        // lease is only kept while worker is running the task. Once task is finished or goes back to the queue, it can be claimed again.
+        // Cancelled task keeps the lease, so that worker can still upload what it has generated so far.
+        let keep_lease = matches!(status, TaskStatus::InProgress { .. } | TaskStatus::Cancelled);
 
-        sqlx::query!(
-            "update sandbox_tasks set status = $1::jsonb, is_pending = $2 where task_id = $3", 
-            serde_json::to_value(&persisted_status).unwrap(),
+        Ok(sqlx::query!(
+            r#"
+                update sandbox_tasks
+                set
+                    status = $1::jsonb,
+                    is_pending = $2,
+                    lease_worker_id = case when $4 then lease_worker_id else null end,
+                    lease_expires_at = case when $4 then lease_expires_at else null end
+                where task_id = $3 and status_kind <> 'cancelled' and lease_worker_id = $5
+            "#,
+            serde_json::to_value(&persisted_status)?,
             is_pending,
-            id.as_str()
+            id.as_str(),
+            keep_lease,
+            worker_id
         )
             .execute(&self.pool)
-            .await
-            .unwrap();
+            .await?
+            .rows_affected() > 0)
     }
 
-    pub async fn get_generated_image(&self, task_id: &TaskId) -> Option<Vec<u8>> {
+    pub async fn get_generated_image(&self, task_id: &TaskId) -> DatabaseResult<Option<Vec<u8>>> {
         match self.bucket.get_object(&format!("output/images/{}", task_id.as_str())).await {
-            Ok(v) => Some(v.to_vec()),
-            Err(err) => match err {
-                S3Error::HttpFailWithBody(code, _body) => match code {
-                    404 => None,
-                    other => panic!("failed to get generated image because of http error: {:?}", other),
-                },
-                other => panic!("failed to get generated image because of error: {:?}", other),
-            },
+            Ok(v) => Ok(Some(v.to_vec())),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(err) => Err(err.into()),
         }
     }
 
-    pub async fn create_or_get_user_by_email(&self, email: &str) -> UserId {
+    pub async fn create_or_get_user_by_identity(&self, provider: &str, subject: &str, email: &str) -> DatabaseResult<UserId> {
+        let existing = sqlx::query!("select id from sandbox_users where auth_provider = $1 and auth_subject = $2", provider, subject)
+            .fetch_optional(&self.pool)
+            .await?;
+        if let Some(user) = existing {
+            return Ok(UserId::from_string(user.id));
+        }
+
+        // users created before identities were tracked are known only by email, so they are linked on their first login.
+        let legacy = sqlx::query!(
+            "update sandbox_users set auth_subject = $2 where auth_provider = $1 and auth_subject is null and email = $3 returning id",
+            provider,
+            subject,
+            email
+        ).fetch_optional(&self.pool).await?;
+        if let Some(user) = legacy {
+            return Ok(UserId::from_string(user.id));
+        }
+
         let new_id = Ulid::new();
 
         let user_id = sqlx::query_as!(PersistedUserId, r#"
             with ins as (
-                insert into sandbox_users (id, email) values ($1, $2) on conflict do nothing returning id
+                insert into sandbox_users (id, email, auth_provider, auth_subject) values ($1, $2, $3, $4) on conflict do nothing returning id
             )
             select id as "id!" from ins
-            union all select id as "id!" from sandbox_users where email = $2 limit 1;
-        "#, new_id.to_string(), email).fetch_one(&self.pool).await.unwrap();
+            union all select id as "id!" from sandbox_users where auth_provider = $3 and auth_subject = $4 limit 1;
+        "#, new_id.to_string(), email, provider, subject).fetch_one(&self.pool).await?;
+
+        Ok(UserId::from_string(user_id.id))
+    }
+
+    pub async fn create_local_user(&self, username: &str, password_hash: &str) -> DatabaseResult<Option<UserId>> {
+        let new_id = Ulid::new();
+
+        Ok(sqlx::query!(
+            "insert into sandbox_users (id, auth_provider, auth_subject, password_hash) values ($1, $2, $3, $4) on conflict do nothing returning id",
+            new_id.to_string(),
+            LOCAL_AUTH_PROVIDER,
+            username,
+            password_hash
+        ).fetch_optional(&self.pool).await?.map(|v| UserId::from_string(v.id)))
+    }
 
-        UserId::from_string(user_id.id)
+    pub async fn get_local_user(&self, username: &str) -> DatabaseResult<Option<(UserId, String)>> {
+        Ok(sqlx::query!(
+            r#"select id, password_hash as "password_hash!" from sandbox_users where auth_provider = $1 and auth_subject = $2 and password_hash is not null"#,
+            LOCAL_AUTH_PROVIDER,
+            username
+        ).fetch_optional(&self.pool).await?.map(|v| (UserId::from_string(v.id), v.password_hash)))
     }
 
-    pub async fn create_task_asset(&self, task_id: &TaskId, data: Vec<u8>) -> AssetId {
+    pub async fn get_user_password_hash(&self, user_id: &UserId) -> DatabaseResult<Option<String>> {
+        Ok(sqlx::query!("select password_hash from sandbox_users where id = $1", user_id.to_string())
+            .fetch_optional(&self.pool)
+            .await?
+            .and_then(|v| v.password_hash))
+    }
+
+    pub async fn set_user_password_hash(&self, user_id: &UserId, password_hash: &str) -> DatabaseResult<()> {
+        sqlx::query!("update sandbox_users set password_hash = $2 where id = $1", user_id.to_string(), password_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn create_task_asset(&self, task_id: &TaskId, data: Vec<u8>) -> DatabaseResult<AssetId> {
         let asset_id = Ulid::new();
 
-        sqlx::query!("insert into sandbox_task_assets (task_id, asset_id) values ($1, $2)", task_id.as_str(), asset_id.to_string()).execute(&self.pool).await.unwrap();
-        self.bucket.put_object(&format!("output/images/{}", asset_id.to_string()), &data).await.unwrap();
+        // object is uploaded before rows are added, so that neither the asset nor the number of generated images point to a missing object.
+        self.bucket.put_object(&format!("output/images/{}", asset_id.to_string()), &data).await?;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!("insert into sandbox_task_assets (task_id, asset_id) values ($1, $2)", task_id.as_str(), asset_id.to_string()).execute(&mut *tx).await?;
+        sqlx::query!("update sandbox_tasks set images_generated = images_generated + 1 where task_id = $1", task_id.as_str()).execute(&mut *tx).await?;
+        tx.commit().await?;
 
-        AssetId::from_string(asset_id.to_string())
+        Ok(AssetId::from_string(asset_id.to_string()))
+    }
+
+    pub async fn get_asset_task_id(&self, asset_id: &AssetId) -> DatabaseResult<Option<TaskId>> {
+        Ok(sqlx::query!("select task_id from sandbox_task_assets where asset_id = $1", asset_id.to_string())
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|v| TaskId::new(v.task_id)))
     }
 
-    pub async fn get_task_assets(&self, task_id: &TaskId) -> Vec<AssetId> {
-        sqlx::query_as!(PersistedAssetId, "select asset_id as id from sandbox_task_assets where task_id = $1", task_id.as_str())
+    pub async fn delete_task(&self, task_id: &TaskId) -> DatabaseResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!("delete from sandbox_chat_messages where task_id = $1", task_id.as_str())
+            .execute(&mut *tx)
+            .await?;
+
+        let assets = sqlx::query_as!(PersistedAssetId, "delete from sandbox_task_assets where task_id = $1 returning asset_id as id", task_id.as_str())
+            .fetch_all(&mut *tx)
+            .await?;
+
+        sqlx::query!("delete from sandbox_tasks where task_id = $1", task_id.as_str())
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        // objects are removed only after rows are gone, so that there are no rows pointing to missing objects.
+        for asset in assets {
+            self.delete_generated_image(&AssetId::from_string(asset.id)).await?;
+        }
+
+        Ok(())
+    }
+
+    // asset row without an object, for tests which do not reach object storage.
+    #[cfg(test)]
+    pub(crate) async fn add_test_task_asset(&self, task_id: &TaskId, asset_id: &AssetId) -> DatabaseResult<()> {
+        sqlx::query!("insert into sandbox_task_assets (task_id, asset_id) values ($1, $2)", task_id.as_str(), asset_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_task_asset(&self, asset_id: &AssetId) -> DatabaseResult<()> {
+        sqlx::query!("delete from sandbox_task_assets where asset_id = $1", asset_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        self.delete_generated_image(asset_id).await
+    }
+
+    async fn delete_generated_image(&self, asset_id: &AssetId) -> DatabaseResult<()> {
+        self.bucket.delete_object(&format!("output/images/{}", asset_id.to_string())).await?;
+        Ok(())
+    }
+
+    pub async fn get_task_assets(&self, task_id: &TaskId) -> DatabaseResult<Vec<AssetId>> {
+        Ok(sqlx::query_as!(PersistedAssetId, "select asset_id as id from sandbox_task_assets where task_id = $1 order by created_at", task_id.as_str())
             .fetch_all(&self.pool)
-            .await
-            .unwrap()
+            .await?
             .into_iter()
             .map(|v| AssetId::from_string(v.id))
-            .collect()
+            .collect())
     }
 
-    pub async fn get_chat_messages(&self, task_id: &TaskId) -> Vec<ChatMessage> {
+    pub async fn get_chat_messages(&self, task_id: &TaskId) -> DatabaseResult<Vec<ChatMessage>> {
         let messages = sqlx::query_as!(PersistedChatMessage, "select task_id, message_id, content, message_role as \"message_role: _\", message_index from sandbox_chat_messages where task_id = $1 order by message_index desc", task_id.as_str())
             .fetch_all(&self.pool)
-            .await
-            .unwrap();
+            .await?;
 
-        messages
+        Ok(messages
             .into_iter()
             .map(|v| ChatMessage {
                 task_id: TaskId::new(v.task_id),
@@ -282,10 +934,10 @@ impl Database {
                 },
                 index: v.message_index as u32,
             })
-            .collect()
+            .collect())
     }
 
-    pub async fn create_chat_message(&self, task_id: &TaskId, content: String, role: ChatMessageRole, index: u32) -> MessageId {
+    pub async fn create_chat_message(&self, task_id: &TaskId, content: String, role: ChatMessageRole, index: u32) -> DatabaseResult<MessageId> {
         let message_id = Ulid::new();
 
         sqlx::query!(
@@ -293,86 +945,614 @@ impl Database {
             task_id.as_str(),
             message_id.to_string(),
             content,
-            match role {
-                ChatMessageRole::System => PersistedChatMessageRole::System,
-                ChatMessageRole::User => PersistedChatMessageRole::User,
-                ChatMessageRole::Assistant => PersistedChatMessageRole::Assistant,
-            } as PersistedChatMessageRole,
+            persisted_chat_message_role(role) as PersistedChatMessageRole,
             index as i32
-        ).execute(&self.pool).await.unwrap();
+        ).execute(&self.pool).await?;
 
-        MessageId::new(message_id.to_string())
+        Ok(MessageId::new(message_id.to_string()))
     }
 
-    pub async fn append_chat_message(&self, task_id: &TaskId, content: String, role: ChatMessageRole) -> MessageId {
-        let message_id = Ulid::new();
+    pub async fn append_chat_message(&self, task_id: &TaskId, content: String, role: ChatMessageRole) -> DatabaseResult<MessageId> {
+        let mut tx = self.pool.begin().await?;
+
+        lock_task(&mut tx, task_id).await?;
+        let message_id = insert_next_chat_message(&mut tx, task_id, content, role).await?;
+
+        tx.commit().await?;
+
+        Ok(message_id)
+    }
+
+    /// Returns `None` if assistant reply for this task is still being generated. Otherwise, the task is queued to generate the reply.
+    pub async fn add_user_chat_message(&self, usage: &mut UsageTransaction, task_id: &TaskId, content: String) -> DatabaseResult<Option<MessageId>> {
+        let status = lock_task(&mut usage.tx, task_id).await?;
+        if matches!(status, PersistedTaskStatusKind::Pending | PersistedTaskStatusKind::InProgress) {
+            return Ok(None);
+        }
+
+        let message_id = insert_next_chat_message(&mut usage.tx, task_id, content, ChatMessageRole::User).await?;
 
         sqlx::query!(
-            "insert into sandbox_chat_messages (task_id, message_id, content, message_role, message_index) values ($1, $2, $3, $4, (select coalesce(max(message_index) + 1, 0) from sandbox_chat_messages where task_id = $1 and message_id = $2))",
-            task_id.as_str(),
-            message_id.to_string(),
-            content,
-            match role {
-                ChatMessageRole::System => PersistedChatMessageRole::System,
-                ChatMessageRole::User => PersistedChatMessageRole::User,
-                ChatMessageRole::Assistant => PersistedChatMessageRole::Assistant,
-            } as PersistedChatMessageRole
-        ).execute(&self.pool).await.unwrap();
+            "update sandbox_tasks set status = $1::jsonb, is_pending = true, attempts = 0, lease_worker_id = null, lease_expires_at = null where task_id = $2",
+            serde_json::to_value(PersistedTaskStatus::Pending)?,
+            task_id.as_str()
+        )
+            .execute(&mut *usage.tx)
+            .await?;
+
+        Ok(Some(message_id))
+    }
+
+    /// Adds more images to a finished image generation task and queues it again. Returns false if task is not finished.
+    pub async fn extend_image_task(&self, usage: &mut UsageTransaction, task_id: &TaskId, number_of_images: u32) -> DatabaseResult<bool> {
+        if lock_task(&mut usage.tx, task_id).await? != PersistedTaskStatusKind::Finished {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r#"
+                update sandbox_tasks
+                set
+                    params = jsonb_set(params, '{ImageGeneration,number_of_images}', to_jsonb((params->'ImageGeneration'->>'number_of_images')::integer + $2)),
+                    status = $1::jsonb,
+                    is_pending = true,
+                    attempts = 0,
+                    lease_worker_id = null,
+                    lease_expires_at = null
+                where task_id = $3
+            "#,
+            serde_json::to_value(PersistedTaskStatus::Pending)?,
+            number_of_images as i32,
+            task_id.as_str()
+        )
+            .execute(&mut *usage.tx)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Events are recorded by triggers on tasks and assets tables, see add-task-events migration.
+    pub async fn get_task_events(&self, task_id: &TaskId) -> DatabaseResult<Vec<TaskEvent>> {
+        sqlx::query_as!(
+            PersistedTaskEvent,
+            r#"select kind as "kind: _", worker_id, details, created_at from sandbox_task_events where task_id = $1 order by event_id"#,
+            task_id.as_str()
+        )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(task_event_from_persisted_task_event)
+            .collect()
+    }
+
+    pub async fn last_task_event_id(&self) -> DatabaseResult<i64> {
+        Ok(sqlx::query!(r#"select coalesce(max(event_id), 0) as "event_id!" from sandbox_task_events"#)
+            .fetch_one(&self.pool)
+            .await?
+            .event_id)
+    }
+
+    /// Durations of queue wait and run phases which ended with events recorded after the given one.
+    /// Claim ends the queue wait which started with the latest creation or requeue, and finish, cancellation or failure
+    /// ends the run which started with the latest claim.
+    pub async fn task_phase_durations(&self, after_event_id: i64) -> DatabaseResult<Vec<TaskPhaseDuration>> {
+        sqlx::query_as!(
+            PersistedTaskPhaseDuration,
+            r#"
+                select e.event_id, e.kind as "kind: _", extract(epoch from e.created_at - s.created_at)::double precision as "seconds!"
+                from sandbox_task_events e
+                join lateral (
+                    select p.kind, p.created_at from sandbox_task_events p
+                    where p.task_id = e.task_id and p.event_id < e.event_id and p.kind in ('created', 'requeued', 'claimed')
+                    order by p.event_id desc
+                    limit 1
+                ) s on (e.kind = 'claimed') = (s.kind <> 'claimed')
+                where e.event_id > $1 and e.kind in ('claimed', 'finished', 'cancelled', 'failed')
+                order by e.event_id
+            "#,
+            after_event_id
+        )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .filter_map(|v| {
+                let phase = match v.kind {
+                    PersistedTaskEventKind::Claimed => TaskPhase::QueueWait,
+                    PersistedTaskEventKind::Finished => TaskPhase::Run(TaskStatusKind::Finished),
+                    PersistedTaskEventKind::Cancelled => TaskPhase::Run(TaskStatusKind::Cancelled),
+                    PersistedTaskEventKind::Failed => TaskPhase::Run(TaskStatusKind::Failed),
+                    _ => return None,
+                };
+
+                Some(Ok(TaskPhaseDuration {
+                    event_id: v.event_id,
+                    phase,
+                    duration: Duration::from_secs_f64(v.seconds.max(0.0)),
+                }))
+            })
+            .collect()
+    }
+
+    pub async fn images_generated(&self, task_id: &TaskId) -> DatabaseResult<u32> {
+        Ok(sqlx::query!("select images_generated from sandbox_tasks where task_id = $1", task_id.as_str())
+            .fetch_one(&self.pool)
+            .await?
+            .images_generated as u32)
+    }
+
+    pub async fn update_chat_message_content(&self, message_id: &MessageId, content: String) -> DatabaseResult<()> {
+        sqlx::query!("update sandbox_chat_messages set content = $1 where message_id = $2", content, message_id.as_str())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_chat_message(&self, message_id: &MessageId) -> DatabaseResult<()> {
+        sqlx::query!("delete from sandbox_chat_messages where message_id = $1", message_id.as_str())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
 
-        MessageId::new(message_id.to_string())
+    pub async fn total_pending_tasks(&self) -> DatabaseResult<u64> {
+        Ok(sqlx::query!("select count(*) as cnt from sandbox_tasks where is_pending = true")
+            .fetch_one(&self.pool)
+            .await?
+            .cnt
+            .unwrap_or(0) as u64)
     }
 
-    pub async fn total_pending_tasks(&self) -> u64 {
-        sqlx::query!("select count(*) as cnt from sandbox_tasks where is_pending = true")
+    pub async fn total_in_progress_tasks(&self) -> DatabaseResult<u64> {
+        Ok(sqlx::query!("select count(*) as cnt from sandbox_tasks where status_kind = 'in_progress'")
             .fetch_one(&self.pool)
-            .await
-            .unwrap()
+            .await?
             .cnt
-            .unwrap() as u64
+            .unwrap_or(0) as u64)
     }
 
-    pub async fn total_in_progress_tasks(&self) -> u64 {
-        sqlx::query!("select count(*) as cnt from sandbox_tasks where status->'InProgress' is not null")
+    pub async fn finished_tasks_within_last_day(&self) -> DatabaseResult<u64> {
+        Ok(sqlx::query!("select count(*) as cnt from sandbox_tasks where status_kind = 'finished' and finished_at > now() - interval '24' hour")
             .fetch_one(&self.pool)
-            .await
-            .unwrap()
+            .await?
             .cnt
-            .unwrap() as u64
+            .unwrap_or(0) as u64)
     }
 
-    pub async fn finished_tasks_within_last_day(&self) -> u64 {
-        sqlx::query!("select count(*) as cnt from sandbox_tasks where status::text = '\"Finished\"' and created_at > now() - interval '24' hour")
+    pub async fn failed_tasks_within_last_day(&self) -> DatabaseResult<u64> {
+        Ok(sqlx::query!("select count(*) as cnt from sandbox_tasks where status_kind = 'failed' and finished_at > now() - interval '24' hour")
             .fetch_one(&self.pool)
-            .await
-            .unwrap()
+            .await?
             .cnt
-            .unwrap() as u64
+            .unwrap_or(0) as u64)
     }
 
-    pub async fn get_max_task_pending_time(&self) -> Option<Duration> {
-        sqlx::query!("select extract(epoch from max(now() - created_at))::int as lag from sandbox_tasks where is_pending = true")
+    pub async fn get_max_task_pending_time(&self) -> DatabaseResult<Option<Duration>> {
+        Ok(sqlx::query!("select extract(epoch from max(now() - created_at))::int as lag from sandbox_tasks where is_pending = true")
             .fetch_one(&self.pool)
-            .await
-            .unwrap()
+            .await?
             .lag
-            .map(|v| Duration::from_secs(v.try_into().unwrap()))
+            .map(|v| Duration::from_secs(v.max(0) as u64)))
     }
 
-    pub async fn update_worker_last_ping_time(&self) {
-        sqlx::query!("update sandbox_workers set last_ping_at = now()")
+    pub async fn register_worker(&self, worker_id: &str, hostname: &str, version: &str, task_kinds: &[TaskKind], models: &[String]) -> DatabaseResult<()> {
+        let task_kinds: Vec<String> = task_kinds.iter().map(|v| persisted_task_kind(v).to_owned()).collect();
+
+        sqlx::query!(
+            r#"
+                insert into sandbox_workers (worker_id, hostname, version, task_kinds, models) values ($1, $2, $3, $4, $5)
+                on conflict (worker_id) do update set
+                    hostname = excluded.hostname,
+                    version = excluded.version,
+                    task_kinds = excluded.task_kinds,
+                    models = excluded.models,
+                    registered_at = now(),
+                    last_ping_at = now()
+            "#,
+            worker_id,
+            hostname,
+            version,
+            &task_kinds,
+            models
+        )
             .execute(&self.pool)
-            .await
-            .unwrap();
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_worker(&self, worker_id: &str) -> DatabaseResult<Option<Worker>> {
+        Ok(sqlx::query_as!(PersistedWorker, "select worker_id, hostname, version, task_kinds, models, registered_at, last_ping_at from sandbox_workers where worker_id = $1", worker_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(worker_from_persisted_worker))
     }
 
-    pub async fn total_active_workers(&self) -> u64 {
-        sqlx::query!("select count(*) as cnt from sandbox_workers where now() - last_ping_at < interval '10' minute")
+    pub async fn get_workers(&self) -> DatabaseResult<Vec<Worker>> {
+        Ok(sqlx::query_as!(PersistedWorker, "select worker_id, hostname, version, task_kinds, models, registered_at, last_ping_at from sandbox_workers order by registered_at")
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(worker_from_persisted_worker)
+            .collect())
+    }
+
+    pub async fn update_worker_last_ping_time(&self, worker_id: &str) -> DatabaseResult<()> {
+        sqlx::query!("update sandbox_workers set last_ping_at = now() where worker_id = $1", worker_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn total_active_workers(&self) -> DatabaseResult<u64> {
+        Ok(sqlx::query!("select count(*) as cnt from sandbox_workers where now() - last_ping_at < interval '10' minute")
             .fetch_one(&self.pool)
-            .await
-            .unwrap()
+            .await?
             .cnt
-            .unwrap()
-            .try_into()
-            .unwrap()
+            .unwrap_or(0) as u64)
+    }
+
+    pub async fn create_api_key(&self, user_id: &str, name: &str, scope: ApiKeyScope, key_prefix: &str, key_hash: &str) -> DatabaseResult<ApiKey> {
+        let key = sqlx::query_as!(
+            PersistedApiKey,
+            r#"
+                insert into sandbox_api_keys (key_id, user_id, name, scope, key_prefix, key_hash) values ($1, $2, $3, $4, $5, $6)
+                returning key_id, name, scope as "scope: _", key_prefix, created_at, last_used_at
+            "#,
+            Ulid::new().to_string(),
+            user_id,
+            name,
+            persisted_api_key_scope(scope) as PersistedApiKeyScope,
+            key_prefix,
+            key_hash
+        )
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(api_key_from_persisted_api_key(key))
     }
+
+    pub async fn get_user_api_keys(&self, user_id: &str) -> DatabaseResult<Vec<ApiKey>> {
+        Ok(sqlx::query_as!(
+            PersistedApiKey,
+            r#"
+                select key_id, name, scope as "scope: _", key_prefix, created_at, last_used_at from sandbox_api_keys
+                where user_id = $1 and revoked_at is null
+                order by created_at desc
+            "#,
+            user_id
+        )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(api_key_from_persisted_api_key)
+            .collect())
+    }
+
+    pub async fn revoke_api_key(&self, user_id: &str, key_id: &str) -> DatabaseResult<bool> {
+        Ok(sqlx::query!(
+            "update sandbox_api_keys set revoked_at = now() where key_id = $1 and user_id = $2 and revoked_at is null",
+            key_id,
+            user_id
+        )
+            .execute(&self.pool)
+            .await?
+            .rows_affected() > 0)
+    }
+
+    /// Returns owner and scope of the key if it is valid. Usage time is recorded at the same time.
+    pub async fn use_api_key(&self, key_hash: &str) -> DatabaseResult<Option<(String, ApiKeyScope)>> {
+        Ok(sqlx::query!(
+            r#"update sandbox_api_keys set last_used_at = now() where key_hash = $1 and revoked_at is null returning user_id, scope as "scope: PersistedApiKeyScope""#,
+            key_hash
+        )
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|key| (key.user_id, api_key_scope_from_persisted(key.scope))))
+    }
+
+    /// Quota check, the task change it allows and the usage record are done in a single transaction, which holds a lock of
+    /// the requester. Otherwise concurrent requests could all pass the check before any of them is recorded.
+    pub async fn begin_usage_transaction(&self, requester: &str) -> DatabaseResult<UsageTransaction> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("select pg_advisory_xact_lock(hashtext($1))")
+            .bind(requester)
+            .execute(&mut *tx)
+            .await?;
+
+        Ok(UsageTransaction {
+            tx,
+            requester: requester.to_owned(),
+        })
+    }
+}
+
+/// Changes are rolled back if it is dropped without commit.
+pub struct UsageTransaction {
+    tx: Transaction<'static, Postgres>,
+    requester: String,
+}
+
+impl UsageTransaction {
+    pub async fn get_usage(&mut self) -> DatabaseResult<Usage> {
+        let usage = sqlx::query!(
+            r#"
+            select
+                (
+                    select count(distinct e.task_id) from sandbox_usage_events e
+                    join sandbox_tasks t on t.task_id = e.task_id
+                    where e.requester = $1 and e.created_at > now() - interval '1 day' and (t.is_pending or t.lease_worker_id is not null)
+                ) as "active_tasks!",
+                count(*) as "daily_tasks!",
+                coalesce(sum(tokens), 0)::bigint as "daily_tokens!",
+                min(created_at) as oldest_event_at
+            from sandbox_usage_events
+            where requester = $1 and created_at > now() - interval '1 day'
+            "#,
+            self.requester
+        )
+            .fetch_one(&mut *self.tx)
+            .await?;
+
+        Ok(Usage {
+            active_tasks: usage.active_tasks as u32,
+            daily_tasks: usage.daily_tasks as u32,
+            daily_tokens: usage.daily_tokens as u64,
+            oldest_event_at: usage.oldest_event_at.map(datetime_from_offset_date_time),
+        })
+    }
+
+    pub async fn record_usage(&mut self, task_id: &TaskId, tokens: u64) -> DatabaseResult<()> {
+        sqlx::query!(
+            "insert into sandbox_usage_events (requester, task_id, tokens) values ($1, $2, $3)",
+            self.requester,
+            task_id.as_str(),
+            tokens as i64
+        )
+            .execute(&mut *self.tx)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn commit(self) -> DatabaseResult<()> {
+        self.tx.commit().await?;
+        Ok(())
+    }
+}
+
+async fn listen_for_task_updates(mut listener: PgListener, task_updates: broadcast::Sender<TaskId>, pending_tasks: broadcast::Sender<TaskId>) {
+    loop {
+        match listener.recv().await {
+            // send fails only when nobody is subscribed right now, which is fine.
+            Ok(notification) => {
+                let id = TaskId::new(notification.payload().to_owned());
+                let _ = match notification.channel() {
+                    "sandbox_pending_tasks" => pending_tasks.send(id),
+                    _ => task_updates.send(id),
+                };
+            },
+            Err(err) => {
+                error!("failed to receive task update notification: {:?}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            },
+        }
+    }
+}
+
+// worker disappeared while running the task too many times, it might be the task which brings workers down.
+fn worker_lost() -> PersistedTaskStatus {
+    PersistedTaskStatus::Failed {
+        reason: "worker_lost".to_owned(),
+        retryable: true,
+    }
+}
+
+fn task_from_persisted_task(task: PersistedTask) -> DatabaseResult<Task> {
+    let id = TaskId::new(task.id);
+    let status = match task.status_kind {
+        PersistedTaskStatusKind::Pending => TaskStatus::Pending,
+        PersistedTaskStatusKind::InProgress => {
+            let progress = serde_json::from_value::<PersistedTaskProgress>(task.progress.unwrap_or_default())?;
+            TaskStatus::InProgress {
+                current_step: progress.current_step,
+                total_steps: progress.total_steps,
+                current_image: progress.current_image.unwrap_or(0),
+            }
+        },
+        PersistedTaskStatusKind::Finished => TaskStatus::Finished,
+        PersistedTaskStatusKind::Cancelled => TaskStatus::Cancelled,
+        PersistedTaskStatusKind::Failed => TaskStatus::Failed {
+            reason: task.failure_reason.unwrap_or_default(),
+            retryable: task.failure_retryable.unwrap_or(false),
+        },
+    };
+
+    let created_at = datetime_from_offset_date_time(task.created_at);
+
+    // params column is filled for all tasks since params migration, null is treated the same as any other broken value.
+    let params = match serde_json::from_value::<PersistedTaskParams>(task.params.unwrap_or_default())? {
+        PersistedTaskParams::ImageGeneration {
+            iterations,
+            number_of_images,
+            prompt
+        } => TaskParams::ImageGenerationParams {
+            prompt,
+            iterations,
+            number_of_images
+        },
+        PersistedTaskParams::ChatMessageGeneration {} => TaskParams::ChatMessageGenerationParams {
+        },
+    };
+
+    let visibility = match task.visibility {
+        PersistedTaskVisibility::Private => TaskVisibility::Private,
+        PersistedTaskVisibility::Unlisted => TaskVisibility::Unlisted,
+        PersistedTaskVisibility::Public => TaskVisibility::Public,
+    };
+
+    Ok(Task {
+        id,
+        user_id: task.user_id,
+        capability_token_hash: task.capability_token_hash,
+        status,
+        created_at,
+        params,
+        visibility,
+    })
+}
+
+fn task_event_from_persisted_task_event(event: PersistedTaskEvent) -> DatabaseResult<TaskEvent> {
+    let details = event.details.unwrap_or_default();
+    let kind = match event.kind {
+        PersistedTaskEventKind::Created => TaskEventKind::Created,
+        PersistedTaskEventKind::Claimed => TaskEventKind::Claimed {
+            attempt: serde_json::from_value::<PersistedClaimDetails>(details)?.attempt,
+        },
+        PersistedTaskEventKind::Progress => {
+            let progress = serde_json::from_value::<PersistedTaskProgress>(details)?;
+            TaskEventKind::Progress {
+                current_image: progress.current_image.unwrap_or(0),
+                current_step: progress.current_step,
+                total_steps: progress.total_steps,
+            }
+        },
+        PersistedTaskEventKind::AssetCreated => TaskEventKind::AssetCreated {
+            asset_id: AssetId::from_string(serde_json::from_value::<PersistedAssetDetails>(details)?.asset_id),
+        },
+        PersistedTaskEventKind::Requeued => TaskEventKind::Requeued,
+        PersistedTaskEventKind::Finished => TaskEventKind::Finished,
+        PersistedTaskEventKind::Cancelled => TaskEventKind::Cancelled,
+        PersistedTaskEventKind::Failed => {
+            let failure = serde_json::from_value::<PersistedFailureDetails>(details)?;
+            TaskEventKind::Failed {
+                reason: failure.reason.unwrap_or_default(),
+                retryable: failure.retryable.unwrap_or(false),
+            }
+        },
+    };
+
+    Ok(TaskEvent {
+        kind,
+        worker_id: event.worker_id,
+        created_at: datetime_from_offset_date_time(event.created_at),
+    })
+}
+
+// messages of a task are appended while holding a lock on the task row, so that indexes are assigned one at a time.
+async fn lock_task(connection: &mut PgConnection, task_id: &TaskId) -> DatabaseResult<PersistedTaskStatusKind> {
+    Ok(sqlx::query!(r#"select status_kind as "status_kind: PersistedTaskStatusKind" from sandbox_tasks where task_id = $1 for update"#, task_id.as_str())
+        .fetch_one(&mut *connection)
+        .await?
+        .status_kind)
+}
+
+async fn insert_next_chat_message(connection: &mut PgConnection, task_id: &TaskId, content: String, role: ChatMessageRole) -> DatabaseResult<MessageId> {
+    let message_id = Ulid::new();
+
+    sqlx::query!(
+        "insert into sandbox_chat_messages (task_id, message_id, content, message_role, message_index) values ($1, $2, $3, $4, (select coalesce(max(message_index) + 1, 0) from sandbox_chat_messages where task_id = $1))",
+        task_id.as_str(),
+        message_id.to_string(),
+        content,
+        persisted_chat_message_role(role) as PersistedChatMessageRole
+    )
+        .execute(&mut *connection)
+        .await?;
+
+    Ok(MessageId::new(message_id.to_string()))
+}
+
+fn api_key_from_persisted_api_key(key: PersistedApiKey) -> ApiKey {
+    ApiKey {
+        id: key.key_id,
+        name: key.name,
+        scope: api_key_scope_from_persisted(key.scope),
+        key_prefix: key.key_prefix,
+        created_at: datetime_from_offset_date_time(key.created_at),
+        last_used_at: key.last_used_at.map(datetime_from_offset_date_time),
+    }
+}
+
+fn persisted_api_key_scope(scope: ApiKeyScope) -> PersistedApiKeyScope {
+    match scope {
+        ApiKeyScope::ReadOnly => PersistedApiKeyScope::ReadOnly,
+        ApiKeyScope::CreateTasks => PersistedApiKeyScope::CreateTasks,
+    }
+}
+
+fn api_key_scope_from_persisted(scope: PersistedApiKeyScope) -> ApiKeyScope {
+    match scope {
+        PersistedApiKeyScope::ReadOnly => ApiKeyScope::ReadOnly,
+        PersistedApiKeyScope::CreateTasks => ApiKeyScope::CreateTasks,
+    }
+}
+
+fn persisted_chat_message_role(role: ChatMessageRole) -> PersistedChatMessageRole {
+    match role {
+        ChatMessageRole::System => PersistedChatMessageRole::System,
+        ChatMessageRole::User => PersistedChatMessageRole::User,
+        ChatMessageRole::Assistant => PersistedChatMessageRole::Assistant,
+    }
+}
+
+fn worker_from_persisted_worker(worker: PersistedWorker) -> Worker {
+    Worker {
+        id: worker.worker_id,
+        hostname: worker.hostname,
+        version: worker.version,
+        task_kinds: worker.task_kinds.iter().filter_map(|v| task_kind_from_persisted(v)).collect(),
+        models: worker.models,
+        registered_at: datetime_from_offset_date_time(worker.registered_at),
+        last_ping_at: datetime_from_offset_date_time(worker.last_ping_at),
+    }
+}
+
+fn persisted_task_visibility(visibility: TaskVisibility) -> PersistedTaskVisibility {
+    match visibility {
+        TaskVisibility::Private => PersistedTaskVisibility::Private,
+        TaskVisibility::Unlisted => PersistedTaskVisibility::Unlisted,
+        TaskVisibility::Public => PersistedTaskVisibility::Public,
+    }
+}
+
+fn persisted_task_kind(kind: &TaskKind) -> &'static str {
+    match kind {
+        TaskKind::ImageGeneration => "ImageGeneration",
+        TaskKind::ChatMessageGeneration => "ChatMessageGeneration",
+    }
+}
+
+fn persisted_task_status_kind(kind: &TaskStatusKind) -> PersistedTaskStatusKind {
+    match kind {
+        TaskStatusKind::Pending => PersistedTaskStatusKind::Pending,
+        TaskStatusKind::InProgress => PersistedTaskStatusKind::InProgress,
+        TaskStatusKind::Finished => PersistedTaskStatusKind::Finished,
+        TaskStatusKind::Cancelled => PersistedTaskStatusKind::Cancelled,
+        TaskStatusKind::Failed => PersistedTaskStatusKind::Failed,
+    }
+}
+
+fn task_kind_from_persisted(kind: &str) -> Option<TaskKind> {
+    match kind {
+        "ImageGeneration" => Some(TaskKind::ImageGeneration),
+        "ChatMessageGeneration" => Some(TaskKind::ChatMessageGeneration),
+        _ => None,
+    }
+}
+
+fn datetime_from_offset_date_time(value: OffsetDateTime) -> DateTime<Utc> {
+    DateTime::from_utc(NaiveDateTime::from_timestamp_opt(value.unix_timestamp(), 0).unwrap(), Utc)
+}
+
+// range of timestamps coming from requests is checked by handlers.
+fn offset_date_time_from_datetime(value: DateTime<Utc>) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(value.timestamp()).unwrap()
+}
+
+fn offset_date_time_from_micros(micros: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(micros as i128 * 1000).unwrap()
 }
@@ -1,4 +1,7 @@
 use {
+    std::sync::atomic::{AtomicBool, Ordering},
+    tracing::info,
+    tokio::sync::mpsc::UnboundedSender,
     candle::{Device, DType, Tensor},
     candle_nn::VarBuilder,
     candle_transformers::generation::LogitsProcessor,
@@ -57,7 +60,8 @@ impl LlamaChatModel {
         }
     }
 
-    pub fn chat(&self, messages: Vec<Message>) -> Message {
+    /// Reply generated so far is sent to `partial_reply` after every token.
+    pub fn chat(&self, messages: Vec<Message>, cancelled: &AtomicBool, partial_reply: UnboundedSender<String>) -> Message {
         let mut tokens = Vec::new();
 
         for message in messages.chunks(2) {
@@ -93,6 +97,11 @@ impl LlamaChatModel {
         let max_tokens = 5000;
         let mut index = 0;
         while index < max_tokens {
+            if cancelled.load(Ordering::Relaxed) {
+                info!("stopping reply generation because task is cancelled");
+                break;
+            }
+
             let context_size = if index > 0 {
                 1
             } else {
@@ -113,7 +122,8 @@ impl LlamaChatModel {
             tokens.push(next_token);
             new_tokens.push(next_token);
 
-            println!("{}", self.tokenizer.decode(&new_tokens, false).unwrap());
+            // nobody may be listening for partial reply anymore, which is fine.
+            let _ = partial_reply.send(self.tokenizer.decode(&new_tokens, true).unwrap());
 
             index += 1;
         }
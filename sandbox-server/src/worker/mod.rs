@@ -1,7 +1,9 @@
 use {
-    std::{time::Duration, sync::Arc},
+    std::{time::{Duration, Instant}, sync::{Arc, atomic::{AtomicBool, Ordering}}, env::var, panic::AssertUnwindSafe, any::Any},
     tracing::{info, error},
-    tokio::{time::sleep, sync::Mutex},
+    ulid::Ulid,
+    tokio::{time::sleep, sync::mpsc},
+    futures::FutureExt,
     tonic::{
         service::Interceptor,
         metadata::MetadataValue,
@@ -15,33 +17,47 @@ use {
         self,
         sandbox_service_client::SandboxServiceClient,
         task_params::{Params, ImageGenerationParams},
+        worker_message,
+        server_message,
         TaskId,
-        GetTaskToRunRequest,
+        WorkerMessage,
+        TaskAssignment,
         UpdateTaskStatusRequest,
         CreateTaskAssetRequest,
-        GetChatMessagesRequest,
         AddChatAssistantMessageRequest,
+        RegisterWorkerRequest,
     },
     self::{
         llama::{LlamaChatModel, Message, Role},
+        stable_diffusion::{StableDiffusionImageGenerationModel, ImageGenerationStatus},
         storage::Storage,
     },
 };
 
 pub mod llama;
+pub mod stable_diffusion;
 pub mod storage;
 
+type WorkerMessageSender = mpsc::UnboundedSender<WorkerMessage>;
+
+// partial chat replies are sent to server no more often than this.
+const CHAT_PROGRESS_REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
 pub async fn run_worker(config: &Config) {
-    info!("sandbox worker started");
+    let worker_id = config.get_string("worker.id").ok()
+        .or_else(|| var("HOSTNAME").ok())
+        .unwrap_or_else(|| Ulid::new().to_string());
+    info!("sandbox worker started with id {}", worker_id);
+
     let endpoint = config.get_string("worker.endpoint").unwrap();
-    let client = Arc::new(Mutex::new(SandboxServiceClient::with_interceptor(
+    let mut client = SandboxServiceClient::with_interceptor(
         Channel::from_shared(endpoint)
             .unwrap()
             .connect()
             .await
             .unwrap(),
-        AuthTokenSetterInterceptor::new(config.get_string("token.worker_token").unwrap()),
-    )));
+        AuthTokenSetterInterceptor::new(config.get_string("token.worker_token").unwrap(), worker_id),
+    );
 
     let storage = Storage::new(&config);
 
@@ -52,38 +68,148 @@ pub async fn run_worker(config: &Config) {
     info!("chat model loaded");
 
     loop {
-        let res = match client.lock().await.get_task_to_run(GetTaskToRunRequest {}).await {
-            Ok(v) => v.into_inner(),
-            Err(err) => {
-                error!("failed to request task to run: {:?}", err);
-                sleep(Duration::from_secs(10)).await;
-                continue;
+        if let Err(err) = run_worker_session(&mut client, &text_to_image_model, &chat_model).await {
+            error!("worker session failed: {:?}", err);
+        }
+
+        info!("reconnecting to server in 10 seconds");
+        sleep(Duration::from_secs(10)).await;
+    }
+}
+
+async fn run_worker_session(
+    client: &mut SandboxServiceClient<InterceptedService<Channel, AuthTokenSetterInterceptor>>,
+    text_to_image_model: &StableDiffusionImageGenerationModel,
+    chat_model: &LlamaChatModel,
+) -> Result<(), Status> {
+    let (outbound, outbound_rx) = mpsc::unbounded_channel();
+    send(&outbound, worker_message::Message::Register(registration_request()));
+
+    let outbound_stream = futures::stream::unfold(outbound_rx, |mut rx| async move {
+        rx.recv().await.map(|v| (v, rx))
+    });
+    let mut inbound = client.worker_session(outbound_stream).await?.into_inner();
+    info!("connected to server, waiting for tasks");
+
+    // server messages are read in background, so that cancellation is received while model is running.
+    let (assignments_tx, mut assignments) = mpsc::unbounded_channel::<(TaskAssignment, Arc<AtomicBool>)>();
+    let reader = tokio::spawn(async move {
+        let mut current_task: Option<(TaskId, Arc<AtomicBool>)> = None;
+
+        let res = loop {
+            let message = match inbound.message().await {
+                Ok(Some(v)) => v,
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            };
+
+            match message.message {
+                Some(server_message::Message::AssignTask(task)) => {
+                    let cancelled = Arc::new(AtomicBool::new(false));
+                    current_task = Some((task.id.clone().unwrap(), cancelled.clone()));
+
+                    if assignments_tx.send((task, cancelled)).is_err() {
+                        break Ok(());
+                    }
+                },
+                Some(server_message::Message::CancelTask(cancellation)) => {
+                    if let Some((id, cancelled)) = current_task.as_ref() {
+                        if Some(id) == cancellation.task_id.as_ref() {
+                            info!("task {} is cancelled", id.id);
+                            cancelled.store(true, Ordering::Relaxed);
+                        }
+                    }
+                },
+                None => {},
             }
         };
 
-        let task = match res.task_to_run {
+        // server requeues the task when session is closed, so there is no point in finishing it.
+        if let Some((_, cancelled)) = current_task {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+
+        res
+    });
+
+    while let Some((task, cancelled)) = assignments.recv().await {
+        let id = task.id.unwrap();
+        info!("received task {}", id.id);
+
+        let params = match task.params.and_then(|v| v.params) {
             Some(v) => v,
             None => {
-                info!("no tasks at this moment, waiting...");
-                sleep(Duration::from_secs(10)).await;
+                report_failure(&outbound, id, "task_params_missing".to_owned(), false);
                 continue;
             }
         };
 
-        match task.params.unwrap().params.unwrap() {
-            Params::ImageGeneration(image_generation) => run_image_generation_task(client.clone(), &text_to_image_model, task.id.unwrap(), &image_generation).await,
-            Params::ChatMessageGeneration(_) => run_chat_message_generation_task(client.clone(), &chat_model, task.id.unwrap()).await,
+        // model panics are reported as task failures, so that the worker itself keeps running and the task can be retried.
+        let res = match params {
+            Params::ImageGeneration(image_generation) => AssertUnwindSafe(run_image_generation_task(&outbound, text_to_image_model, id.clone(), &image_generation, task.first_image, cancelled)).catch_unwind().await,
+            Params::ChatMessageGeneration(_) => AssertUnwindSafe(run_chat_message_generation_task(&outbound, chat_model, id.clone(), task.chat_messages, cancelled)).catch_unwind().await,
         };
 
-        info!("finished processing task");
+        match res {
+            Ok(_) => info!("finished processing task"),
+            Err(panic) => {
+                let reason = panic_message(panic.as_ref());
+                error!("task {} failed: {}", id.id, reason);
+                report_failure(&outbound, id, reason, true);
+            }
+        }
+    }
+
+    reader.await.unwrap()
+}
+
+fn registration_request() -> RegisterWorkerRequest {
+    RegisterWorkerRequest {
+        hostname: var("HOSTNAME").unwrap_or("unknown".to_owned()),
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        task_kinds: vec![
+            rpc::TaskKind::ImageGeneration.into(),
+            rpc::TaskKind::ChatMessageGeneration.into(),
+        ],
+        models: vec![
+            "stable-diffusion-v2.1".to_owned(),
+            "llama-2-7b-chat".to_owned(),
+        ],
+    }
+}
+
+// send fails only when session is already closed, in which case the task is requeued by server anyway.
+fn send(outbound: &WorkerMessageSender, message: worker_message::Message) {
+    let _ = outbound.send(WorkerMessage { message: Some(message) });
+}
+
+fn report_failure(outbound: &WorkerMessageSender, id: TaskId, reason: String, retryable: bool) {
+    send(outbound, worker_message::Message::TaskStatus(UpdateTaskStatusRequest {
+        id: Some(id),
+        task_status: Some(rpc::update_task_status_request::TaskStatus::Failed(rpc::FailedTaskDetails {
+            reason,
+            retryable,
+        })),
+    }));
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "worker_panicked".to_owned()
     }
 }
 
 async fn run_image_generation_task(
-    client: Arc<Mutex<SandboxServiceClient<InterceptedService<Channel, AuthTokenSetterInterceptor>>>>,
+    outbound: &WorkerMessageSender,
     text_to_image_model: &StableDiffusionImageGenerationModel,
     id: TaskId,
-    params: &ImageGenerationParams
+    params: &ImageGenerationParams,
+    first_image: u32,
+    cancelled: Arc<AtomicBool>
 ) {
     let prompt = params.prompt.clone();
     let total_images = params.number_of_images;
@@ -91,10 +217,10 @@ async fn run_image_generation_task(
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
     {
         let id = id.clone();
-        let client = client.clone();
+        let outbound = outbound.clone();
 
         tokio::spawn(async move {
-            let mut current_image = 0;
+            let mut current_image = first_image;
 
             while let Some(update) = rx.recv().await {
                 match update {
@@ -102,58 +228,62 @@ async fn run_image_generation_task(
                     ImageGenerationStatus::StartedImageGeneration { current_image: i } => {
                         current_image = i;
                     }
-                    ImageGenerationStatus::InProgress { current_step, total_steps } => {
-                        let res = client.lock().await.update_task_status(UpdateTaskStatusRequest {
-                            id: Some(id.clone()),
-                            task_status: Some(rpc::update_task_status_request::TaskStatus::InProgress(rpc::InProgressTaskDetails {
-                                current_step,
-                                total_steps,
-                                current_image,
-                            })),
-                        }).await;
-
-                        if let Err(err) = res {
-                            error!("failed to report task status: {:?}", err);
-                        }
-                    },
+                    ImageGenerationStatus::InProgress { current_step, total_steps } => send(&outbound, worker_message::Message::TaskStatus(UpdateTaskStatusRequest {
+                        id: Some(id.clone()),
+                        task_status: Some(rpc::update_task_status_request::TaskStatus::InProgress(rpc::InProgressTaskDetails {
+                            current_step,
+                            total_steps,
+                            current_image,
+                        })),
+                    })),
                 }
             }
         });
     }
 
-    for image in 0..total_images {
+    // images generated before (task was extended or requeued) are kept, numbering continues after them.
+    for image in first_image..total_images {
+        if cancelled.load(Ordering::Relaxed) {
+            info!("task {} is cancelled, stopping after {} images", id.id, image);
+            break;
+        }
+
         tx.send(ImageGenerationStatus::StartedImageGeneration { current_image: image }).unwrap();
         info!("generating image ({}/{}) for prompt: {}, task id: {}", image + 1, total_images, prompt, id.id);
 
-        let image = text_to_image_model.run(&prompt, tx.clone());
+        let image = match text_to_image_model.run(&prompt, tx.clone(), &cancelled) {
+            Some(v) => v,
+            None => {
+                info!("task {} is cancelled, stopping during image {}", id.id, image + 1);
+                break;
+            }
+        };
         info!("finished generating image");
 
-        client.lock().await.create_task_asset(CreateTaskAssetRequest {
+        send(outbound, worker_message::Message::TaskAsset(CreateTaskAssetRequest {
             task_id: Some(id.clone()),
             image,
-        }).await.unwrap();
+        }));
     }
 
     tx.send(ImageGenerationStatus::Finished).unwrap();
-    client.lock().await.update_task_status(UpdateTaskStatusRequest {
-        id: Some(id.clone()),
+    send(outbound, worker_message::Message::TaskStatus(UpdateTaskStatusRequest {
+        id: Some(id),
         task_status: Some(rpc::update_task_status_request::TaskStatus::Finished(rpc::FinishedTaskDetails {})),
-    }).await.unwrap();
+    }));
 }
 
 async fn run_chat_message_generation_task(
-    client: Arc<Mutex<SandboxServiceClient<InterceptedService<Channel, AuthTokenSetterInterceptor>>>>,
+    outbound: &WorkerMessageSender,
     chat_model: &LlamaChatModel,
-    id: TaskId
+    id: TaskId,
+    mut messages: Vec<rpc::get_chat_messages_response::ChatMessage>,
+    cancelled: Arc<AtomicBool>
 ) {
-    client.lock().await.update_task_status(UpdateTaskStatusRequest {
+    send(outbound, worker_message::Message::TaskStatus(UpdateTaskStatusRequest {
         id: Some(id.clone()),
         task_status: Some(rpc::update_task_status_request::TaskStatus::InProgress(rpc::InProgressTaskDetails { current_step: 0, total_steps: 0, current_image: 0 })),
-    }).await.unwrap();
-
-    let mut messages = client.lock().await.get_chat_messages(GetChatMessagesRequest {
-        task_id: Some(id.clone()),
-    }).await.unwrap().into_inner().messages;
+    }));
 
     messages.sort_by_key(|v| v.message_index);
 
@@ -168,29 +298,58 @@ async fn run_chat_message_generation_task(
         ))
         .collect();
 
-    let res = chat_model.chat(messages);
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let progress_reporter = {
+        let id = id.clone();
+        let outbound = outbound.clone();
+
+        tokio::spawn(async move {
+            let mut last_report: Option<Instant> = None;
+
+            while let Some(content) = rx.recv().await {
+                if content.is_empty() || last_report.map(|v| v.elapsed() < CHAT_PROGRESS_REPORT_INTERVAL).unwrap_or(false) {
+                    continue;
+                }
+                last_report = Some(Instant::now());
+
+                send(&outbound, worker_message::Message::ChatAssistantMessageProgress(rpc::ChatAssistantMessageProgress {
+                    task_id: Some(id.clone()),
+                    content,
+                }));
+            }
+        })
+    };
+
+    let res = chat_model.chat(messages, &cancelled, tx);
+    // all progress should reach server before the final reply.
+    progress_reporter.await.unwrap();
 
     info!("finished running chat message generation: {:?}", res);
 
-    client.lock().await.add_chat_assistant_message(AddChatAssistantMessageRequest {
-        content: res.content().to_owned(),
-        task_id: Some(id.clone()),
-    }).await.unwrap();
+    // when task is cancelled, the part of the reply generated so far is still kept.
+    if !res.content().is_empty() {
+        send(outbound, worker_message::Message::ChatAssistantMessage(AddChatAssistantMessageRequest {
+            content: res.content().to_owned(),
+            task_id: Some(id.clone()),
+        }));
+    }
 
-    client.lock().await.update_task_status(UpdateTaskStatusRequest {
+    send(outbound, worker_message::Message::TaskStatus(UpdateTaskStatusRequest {
         id: Some(id),
         task_status: Some(rpc::update_task_status_request::TaskStatus::Finished(rpc::FinishedTaskDetails {})),
-    }).await.unwrap();
+    }));
 }
 
 pub struct AuthTokenSetterInterceptor {
     token: String,
+    worker_id: String,
 }
 
 impl AuthTokenSetterInterceptor {
-    pub fn new(token: String) -> Self {
+    pub fn new(token: String, worker_id: String) -> Self {
         Self {
             token,
+            worker_id,
         }
     }
 }
@@ -199,6 +358,10 @@ impl Interceptor for AuthTokenSetterInterceptor {
     fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
         let auth_header_value: MetadataValue<tonic::metadata::Ascii> = MetadataValue::try_from(&self.token).expect("failed to create metadata");
         req.metadata_mut().insert("x-access-token", auth_header_value);
+
+        let worker_id_header_value: MetadataValue<tonic::metadata::Ascii> = MetadataValue::try_from(&self.worker_id).expect("failed to create metadata");
+        req.metadata_mut().insert("x-worker-id", worker_id_header_value);
+
         Ok(req)
     }
 }
@@ -218,7 +381,7 @@ mod tests {
                 .connect()
                 .await
                 .unwrap(),
-            AuthTokenSetterInterceptor::new("test-token".to_owned()),
+            AuthTokenSetterInterceptor::new("test-token".to_owned(), "test-worker".to_owned()),
         );
 
         let res = match client.get_task_to_run(GetTaskToRunRequest {}).await {
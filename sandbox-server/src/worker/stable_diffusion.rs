@@ -0,0 +1,127 @@
+use {
+    std::{io::Cursor, sync::atomic::{AtomicBool, Ordering}},
+    tracing::info,
+    tokio::sync::mpsc::UnboundedSender,
+    candle::{Device, DType, Tensor, IndexOp, Module},
+    candle_transformers::models::stable_diffusion::{
+        self,
+        StableDiffusionConfig,
+        clip::ClipTextTransformer,
+        unet_2d::UNet2DConditionModel,
+        vae::AutoEncoderKL,
+    },
+    tokenizers::Tokenizer,
+    image::{RgbImage, ImageOutputFormat},
+    super::storage::Storage,
+};
+
+const HEIGHT: usize = 512;
+const WIDTH: usize = 512;
+const STEPS: usize = 30;
+const GUIDANCE_SCALE: f64 = 7.5;
+const VAE_SCALE: f64 = 0.18215;
+
+pub enum ImageGenerationStatus {
+    StartedImageGeneration { current_image: u32 },
+    InProgress { current_step: u32, total_steps: u32 },
+    Finished,
+}
+
+// based on https://github.com/huggingface/candle/blob/main/candle-examples/examples/stable-diffusion/main.rs
+pub struct StableDiffusionImageGenerationModel {
+    device: Device,
+    config: StableDiffusionConfig,
+    tokenizer: Tokenizer,
+    text_model: ClipTextTransformer,
+    unet: UNet2DConditionModel,
+    vae: AutoEncoderKL,
+}
+
+impl StableDiffusionImageGenerationModel {
+    pub async fn new(storage: &Storage) -> Self {
+        let device = Device::Cpu;
+        let config = StableDiffusionConfig::v1_5(None, Some(HEIGHT), Some(WIDTH));
+
+        let tokenizer = storage.load_model_file("stable_diffusion", "tokenizer.json").await;
+        let tokenizer = Tokenizer::from_file(tokenizer).unwrap();
+
+        let clip_weights = storage.load_model_file("stable_diffusion", "clip.safetensors").await;
+        let text_model = stable_diffusion::build_clip_transformer(&config.clip, clip_weights, &device, DType::F32).unwrap();
+
+        let vae_weights = storage.load_model_file("stable_diffusion", "vae.safetensors").await;
+        let vae = config.build_vae(vae_weights, &device, DType::F32).unwrap();
+
+        let unet_weights = storage.load_model_file("stable_diffusion", "unet.safetensors").await;
+        let unet = config.build_unet(unet_weights, &device, 4, false, DType::F32).unwrap();
+
+        Self {
+            device,
+            config,
+            tokenizer,
+            text_model,
+            unet,
+            vae,
+        }
+    }
+
+    /// Returns png image, or `None` if the task was cancelled. Cancellation is checked before every sampler step.
+    pub fn run(&self, prompt: &str, status: UnboundedSender<ImageGenerationStatus>, cancelled: &AtomicBool) -> Option<Vec<u8>> {
+        let mut scheduler = self.config.build_scheduler(STEPS).unwrap();
+
+        // unconditional embeddings go first, for classifier-free guidance.
+        let text_embeddings = Tensor::cat(&[self.text_embeddings(""), self.text_embeddings(prompt)], 0).unwrap();
+
+        let latents = (Tensor::randn(0f32, 1f32, (1, 4, HEIGHT / 8, WIDTH / 8), &self.device).unwrap() * scheduler.init_noise_sigma()).unwrap();
+        let mut latents = latents.to_dtype(DType::F32).unwrap();
+
+        let timesteps = scheduler.timesteps().to_vec();
+        for (timestep_index, &timestep) in timesteps.iter().enumerate() {
+            if cancelled.load(Ordering::Relaxed) {
+                info!("stopping image generation at step {}/{} because task is cancelled", timestep_index, timesteps.len());
+                return None;
+            }
+
+            // nobody may be listening for progress anymore, which is fine.
+            let _ = status.send(ImageGenerationStatus::InProgress {
+                current_step: timestep_index as u32,
+                total_steps: timesteps.len() as u32,
+            });
+
+            let latent_model_input = Tensor::cat(&[&latents, &latents], 0).unwrap();
+            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep).unwrap();
+            let noise_pred = self.unet.forward(&latent_model_input, timestep as f64, &text_embeddings).unwrap();
+
+            let noise_pred = noise_pred.chunk(2, 0).unwrap();
+            let (noise_pred_uncond, noise_pred_text) = (&noise_pred[0], &noise_pred[1]);
+            let noise_pred = (noise_pred_uncond + ((noise_pred_text - noise_pred_uncond).unwrap() * GUIDANCE_SCALE).unwrap()).unwrap();
+
+            latents = scheduler.step(&noise_pred, timestep, &latents).unwrap();
+        }
+
+        let image = self.vae.decode(&(&latents / VAE_SCALE).unwrap()).unwrap();
+        let image = ((image / 2.).unwrap() + 0.5).unwrap().to_device(&Device::Cpu).unwrap();
+        let image = (image.clamp(0f32, 1.).unwrap() * 255.).unwrap().to_dtype(DType::U8).unwrap().i(0).unwrap();
+
+        Some(encode_png(&image))
+    }
+
+    fn text_embeddings(&self, prompt: &str) -> Tensor {
+        let pad_id = *self.tokenizer.get_vocab(true).get("<|endoftext|>").unwrap();
+        let mut tokens = self.tokenizer.encode(prompt, true).unwrap().get_ids().to_vec();
+        tokens.resize(self.config.clip.max_position_embeddings, pad_id);
+
+        let tokens = Tensor::new(tokens.as_slice(), &self.device).unwrap().unsqueeze(0).unwrap();
+        self.text_model.forward(&tokens).unwrap()
+    }
+}
+
+// image tensor is (channels, height, width).
+fn encode_png(image: &Tensor) -> Vec<u8> {
+    let (_, height, width) = image.dims3().unwrap();
+    let pixels = image.permute((1, 2, 0)).unwrap().flatten_all().unwrap().to_vec1::<u8>().unwrap();
+    let image = RgbImage::from_raw(width as u32, height as u32, pixels).unwrap();
+
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    png.into_inner()
+}
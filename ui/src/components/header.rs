@@ -5,7 +5,7 @@ use {
     yew_router::prelude::*,
     gloo_storage::{Storage, LocalStorage},
     web_sys::window,
-    crate::utils::{Route, MultiClass, start_oauth_flow},
+    crate::utils::{Route, MultiClass},
 };
 
 #[derive(Properties, PartialEq)]
@@ -84,6 +84,26 @@ pub fn header(props: &HeaderProps) -> Html {
         html!()
     };
 
+    let api_keys_menu_entry = if props.is_logged_in {
+        let style = MultiClass::new().with(&menu_entry_style);
+        let style = if props.current_route == Route::ApiKeys {
+            style.with(&active_menu_entry_style)
+        } else {
+            style
+        };
+
+        let open_api_keys = {
+            let navigator = navigator.clone();
+            Callback::from(move |_| {
+                navigator.push(&Route::ApiKeys);
+            })
+        };
+
+        html!(<span class={style} onclick={open_api_keys}>{"api keys"}</span>)
+    } else {
+        html!()
+    };
+
     let about_menu_entry = {
         let style = MultiClass::new().with(&menu_entry_style);
         let style = if props.current_route == Route::About {
@@ -102,7 +122,10 @@ pub fn header(props: &HeaderProps) -> Html {
         html!(<span class={style} onclick={open_about}>{"about"}</span>)
     };
 
-    let login = Callback::from(move |_| start_oauth_flow());
+    let login = {
+        let navigator = navigator.clone();
+        Callback::from(move |_| navigator.push(&Route::Login))
+    };
 
     let login_menu_entry = if props.is_logged_in {
         html!()
@@ -122,6 +145,7 @@ pub fn header(props: &HeaderProps) -> Html {
         <header class={style}>
             <span class={title_style} onclick={return_home}>{ "sandbox" }</span>
             { history_menu_entry }
+            { api_keys_menu_entry }
             { about_menu_entry }
             { login_menu_entry }
             { logout_menu_entry }
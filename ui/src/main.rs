@@ -14,6 +14,7 @@ use {
             task::TaskPage,
             login::LoginPage,
             history::HistoryPage,
+            api_keys::ApiKeysPage,
             home::HomePage,
             about::AboutPage,
         },
@@ -73,6 +74,7 @@ fn router_component(props: &RouterComponentProps) -> Html {
         Route::Login => html!(<LoginPage login={login} />),
         Route::Task { id }=> html!(<TaskPage task_id={id.clone()} />),
         Route::History => html!(<HistoryPage />),
+        Route::ApiKeys => html!(<ApiKeysPage />),
         Route::About => html!(<AboutPage />),
     };
 
@@ -0,0 +1,280 @@
+use {
+    std::sync::{Arc, Mutex},
+    tracing::error,
+    yew::prelude::*,
+    yew_router::prelude::*,
+    wasm_bindgen_futures::spawn_local,
+    web_sys::{EventTarget, HtmlInputElement},
+    wasm_bindgen::JsCast,
+    stylist::{style, yew::styled_component},
+    tonic::Code,
+    rpc::{ApiKey, ApiKeyScope, CreateApiKeyRequest, ListApiKeysRequest, RevokeApiKeyRequest},
+    crate::utils::{Route, client},
+};
+
+#[styled_component(ApiKeysPage)]
+pub fn api_keys_page() -> Html {
+    let navigator = use_navigator().unwrap();
+    let client = Arc::new(Mutex::new(client()));
+
+    let api_keys = use_state(|| None::<Vec<ApiKey>>);
+    let name = use_state(String::new);
+    let scope = use_state(|| ApiKeyScope::ReadOnly);
+    // key is shown only right after it is created, server does not store it.
+    let created_key = use_state(|| None::<String>);
+
+    {
+        let client = client.clone();
+        let api_keys = api_keys.clone();
+
+        use_effect_with_deps(move |_| {
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+                match client.list_api_keys(ListApiKeysRequest {}).await {
+                    Ok(v) => api_keys.set(Some(v.into_inner().api_keys)),
+                    Err(err) if err.code() == Code::Unauthenticated => navigator.push(&Route::Login),
+                    Err(err) => error!("failed to list api keys: {:?}", err),
+                }
+            });
+        }, [None::<String>]);
+    }
+
+    let create_key = {
+        let client = client.clone();
+        let api_keys = api_keys.clone();
+        let name = name.clone();
+        let scope = scope.clone();
+        let created_key = created_key.clone();
+
+        Callback::from(move |_| {
+            if name.trim().is_empty() {
+                return;
+            }
+
+            let client = client.clone();
+            let api_keys = api_keys.clone();
+            let name = name.clone();
+            let scope = *scope;
+            let created_key = created_key.clone();
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+                let res = client.create_api_key(CreateApiKeyRequest {
+                    name: (*name).clone(),
+                    scope: scope.into(),
+                }).await;
+
+                match res {
+                    Ok(v) => {
+                        let res = v.into_inner();
+                        let mut keys = (*api_keys).clone().unwrap_or_default();
+                        keys.insert(0, res.api_key.unwrap());
+
+                        api_keys.set(Some(keys));
+                        created_key.set(Some(res.key));
+                        name.set(String::new());
+                    },
+                    Err(err) => error!("failed to create api key: {:?}", err),
+                }
+            });
+        })
+    };
+
+    let revoke_key = {
+        let client = client.clone();
+        let api_keys = api_keys.clone();
+
+        Callback::from(move |id: String| {
+            let client = client.clone();
+            let api_keys = api_keys.clone();
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+                let res = client.revoke_api_key(RevokeApiKeyRequest {
+                    id: id.clone(),
+                }).await;
+
+                match res {
+                    Ok(_) => api_keys.set((*api_keys).as_ref().map(|keys| keys.iter()
+                        .filter(|v| v.id != id)
+                        .cloned()
+                        .collect())),
+                    Err(err) => error!("failed to revoke api key: {:?}", err),
+                }
+            });
+        })
+    };
+
+    let style = style!(r#"
+        width: 720px;
+        margin: 0 auto;
+
+        h2 {
+            font-weight: 400;
+        }
+
+        .description {
+            opacity: 0.7;
+            margin-bottom: 24px;
+        }
+
+        .create {
+            display: flex;
+            gap: 8px;
+            margin-bottom: 24px;
+        }
+
+        input, select {
+            padding: 6px 8px;
+            font-size: 12pt;
+            border-radius: 4px;
+            border: none;
+            outline: none;
+        }
+
+        input {
+            flex-grow: 1;
+        }
+
+        button {
+            padding: 4px 12px;
+            font-size: 11pt;
+            background-color: transparent;
+            color: white;
+            border: 1px solid white;
+            border-radius: 4px;
+            cursor: pointer;
+            transition: color 0.2s ease-out, background-color 0.2s ease-out;
+        }
+
+        button:hover {
+            background-color: white;
+            color: black;
+        }
+
+        .created-key {
+            padding: 12px;
+            margin-bottom: 24px;
+            border: 1px solid #5695DC;
+            border-radius: 4px;
+        }
+
+        .created-key code {
+            display: block;
+            margin-top: 8px;
+            user-select: all;
+        }
+
+        table {
+            width: 100%;
+            border-collapse: collapse;
+        }
+
+        td, th {
+            text-align: left;
+            padding: 8px 4px;
+            border-bottom: 1px solid #3D3D3D;
+            font-weight: 400;
+        }
+    "#).unwrap();
+
+    let created_key_notice = match created_key.as_ref() {
+        Some(key) => html!(
+            <div class="created-key">
+                {"copy your new key now, it will not be shown again:"}
+                <code>{ key.clone() }</code>
+            </div>
+        ),
+        None => html!(),
+    };
+
+    let keys = match api_keys.as_ref() {
+        None => html!(<div>{"loading api keys..."}</div>),
+        Some(keys) if keys.is_empty() => html!(<div>{"you have no api keys yet."}</div>),
+        Some(keys) => {
+            let rows = keys.iter()
+                .map(|key| {
+                    let revoke_key = revoke_key.clone();
+                    let id = key.id.clone();
+
+                    html!(
+                        <tr>
+                            <td>{ key.name.clone() }</td>
+                            <td><code>{ format!("{}...", key.key_prefix) }</code></td>
+                            <td>{ scope_name(key.scope()) }</td>
+                            <td>{ key.last_used_at.as_ref().map(|v| format_timestamp(v.seconds)).unwrap_or("never".to_owned()) }</td>
+                            <td><button onclick={move |_| revoke_key.emit(id.clone())}>{"revoke"}</button></td>
+                        </tr>
+                    )
+                })
+                .collect::<Html>();
+
+            html!(
+                <table>
+                    <tr>
+                        <th>{"name"}</th>
+                        <th>{"key"}</th>
+                        <th>{"scope"}</th>
+                        <th>{"last used"}</th>
+                        <th></th>
+                    </tr>
+                    { rows }
+                </table>
+            )
+        },
+    };
+
+    html!(
+        <div class={style}>
+            <h2>{"api keys"}</h2>
+            <div class="description">
+                {"api keys can be used instead of login token in scripts, pass them in x-access-token header or as bearer token for openai-compatible endpoints."}
+            </div>
+            <div class="create">
+                <input
+                    placeholder="key name, for example: ci"
+                    value={(*name).clone()}
+                    onchange={
+                        let name = name.clone();
+                        move |e: Event| {
+                            let target: Option<EventTarget> = e.target();
+                            if let Some(input) = target.and_then(|t| t.dyn_into::<HtmlInputElement>().ok()) {
+                                name.set(input.value());
+                            }
+                        }
+                    } />
+                <select onchange={
+                    let scope = scope.clone();
+                    move |e: Event| {
+                        let target: Option<EventTarget> = e.target();
+                        if let Some(select) = target.and_then(|t| t.dyn_into::<web_sys::HtmlSelectElement>().ok()) {
+                            scope.set(if select.value() == "create_tasks" { ApiKeyScope::CreateTasks } else { ApiKeyScope::ReadOnly });
+                        }
+                    }
+                }>
+                    <option value="read_only" selected={*scope == ApiKeyScope::ReadOnly}>{"read only"}</option>
+                    <option value="create_tasks" selected={*scope == ApiKeyScope::CreateTasks}>{"create tasks"}</option>
+                </select>
+                <button onclick={create_key}>{"create key"}</button>
+            </div>
+            { created_key_notice }
+            { keys }
+        </div>
+    )
+}
+
+fn scope_name(scope: ApiKeyScope) -> &'static str {
+    match scope {
+        ApiKeyScope::ReadOnly => "read only",
+        ApiKeyScope::CreateTasks => "create tasks",
+    }
+}
+
+fn format_timestamp(seconds: i64) -> String {
+    let now = web_time::SystemTime::now()
+        .duration_since(web_time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    timeago::Formatter::new().convert(std::time::Duration::from_secs((now - seconds).max(0) as u64))
+}
@@ -1,81 +1,245 @@
 use {
-    std::{sync::{Arc, Mutex}, time::Duration},
-    tracing::info,
+    std::{sync::{Arc, Mutex}, time::Duration, rc::Rc},
+    tracing::{info, error},
     yew::prelude::*,
     yew_router::prelude::*,
+    wasm_bindgen::{JsCast, closure::Closure},
     wasm_bindgen_futures::spawn_local,
+    web_sys::window,
     stylist::{style, yew::styled_component},
     timeago::Formatter,
     tonic::Code,
-    rpc::{self, Task, GetAllTasksRequest},
-    crate::utils::{Route, client},
+    rpc::{self, Task, TaskId, GetAllTasksRequest, DeleteTaskRequest},
+    crate::{
+        components::prompt_input::PromptInput,
+        utils::{Route, client, error_message},
+    },
 };
 
-#[derive(Properties, Eq, PartialEq)]
+const PAGE_SIZE: u32 = 30;
+// distance from the end of the list at which the next page starts loading.
+const LOAD_MORE_THRESHOLD_PX: f64 = 600.0;
+
+#[derive(Properties, PartialEq)]
 pub struct HistoryEntryProps {
     id: String,
     prompt: String,
     finished: bool,
     time_since: Duration,
-    cover_asset_id: Option<String>,
+    cover_asset_url: Option<String>,
+    on_delete: Callback<String>,
+}
+
+#[derive(Clone, Default, PartialEq)]
+pub struct HistoryState {
+    tasks: Vec<Task>,
+    // cursor of the next page, not set when all pages are loaded.
+    next_cursor: Option<String>,
+    is_loaded: bool,
+    is_loading: bool,
+    error: Option<String>,
+    query: String,
+    // incremented on every search, pages of previous searches are dropped when they arrive.
+    generation: u32,
+}
+
+pub enum HistoryAction {
+    Search(String),
+    StartLoading,
+    PageLoaded { generation: u32, cursor: Option<String>, tasks: Vec<Task>, next_cursor: Option<String> },
+    LoadFailed { generation: u32, error: String },
+    RemoveTask(String),
+}
+
+impl Reducible for HistoryState {
+    type Action = HistoryAction;
+
+    fn reduce(self: Rc<Self>, action: Self::Action) -> Rc<Self> {
+        match action {
+            Self::Action::Search(query) => Self {
+                query,
+                generation: self.generation + 1,
+                ..Self::default()
+            },
+            Self::Action::StartLoading => Self {
+                is_loading: true,
+                ..(*self).clone()
+            },
+            // scroll events may request the same page twice before state is updated, only the first response is used.
+            Self::Action::PageLoaded { generation, cursor, tasks, next_cursor } => {
+                if generation != self.generation || cursor != self.next_cursor || (cursor.is_none() && self.is_loaded) {
+                    return self;
+                }
+
+                Self {
+                    tasks: self.tasks.iter().cloned().chain(tasks).collect(),
+                    next_cursor,
+                    is_loaded: true,
+                    is_loading: false,
+                    ..(*self).clone()
+                }
+            },
+            Self::Action::LoadFailed { generation, error } => {
+                if generation != self.generation {
+                    return self;
+                }
+
+                Self {
+                    is_loading: false,
+                    error: Some(error),
+                    ..(*self).clone()
+                }
+            },
+            Self::Action::RemoveTask(id) => Self {
+                tasks: self.tasks.iter().filter(|v| v.id.as_ref().unwrap().id != id).cloned().collect(),
+                ..(*self).clone()
+            },
+        }.into()
+    }
 }
 
 #[styled_component(HistoryPage)]
 pub fn history_page() -> Html {
     let navigator = use_navigator().unwrap();
     let client = Arc::new(Mutex::new(client()));
-    let state = use_state(|| None::<Vec<Task>>);
-    let state_setter = state.setter();
+    let state = use_reducer(HistoryState::default);
+    let search_query = use_state(String::new);
 
-    use_effect_with_deps(move |_| {
+    let delete_task = {
         let client = client.clone();
-        let state_setter = state_setter.clone();
-
-        spawn_local(async move {
-            let mut client = client.lock().unwrap();
-            let tasks = match client.get_all_tasks(GetAllTasksRequest {}).await {
-                Ok(v) => v.into_inner(),
-                Err(err) => match err.code() {
-                    Code::Unauthenticated => {
-                        navigator.push(&Route::Login);
-                        return;
+        let state = state.clone();
+
+        Callback::from(move |id: String| {
+            let client = client.clone();
+            let state = state.clone();
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+                let res = client.delete_task(DeleteTaskRequest {
+                    task_id: Some(TaskId { id: id.clone() }),
+                }).await;
+
+                match res {
+                    Ok(_) => state.dispatch(HistoryAction::RemoveTask(id)),
+                    Err(err) => error!("failed to delete task: {:?}", err),
+                }
+            });
+        })
+    };
+
+    let load_more = {
+        let client = client.clone();
+        let state = state.clone();
+
+        Callback::from(move |_| {
+            if state.is_loading || state.error.is_some() || (state.is_loaded && state.next_cursor.is_none()) {
+                return;
+            }
+
+            let client = client.clone();
+            let state = state.clone();
+            let navigator = navigator.clone();
+            let generation = state.generation;
+            let cursor = state.next_cursor.clone();
+            let query = state.query.clone();
+
+            state.dispatch(HistoryAction::StartLoading);
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+                let res = client.get_all_tasks(GetAllTasksRequest {
+                    cursor: cursor.clone().unwrap_or_default(),
+                    limit: PAGE_SIZE,
+                    query,
+                    ..GetAllTasksRequest::default()
+                }).await;
+
+                match res {
+                    Ok(res) => {
+                        let res = res.into_inner();
+                        state.dispatch(HistoryAction::PageLoaded {
+                            generation,
+                            cursor,
+                            tasks: res.tasks,
+                            next_cursor: res.next_cursor,
+                        });
                     },
-                    other => panic!("error while getting all tasks: {:?}", other),
-                },
-            };
-            state_setter.set(Some(tasks.tasks));
+                    Err(err) if err.code() == Code::Unauthenticated => navigator.push(&Route::Login),
+                    Err(err) => {
+                        error!("error while getting tasks: {:?}", err);
+                        state.dispatch(HistoryAction::LoadFailed { generation, error: error_message(&err) });
+                    },
+                }
+            });
+        })
+    };
+
+    // next page is loaded when the end of the list is close to the viewport: on scroll, and after every update in case
+    // loaded tasks do not fill the screen yet.
+    use_effect_with_deps(move |_| {
+        let window = window().unwrap();
+        if is_near_bottom() {
+            load_more.emit(());
+        }
+
+        let listener = Closure::<dyn Fn()>::new(move || {
+            if is_near_bottom() {
+                load_more.emit(());
+            }
+        });
+        window.add_event_listener_with_callback("scroll", listener.as_ref().unchecked_ref()).unwrap();
+
+        move || {
+            window.remove_event_listener_with_callback("scroll", listener.as_ref().unchecked_ref()).unwrap();
+        }
+    }, (*state).clone());
+
+    let search = {
+        let state = state.clone();
+        let search_query = search_query.clone();
+
+        Callback::from(move |_| {
+            let query = search_query.trim().to_owned();
+            if query != state.query {
+                state.dispatch(HistoryAction::Search(query));
+            }
         })
-    }, [None::<String>]);
+    };
 
     let loading_style = style!(r#"
         text-align: center;
         font-size: 14pt;
-        margin: 0 auto;
+        margin: 20px auto;
         display: block;
     "#).unwrap();
 
-    let tasks = if state.is_some() {
-        let tasks: Vec<_> = state.iter()
-            .flat_map(|v| v.iter())
-            .map(|v| {
-                let prompt = match v.params.as_ref().unwrap().params.as_ref().unwrap() {
-                    rpc::task_params::Params::ImageGeneration(v) => v.prompt.clone(),
-                    rpc::task_params::Params::ChatMessageGeneration(_) => "chat".to_owned(),
-                };
-
-                html!(<HistoryEntry 
-                    id={v.id.as_ref().unwrap().id.clone()} 
-                    prompt={prompt}
-                    finished={is_finished(v)}
-                    time_since={Duration::from_secs(web_time::SystemTime::now().duration_since(web_time::UNIX_EPOCH).unwrap().as_secs() - v.created_at.as_ref().unwrap().seconds as u64)}
-                    cover_asset_id={v.assets.get(0).map(|v| v.id.clone())} />
-                )
-            })
-            .collect();
-
-        html!(<>{ tasks }</>)
-    } else {
+    let tasks: Vec<_> = state.tasks.iter()
+        .map(|v| {
+            let prompt = match v.params.as_ref().unwrap().params.as_ref().unwrap() {
+                rpc::task_params::Params::ImageGeneration(v) => v.prompt.clone(),
+                rpc::task_params::Params::ChatMessageGeneration(_) => "chat".to_owned(),
+            };
+
+            html!(<HistoryEntry 
+                id={v.id.as_ref().unwrap().id.clone()} 
+                prompt={prompt}
+                finished={is_finished(v)}
+                time_since={Duration::from_secs(web_time::SystemTime::now().duration_since(web_time::UNIX_EPOCH).unwrap().as_secs() - v.created_at.as_ref().unwrap().seconds as u64)}
+                cover_asset_url={v.assets.get(0).map(|v| v.url.clone())}
+                on_delete={delete_task.clone()} />
+            )
+        })
+        .collect();
+
+    let footer = if let Some(error) = &state.error {
+        html!(<span class={loading_style}>{ error.clone() }</span>)
+    } else if state.is_loading || !state.is_loaded {
         html!(<span class={loading_style}>{"Loading..."}</span>)
+    } else if state.tasks.is_empty() {
+        let message = if state.query.is_empty() { "You have no tasks yet." } else { "No tasks match your search." };
+        html!(<span class={loading_style}>{ message }</span>)
+    } else {
+        html!()
     };
 
     let header_style = style!(r#"
@@ -84,10 +248,28 @@ pub fn history_page() -> Html {
         margin-bottom: 16px;
     "#).unwrap();
 
+    let search_style = style!(r#"
+        display: flex;
+        justify-content: center;
+    "#).unwrap();
+
     html!(
         <div>
             <h1 class={header_style}>{"All tasks"}</h1>
-            { tasks }
+            <div class={search_style}>
+                <PromptInput
+                    description={"search prompts and chats"}
+                    action_name={"search"}
+                    action_button_width={120}
+                    value={(*search_query).clone()}
+                    on_change={
+                        let search_query = search_query.clone();
+                        move |v| search_query.set(v)
+                    }
+                    on_run_inference={search} />
+            </div>
+            <>{ tasks }</>
+            { footer }
         </div>
     )
 }
@@ -179,6 +361,23 @@ pub fn history_entry(props: &HistoryEntryProps) -> Html {
             background-color: #F6F4F3;
             color: #5695DC;
         }
+
+        div {
+            margin: auto;
+        }
+
+        .delete {
+            margin-top: 8px;
+            background-color: transparent;
+            border-color: #CED0CE;
+            color: #CED0CE;
+        }
+
+        .delete:hover {
+            background-color: #D64933;
+            border-color: #D64933;
+            color: white;
+        }
     "#).unwrap();
 
     let task_timestamp_style = style!(r#"
@@ -188,11 +387,11 @@ pub fn history_entry(props: &HistoryEntryProps) -> Html {
         font-size: 10pt;
     "#).unwrap();
 
-    let image = if let Some(asset_id) = props.cover_asset_id.as_ref() {
+    let image = if let Some(asset_url) = props.cover_asset_url.as_ref() {
         html!(
             <div class={image_style}>
                 <span>{"loading..."}</span>
-                <img src={format!("/v1/storage/{}", asset_id)} />
+                <img src={asset_url.clone()} />
             </div>
         )
     } else {
@@ -208,6 +407,19 @@ pub fn history_entry(props: &HistoryEntryProps) -> Html {
         })
     };
 
+    let delete_task = {
+        let id = props.id.clone();
+        let on_delete = props.on_delete.clone();
+
+        Callback::from(move |_| on_delete.emit(id.clone()))
+    };
+
+    let delete_button = if props.finished {
+        html!(<button class="delete" onclick={delete_task}>{"delete"}</button>)
+    } else {
+        html!()
+    };
+
     html!(
         <div class={entry_style}>
             { image }
@@ -216,16 +428,26 @@ pub fn history_entry(props: &HistoryEntryProps) -> Html {
                 <span class={task_timestamp_style}>{Formatter::new().convert(props.time_since)}</span>
             </div>
             <div class={controls_style}>
-                <button onclick={open_task}>{"open"}</button>
+                <div>
+                    <button onclick={open_task}>{"open"}</button>
+                    { delete_button }
+                </div>
             </div>
         </div>
     )
 }
 
 fn is_finished(task: &Task) -> bool {
-    if let rpc::task::Status::FinishedDetails(_) = task.status.as_ref().unwrap() {
-        return true;
-    }
+    matches!(
+        task.status.as_ref().unwrap(),
+        rpc::task::Status::FinishedDetails(_) | rpc::task::Status::CancelledDetails(_) | rpc::task::Status::FailedDetails(_)
+    )
+}
 
-    false
-}
\ No newline at end of file
+fn is_near_bottom() -> bool {
+    let window = window().unwrap();
+    let viewport_bottom = window.scroll_y().unwrap_or(0.0) + window.inner_height().ok().and_then(|v| v.as_f64()).unwrap_or(0.0);
+    let page_height = window.document().and_then(|v| v.body()).map(|v| v.scroll_height() as f64).unwrap_or(0.0);
+
+    viewport_bottom >= page_height - LOAD_MORE_THRESHOLD_PX
+}
@@ -5,10 +5,10 @@ use {
     tracing::info,
     stylist::{style, yew::styled_component},
     wasm_bindgen_futures::spawn_local,
-    rpc::{CreateTaskRequest, TaskParams, task_params::{Params, ChatMessageGenerationParams}, AddChatUserMessageRequest},
+    rpc::{CreateTaskRequest, TaskParams, TaskVisibility, task_params::{Params, ChatMessageGenerationParams}, AddChatUserMessageRequest},
     crate::{
         components::{prompt_input::PromptInput, model_highlight::ModelHighlight},
-        utils::{client_with_token, Route},
+        utils::{client_with_token, save_task_token, Route, error_message},
     },
     super::reducer::{ChatParams, TaskCreationParams, TaskCreationParamsAction},
 };
@@ -25,10 +25,12 @@ pub fn chat_task_creation(props: &ChatTaskCreationProps) -> Html {
     let navigator = use_navigator().unwrap();
     let params = props.params.clone();
     let client = Arc::new(Mutex::new(client_with_token((props.token).clone())));
+    let error = use_state(|| None::<String>);
     
     let start_chat = {
         let client = client.clone();
         let navigator = navigator.clone();
+        let error = error.clone();
 
         let message = params.message.clone();
 
@@ -37,24 +39,43 @@ pub fn chat_task_creation(props: &ChatTaskCreationProps) -> Html {
             let navigator = navigator.clone();
 
             let message = message.clone();
+            let error = error.clone();
 
             spawn_local(async move {
                 let mut client = client.lock().unwrap();
                 let res = client.create_task(CreateTaskRequest {
                     params: Some(TaskParams {
                         params: Some(Params::ChatMessageGeneration(ChatMessageGenerationParams {})),
+                        visibility: TaskVisibility::Unlisted.into(),
                     }),
 
                     user_message: Some(message),
-                }).await.unwrap().into_inner();
+                    priority: 0,
+                }).await;
 
-                navigator.push(&Route::Task {
-                    id: res.id.unwrap().id,
-                });
+                match res {
+                    Ok(res) => {
+                        let res = res.into_inner();
+                        let id = res.id.unwrap().id;
+                        save_task_token(&id, &res.capability_token);
+                        navigator.push(&Route::Task { id });
+                    },
+                    Err(err) => error.set(Some(error_message(&err))),
+                }
             });
         })
     };
 
+    let error_style = style!(r#"
+        margin-top: 16px;
+        color: #E57373;
+    "#).unwrap();
+
+    let error_notice = match error.as_ref() {
+        Some(err) => html!(<div class={error_style}>{ err.clone() }</div>),
+        None => html!(),
+    };
+
     html!(
         <>
             <ModelHighlight>{"Enter your message to chat with LLM-powered assistant!"}</ModelHighlight>
@@ -68,6 +89,7 @@ pub fn chat_task_creation(props: &ChatTaskCreationProps) -> Html {
                     move |v| params_dispatcher.dispatch(TaskCreationParamsAction::UpdateChatMessage(v))
                 }
                 on_run_inference={start_chat} />
+            { error_notice }
         </>
     )
 }
\ No newline at end of file
@@ -8,9 +8,9 @@ use {
     wasm_bindgen::JsCast,
     stylist::{style, yew::styled_component},
     wasm_bindgen_futures::spawn_local,
-    rpc::{CreateTaskRequest, TaskParams, task_params::{Params, ImageGenerationParams as RpcImageGenerationParams}},
+    rpc::{CreateTaskRequest, TaskParams, TaskVisibility, task_params::{Params, ImageGenerationParams as RpcImageGenerationParams}},
     crate::{
-        utils::{client_with_token, Route},
+        utils::{client_with_token, save_task_token, Route, error_message},
         components::{
             prompt_input::PromptInput,
             model_highlight::ModelHighlight,
@@ -119,11 +119,13 @@ pub fn image_generation_task_creation(props: &ImageGenerationTaskCreationProps)
     let params = props.params.clone();
     let params_dispatcher = props.params_dispatcher.clone();
     let client = Arc::new(Mutex::new(client_with_token((props.token).clone())));
+    let error = use_state(|| None::<String>);
 
     let run_inference = {
         let params = params.clone();
         let client = client.clone();
         let navigator = navigator.clone();
+        let error = error.clone();
 
         let prompt = params.prompt.clone();
 
@@ -133,6 +135,7 @@ pub fn image_generation_task_creation(props: &ImageGenerationTaskCreationProps)
 
             let prompt = prompt.clone();
             let params = params.clone();
+            let error = error.clone();
 
             spawn_local(async move {
                 let mut client = client.lock().unwrap();
@@ -143,13 +146,22 @@ pub fn image_generation_task_creation(props: &ImageGenerationTaskCreationProps)
                             number_of_images: params.number_of_images,
                             prompt,
                         })),
+                        visibility: TaskVisibility::Unlisted.into(),
                     }),
 
                     user_message: None,
-                }).await.unwrap().into_inner();
-                navigator.push(&Route::Task {
-                    id: res.id.unwrap().id,
-                });
+                    priority: 0,
+                }).await;
+
+                match res {
+                    Ok(res) => {
+                        let res = res.into_inner();
+                        let id = res.id.unwrap().id;
+                        save_task_token(&id, &res.capability_token);
+                        navigator.push(&Route::Task { id });
+                    },
+                    Err(err) => error.set(Some(error_message(&err))),
+                }
             });
         })
     };
@@ -205,6 +217,16 @@ pub fn image_generation_task_creation(props: &ImageGenerationTaskCreationProps)
         }
     "#).unwrap();
 
+    let error_style = style!(r#"
+        margin-top: 16px;
+        color: #E57373;
+    "#).unwrap();
+
+    let error_notice = match error.as_ref() {
+        Some(err) => html!(<div class={error_style}>{ err.clone() }</div>),
+        None => html!(),
+    };
+
     let number_of_images_options = [1, 5, 10];
 
     let number_of_images_components = number_of_images_options
@@ -224,7 +246,9 @@ pub fn image_generation_task_creation(props: &ImageGenerationTaskCreationProps)
             let target: Option<EventTarget> = e.target();
             let input = target.and_then(|t| t.dyn_into::<HtmlInputElement>().ok());
             if let Some(input) = input {
-                params_dispatcher.dispatch(TaskCreationParamsAction::SetCustomNumberOfImages(input.value().parse().unwrap()));
+                if let Ok(number_of_images) = input.value().parse() {
+                    params_dispatcher.dispatch(TaskCreationParamsAction::SetCustomNumberOfImages(number_of_images));
+                }
             }
         }
     };
@@ -243,9 +267,10 @@ pub fn image_generation_task_creation(props: &ImageGenerationTaskCreationProps)
                 <div class={option_name_style}>{"number of images"}</div>
                 <div class={option_selector_style}>
                     { number_of_images_components }
-                    <input type="number" onchange={on_number_of_images_custom_change} value={if params.number_of_images_custom { Some(params.number_of_images.to_string()) } else { None }} />
+                    <input type="number" min="1" onchange={on_number_of_images_custom_change} value={if params.number_of_images_custom { Some(params.number_of_images.to_string()) } else { None }} />
                 </div>
             </div>
+            { error_notice }
         </>
     )
 }
@@ -1,12 +1,14 @@
 use {
     std::sync::{Arc, Mutex},
+    tracing::error,
     yew::prelude::*,
     yew_router::prelude::*,
     serde::Deserialize,
     gloo_storage::{LocalStorage, Storage},
     wasm_bindgen_futures::spawn_local,
-    web_sys::window,
-    rpc::OAuthLoginRequest,
+    web_sys::{window, EventTarget, HtmlInputElement},
+    wasm_bindgen::JsCast,
+    rpc::{OAuthLoginRequest, ListAuthProvidersRequest, ListAuthProvidersResponse, PasswordLoginRequest, RegisterLocalAccountRequest},
     stylist::{style, yew::styled_component},
     crate::utils::{Route, client, start_oauth_flow},
 };
@@ -14,6 +16,7 @@ use {
 #[derive(Deserialize, Debug)]
 struct LoginQuery {
     code: Option<String>,
+    state: Option<String>,
 }
 
 #[derive(Properties, PartialEq)]
@@ -30,9 +33,55 @@ pub fn login_page(props: &LoginProps) -> Html {
         font-size: 20pt;
         font-weight: 100;
         padding-top: 200px;
+
+        .providers {
+            display: flex;
+            flex-direction: column;
+            align-items: center;
+            gap: 12px;
+            margin-top: 32px;
+        }
+
+        button {
+            width: 280px;
+            padding: 8px 12px;
+            font-size: 12pt;
+            background-color: transparent;
+            color: white;
+            border: 1px solid white;
+            border-radius: 4px;
+            cursor: pointer;
+            transition: color 0.2s ease-out, background-color 0.2s ease-out;
+        }
+
+        button:hover {
+            background-color: white;
+            color: black;
+        }
+
+        .password-login {
+            display: flex;
+            flex-direction: column;
+            align-items: center;
+            gap: 12px;
+            margin-top: 32px;
+        }
+
+        .password-login input {
+            width: 264px;
+            padding: 6px 8px;
+            font-size: 12pt;
+            border-radius: 4px;
+            border: none;
+            outline: none;
+        }
     "#).unwrap();
 
     let client = Arc::new(Mutex::new(client()));
+    let providers = use_state(|| None::<ListAuthProvidersResponse>);
+    let login_error = use_state(|| None::<String>);
+    let username = use_state(String::new);
+    let password = use_state(String::new);
 
     let location = match use_location() {
         Some(v) => v,
@@ -42,30 +91,192 @@ pub fn login_page(props: &LoginProps) -> Html {
 
     let login_callback = props.login.clone();
 
-    use_effect_with_deps(move |code| {
-        let code = code.clone();
+    {
+        let client = client.clone();
+        let navigator = navigator.clone();
+        let login_callback = login_callback.clone();
+        let providers = providers.clone();
+        let login_error = login_error.clone();
+
+        use_effect_with_deps(move |(code, state)| {
+            let code = code.clone();
+            let state = state.clone();
+
+            if let Some(code) = code {
+                let expected_state = LocalStorage::get::<String>("oauth_state").ok();
+                let provider = LocalStorage::get::<String>("oauth_provider").ok();
+                LocalStorage::delete("oauth_state");
+                LocalStorage::delete("oauth_provider");
+
+                let provider = match provider {
+                    Some(provider) if state.is_some() && state == expected_state => provider,
+                    _ => {
+                        login_error.set(Some("login request is not valid, please try again.".to_owned()));
+                        return;
+                    }
+                };
+
+                spawn_local(async move {
+                    let mut client = client.lock().unwrap();
+
+                    let res = client.o_auth_login(OAuthLoginRequest {
+                        code: code.to_owned(),
+                        redirect_uri: format!("{}/login", window().unwrap().location().origin().unwrap()),
+                        provider,
+                    }).await;
+
+                    match res {
+                        Ok(res) => {
+                            LocalStorage::set("access_token", res.into_inner().token).unwrap();
+                            login_callback.emit(());
+                            navigator.push(&Route::Home);
+                        },
+                        Err(err) => {
+                            error!("failed to log in: {:?}", err);
+                            login_error.set(Some("failed to log in, please try again.".to_owned()));
+                        }
+                    }
+                });
+            } else {
+                spawn_local(async move {
+                    let mut client = client.lock().unwrap();
+
+                    match client.list_auth_providers(ListAuthProvidersRequest {}).await {
+                        Ok(res) => {
+                            let res = res.into_inner();
+
+                            // no need to ask which provider to use when there is only one.
+                            if res.providers.len() == 1 && !res.local_accounts_enabled {
+                                start_oauth_flow(&res.providers[0]);
+                            } else {
+                                providers.set(Some(res));
+                            }
+                        },
+                        Err(err) => {
+                            error!("failed to list auth providers: {:?}", err);
+                            login_error.set(Some("failed to load login options.".to_owned()));
+                        }
+                    }
+                });
+            }
+        }, (query.code.clone(), query.state.clone()));
+    }
+
+    // same handler is used for login and registration, they differ only in rpc which is called.
+    let password_login = {
+        let client = client.clone();
+        let username = username.clone();
+        let password = password.clone();
+        let login_error = login_error.clone();
+
+        Callback::from(move |register: bool| {
+            let client = client.clone();
+            let username = (*username).clone();
+            let password = (*password).clone();
+            let login_error = login_error.clone();
+            let navigator = navigator.clone();
+            let login_callback = login_callback.clone();
 
-        if let Some(code) = code {
             spawn_local(async move {
                 let mut client = client.lock().unwrap();
 
-                let res = client.o_auth_login(OAuthLoginRequest {
-                    code: code.to_owned(),
-                    redirect_uri: format!("{}/login", window().unwrap().location().origin().unwrap()),
-                }).await.unwrap().into_inner();
+                let res = if register {
+                    client.register_local_account(RegisterLocalAccountRequest { username, password }).await.map(|v| v.into_inner().token)
+                } else {
+                    client.password_login(PasswordLoginRequest { username, password }).await.map(|v| v.into_inner().token)
+                };
 
-                LocalStorage::set("access_token", res.token).unwrap();
-                login_callback.emit(());
-                navigator.push(&Route::Home);
+                match res {
+                    Ok(token) => {
+                        LocalStorage::set("access_token", token).unwrap();
+                        login_callback.emit(());
+                        navigator.push(&Route::Home);
+                    },
+                    Err(err) => login_error.set(Some(password_login_error_message(err.message()).to_owned())),
+                }
             });
-        } else {
-            start_oauth_flow();
-        }
-    }, query.code.clone());
+        })
+    };
+
+    let content = match (login_error.as_ref(), providers.as_ref()) {
+        (Some(err), None) => html!({ err.clone() }),
+        (None, Some(providers)) if providers.providers.is_empty() && !providers.local_accounts_enabled => html!({"login is not configured on this server."}),
+        (err, Some(providers)) => {
+            let password_login_form = if providers.local_accounts_enabled {
+                let register_button = if providers.registration_enabled {
+                    let password_login = password_login.clone();
+                    html!(<button onclick={move |_| password_login.emit(true)}>{"register"}</button>)
+                } else {
+                    html!()
+                };
+
+                html!(
+                    <div class="password-login">
+                        <input
+                            placeholder="username"
+                            value={(*username).clone()}
+                            onchange={
+                                let username = username.clone();
+                                move |e: Event| username.set(input_value(e))
+                            } />
+                        <input
+                            type="password"
+                            placeholder="password"
+                            value={(*password).clone()}
+                            onchange={
+                                let password = password.clone();
+                                move |e: Event| password.set(input_value(e))
+                            } />
+                        <button onclick={move |_| password_login.emit(false)}>{"log in"}</button>
+                        { register_button }
+                        { err.clone().unwrap_or_default() }
+                    </div>
+                )
+            } else {
+                html!()
+            };
+
+            let buttons = providers.providers.iter()
+                .map(|provider| {
+                    let onclick = {
+                        let provider = provider.clone();
+                        move |_| start_oauth_flow(&provider)
+                    };
+
+                    html!(<button {onclick}>{ format!("continue with {}", provider.name) }</button>)
+                })
+                .collect::<Html>();
+
+            html!(
+                <>
+                    {"log in to your account"}
+                    { password_login_form }
+                    <div class="providers">{ buttons }</div>
+                </>
+            )
+        },
+        (None, None) => html!({"Logging you in to your account. Please wait..."}),
+    };
 
     html!(
         <div class={style}>
-            {"Logging you in to your account. Please wait..."}
+            { content }
         </div>
     )
-}
\ No newline at end of file
+}
+
+fn input_value(e: Event) -> String {
+    let target: Option<EventTarget> = e.target();
+    target.and_then(|t| t.dyn_into::<HtmlInputElement>().ok()).map(|v| v.value()).unwrap_or_default()
+}
+
+fn password_login_error_message(reason: &str) -> &'static str {
+    match reason {
+        "invalid_credentials" => "wrong username or password.",
+        "username_is_taken" => "this username is already taken.",
+        "username_is_not_valid" => "username should be 3-32 characters long and contain only letters, digits, '_', '-' or '.'.",
+        "password_is_not_valid" => "password should be at least 8 characters long.",
+        "registration_disabled" => "registration is disabled on this server.",
+        _ => "failed to log in, please try again.",
+    }
+}
@@ -1,4 +1,5 @@
 pub mod about;
+pub mod api_keys;
 pub mod history;
 pub mod home;
 pub mod login;
@@ -1,9 +1,229 @@
 use {
+    std::sync::{Arc, Mutex},
+    tracing::error,
     yew::prelude::*,
     stylist::{style, yew::styled_component},
+    wasm_bindgen_futures::spawn_local,
+    pulldown_cmark::{Parser, Options, Event, Tag, html::push_html},
+    rpc::{
+        task::Status,
+        get_task_response::ChatMessage,
+        ChatMessageRole,
+        TaskId,
+        AddChatUserMessageRequest,
+    },
+    crate::{
+        components::prompt_input::PromptInput,
+        utils::{task_client, error_message, task_failure_message},
+    },
 };
 
+#[derive(Properties, PartialEq)]
+pub struct ChatMessageGenerationTaskProps {
+    pub task_id: String,
+    pub status: Status,
+    pub messages: Vec<ChatMessage>,
+}
+
 #[styled_component(ChatMessageGenerationTask)]
-pub fn chat() -> Html {
-    html!("chat")
-}
\ No newline at end of file
+pub fn chat(props: &ChatMessageGenerationTaskProps) -> Html {
+    let client = Arc::new(Mutex::new(task_client(&props.task_id)));
+    let message = use_state(String::new);
+    let error = use_state(|| None::<String>);
+    let is_generating = matches!(props.status, Status::PendingDetails(_) | Status::InProgressDetails(_));
+
+    let send_message = {
+        let client = client.clone();
+        let task_id = props.task_id.clone();
+        let message = message.clone();
+        let error = error.clone();
+
+        Callback::from(move |_| {
+            let content = message.trim().to_owned();
+            if content.is_empty() || is_generating {
+                return;
+            }
+
+            let client = client.clone();
+            let task_id = task_id.clone();
+            let message = message.clone();
+            let error = error.clone();
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+
+                let res = client.add_chat_user_message(AddChatUserMessageRequest {
+                    task_id: Some(TaskId {
+                        id: task_id,
+                    }),
+                    content,
+                }).await;
+
+                // new message itself arrives with the task update.
+                match res {
+                    Ok(_) => {
+                        message.set(String::new());
+                        error.set(None);
+                    },
+                    Err(err) => {
+                        error!("failed to send chat message: {:?}", err);
+                        error.set(Some(error_message(&err)));
+                    },
+                }
+            });
+        })
+    };
+
+    let chat_style = style!(r#"
+        width: 592px;
+        margin: 0 auto;
+        padding-bottom: 40px;
+
+        .message {
+            display: flex;
+            margin-bottom: 12px;
+        }
+
+        .message.user {
+            justify-content: flex-end;
+        }
+
+        .message.system {
+            justify-content: center;
+            font-size: 10pt;
+            opacity: 0.6;
+        }
+
+        .bubble {
+            max-width: 480px;
+            padding: 8px 14px;
+            border-radius: 12px;
+            line-height: 1.4;
+            overflow-wrap: anywhere;
+        }
+
+        .user .bubble {
+            background-color: #5695DC;
+            border-bottom-right-radius: 2px;
+        }
+
+        .assistant .bubble {
+            background-color: #2a2a2a;
+            border-bottom-left-radius: 2px;
+        }
+
+        .bubble p {
+            margin: 6px 0;
+        }
+
+        .bubble pre {
+            padding: 8px;
+            background-color: #111;
+            border-radius: 4px;
+            overflow-x: auto;
+        }
+
+        .bubble code {
+            font-family: monospace;
+            font-size: 10pt;
+        }
+
+        .generating {
+            opacity: 0.6;
+            font-style: italic;
+        }
+
+        .composer {
+            margin-top: 24px;
+        }
+
+        .composer.disabled {
+            opacity: 0.5;
+            pointer-events: none;
+        }
+
+        .error {
+            margin-top: 8px;
+            color: #E57373;
+        }
+    "#).unwrap();
+
+    let last_is_assistant = props.messages.last().map(|v| v.role() == ChatMessageRole::Assistant).unwrap_or(false);
+
+    let messages = props.messages.iter()
+        .map(|message| {
+            let role = match message.role() {
+                ChatMessageRole::System => "system",
+                ChatMessageRole::User => "user",
+                ChatMessageRole::Assistant => "assistant",
+            };
+
+            html!(
+                <div class={classes!("message", role)}>
+                    <div class="bubble">{ render_markdown(&message.content) }</div>
+                </div>
+            )
+        })
+        .collect::<Html>();
+
+    // while reply is streamed, it is rendered as a regular assistant message.
+    let generating = if is_generating && !(last_is_assistant && matches!(props.status, Status::InProgressDetails(_))) {
+        html!(
+            <div class="message assistant">
+                <div class="bubble generating">{"generating..."}</div>
+            </div>
+        )
+    } else {
+        html!()
+    };
+
+    let failed = match &props.status {
+        Status::FailedDetails(failed) => html!(<div class="error">{ task_failure_message(&failed.reason) }</div>),
+        _ => html!(),
+    };
+
+    html!(
+        <div class={chat_style}>
+            { messages }
+            { generating }
+            { failed }
+            <div class={classes!("composer", if is_generating { Some("disabled") } else { None })}>
+                <PromptInput
+                    description={"your message"}
+                    action_name={"send"}
+                    action_button_width={100}
+                    value={(*message).clone()}
+                    on_change={
+                        let message = message.clone();
+                        move |v| message.set(v)
+                    }
+                    on_run_inference={send_message} />
+            </div>
+            { error.as_ref().map(|err| html!(<div class="error">{ err.clone() }</div>)).unwrap_or_default() }
+        </div>
+    )
+}
+
+fn render_markdown(content: &str) -> Html {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+
+    // model output is not trusted, so raw html is shown as text and only safe links are kept.
+    let events = Parser::new_ext(content, options).map(|event| match event {
+        Event::Html(html) => Event::Text(html),
+        Event::Start(Tag::Link(link_type, url, title)) if !is_safe_url(&url) => Event::Start(Tag::Link(link_type, "#".into(), title)),
+        Event::Start(Tag::Image(link_type, url, title)) if !is_safe_url(&url) => Event::Start(Tag::Image(link_type, "".into(), title)),
+        other => other,
+    });
+
+    let mut rendered = String::new();
+    push_html(&mut rendered, events);
+
+    Html::from_html_unchecked(AttrValue::from(rendered))
+}
+
+fn is_safe_url(url: &str) -> bool {
+    let url = url.trim().to_lowercase();
+    url.starts_with("http://") || url.starts_with("https://") || url.starts_with("mailto:") || url.starts_with('#')
+}
@@ -3,7 +3,7 @@ use {
     yew::prelude::*,
     stylist::{style, yew::styled_component},
     rpc::{task::Status, task_params::ImageGenerationParams, TaskAsset, InProgressTaskDetails},
-    crate::utils::MultiClass,
+    crate::utils::{MultiClass, task_failure_message},
 };
 
 #[derive(Properties, PartialEq)]
@@ -11,6 +11,9 @@ pub struct ImageGenerationTaskProps {
     pub status: Status,
     pub params: ImageGenerationParams,
     pub assets: Vec<TaskAsset>,
+    pub is_owner: bool,
+    pub on_delete_asset: Callback<String>,
+    pub on_generate_more: Callback<u32>,
 }
 
 #[derive(Clone)]
@@ -132,10 +135,37 @@ pub fn image_generation_task(props: &ImageGenerationTaskProps) -> Html {
 
     let selected_asset_style = style!("outline: 2px solid white;").unwrap();
 
+    let generate_more_style = style!(r#"
+        margin-top: 8px;
+        user-select: none;
+
+        button {
+            margin-left: 8px;
+            padding: 2px 10px;
+            font-size: 10pt;
+            background-color: transparent;
+            color: white;
+            border: 1px solid white;
+            border-radius: 4px;
+            cursor: pointer;
+            transition:
+                color 0.2s ease-out,
+                background-color 0.2s ease-out;
+        }
+
+        button:hover {
+            background-color: white;
+            color: black;
+        }
+    "#).unwrap();
+
     let status = match &props.status {
-        rpc::task::Status::PendingDetails(_) => html!(<>
+        rpc::task::Status::PendingDetails(pending) => html!(<>
             <span>{"waiting for image generation task to be picked by worker..."}</span>
-            <span>{"this normally takes a few seconds, but may be longer if multiple tasks are in queue"}</span>
+            { match pending.queue_position {
+                Some(position) if position > 0 => html!(<span>{format!("{} tasks ahead in queue", position)}</span>),
+                _ => html!(<span>{"this normally takes a few seconds, but may be longer if multiple tasks are in queue"}</span>),
+            }}
         </>),
         rpc::task::Status::InProgressDetails(in_progress) => {
             html!(<>
@@ -148,22 +178,94 @@ pub fn image_generation_task(props: &ImageGenerationTaskProps) -> Html {
         },
         rpc::task::Status::FinishedDetails(_) => {
             let prompt = props.params.prompt.clone();
+
+            let generate_more = if props.is_owner {
+                let options = [1, 5].into_iter()
+                    .map(|number_of_images| {
+                        let on_generate_more = props.on_generate_more.clone();
+                        html!(<button onclick={move |_| on_generate_more.emit(number_of_images)}>{format!("+{}", number_of_images)}</button>)
+                    })
+                    .collect::<Vec<_>>();
+
+                html!(<div class={generate_more_style}>{"generate more images: "}{ options }</div>)
+            } else {
+                html!()
+            };
             
             html!(<>
                 <span class={prompt_info_style}>{ prompt }</span>
+                { generate_more }
+            </>)
+        },
+        rpc::task::Status::CancelledDetails(_) => {
+            let prompt = props.params.prompt.clone();
+
+            html!(<>
+                <span class={prompt_info_style}>{ prompt }</span>
+                <span>{format!("task was cancelled, {} out of {} images were generated", props.assets.len(), props.params.number_of_images)}</span>
+            </>)
+        },
+        rpc::task::Status::FailedDetails(failed) => {
+            let prompt = props.params.prompt.clone();
+
+            html!(<>
+                <span class={prompt_info_style}>{ prompt }</span>
+                <span>{task_failure_message(&failed.reason)}</span>
+                <span>{format!("{} out of {} images were generated", props.assets.len(), props.params.number_of_images)}</span>
             </>)
         },
     };
 
+    let delete_image_style = style!(r#"
+        display: block;
+        margin: 8px auto 0 auto;
+        padding: 4px 12px;
+        font-size: 10pt;
+        background-color: transparent;
+        color: #CED0CE;
+        border: 1px solid #CED0CE;
+        border-radius: 4px;
+        cursor: pointer;
+        user-select: none;
+        transition:
+            color 0.2s ease-out,
+            background-color 0.2s ease-out;
+
+        :hover {
+            background-color: #D64933;
+            border-color: #D64933;
+            color: white;
+        }
+    "#).unwrap();
+
     let image = if !props.assets.is_empty() {
-        let focused_asset_id = state.focused_asset.as_ref().cloned().unwrap_or(props.assets.get(0).unwrap().id.clone());
-        html!(<img src={format!("/v1/storage/{}", focused_asset_id)} class={image_style} />)
+        // focused asset may be already deleted.
+        let focused_asset = state.focused_asset.as_ref()
+            .and_then(|id| props.assets.iter().find(|v| &v.id == id))
+            .unwrap_or(props.assets.get(0).unwrap());
+
+        let delete_image = if props.is_owner && is_finished(&props.status) {
+            let on_delete_asset = props.on_delete_asset.clone();
+            let asset_id = focused_asset.id.clone();
+
+            html!(<button class={delete_image_style} onclick={move |_| on_delete_asset.emit(asset_id.clone())}>{"delete this image"}</button>)
+        } else {
+            html!()
+        };
+
+        html!(<>
+            <img src={focused_asset.url.clone()} class={image_style} />
+            { delete_image }
+        </>)
     } else {
         html!(<div class={MultiClass::new().with(&image_style).with(&image_placeholder_style)}>{ &props.params.prompt }</div>)
     };
 
     let all_images = if props.assets.len() > 1 {
-        let focused_asset_id = state.focused_asset.as_ref().cloned().unwrap_or(props.assets.get(0).unwrap().id.clone());
+        let focused_asset_id = state.focused_asset.as_ref()
+            .filter(|id| props.assets.iter().any(|v| &v.id == *id))
+            .cloned()
+            .unwrap_or(props.assets.get(0).unwrap().id.clone());
 
         let mut images = Vec::new();
         let selected_asset_style_class = selected_asset_style.get_class_name().to_owned();
@@ -175,7 +277,7 @@ pub fn image_generation_task(props: &ImageGenerationTaskProps) -> Html {
             images.push(html!(<img 
                 class={if focused_asset_id == asset_id { selected_asset_style_class.clone() } else { "".to_owned() }}
                 draggable="false"
-                src={format!("/v1/storage/{}", asset_id)} 
+                src={asset.url.clone()} 
                 onclick={Callback::from(move |_| state_dispatcher.dispatch(TaskStateAction::FocusOnAsset(asset_id.clone())))} />));
         }
 
@@ -213,4 +315,8 @@ fn progress_text(params: &ImageGenerationParams, in_progress_details: &InProgres
     } else {
         format!("generating image {} out of {}", in_progress_details.current_image + 1, total_images)
     }
-}
\ No newline at end of file
+}
+
+fn is_finished(status: &Status) -> bool {
+    matches!(status, Status::FinishedDetails(_) | Status::CancelledDetails(_) | Status::FailedDetails(_))
+}
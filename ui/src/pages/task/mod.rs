@@ -1,21 +1,28 @@
 use {
-    std::{sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, rc::Rc},
-    tracing::info,
+    std::{sync::{Arc, Mutex}, rc::Rc},
+    tracing::{info, error},
     yew::prelude::*,
     yew_router::prelude::*,
     wasm_bindgen_futures::spawn_local,
-    gloo_timers::callback::Interval,
+    gloo_timers::future::TimeoutFuture,
+    futures::{channel::oneshot, future::{select, Either}},
     stylist::{style, yew::styled_component},
-    rpc::{TaskId, Task, TaskParams, GetTaskRequest, task_params::Params},
-    crate::utils::{client, Route, MultiClass},
+    tonic::Code,
+    rpc::{TaskId, Task, TaskParams, get_task_response::ChatMessage, WatchTaskRequest, CancelTaskRequest, ExtendTaskRequest, RetryTaskRequest, DeleteTaskRequest, DeleteTaskAssetRequest, SetTaskVisibilityRequest, TaskVisibility, task_params::Params},
+    crate::utils::{task_client, Route, MultiClass},
     self::{
         image_generation::ImageGenerationTask,
         chat::ChatMessageGenerationTask,
+        timeline::TaskTimeline,
     },
 };
 
 mod chat;
 mod image_generation;
+mod timeline;
+
+const INITIAL_RECONNECT_DELAY_MS: u32 = 500;
+const MAX_RECONNECT_DELAY_MS: u32 = 10_000;
 
 #[derive(Properties, PartialEq)]
 pub struct TaskPageProps {
@@ -25,16 +32,22 @@ pub struct TaskPageProps {
 #[derive(Clone)]
 pub struct TaskState {
     task: Option<Task>,
+    messages: Vec<ChatMessage>,
+    is_owner: bool,
 }
 
 pub enum TaskStateAction {
-    LoadTask(Task),
+    LoadTask { task: Task, messages: Vec<ChatMessage>, is_owner: bool },
+    RemoveAsset(String),
+    SetVisibility(TaskVisibility),
 }
 
 impl Default for TaskState {
     fn default() -> Self {
         Self {
             task: None,
+            messages: Vec::new(),
+            is_owner: false,
         }
     }
 }
@@ -44,8 +57,27 @@ impl Reducible for TaskState {
 
     fn reduce(self: Rc<Self>, action: Self::Action) -> Rc<Self> {
         match action {
-            Self::Action::LoadTask(task) => Self {
+            Self::Action::LoadTask { task, messages, is_owner } => Self {
                 task: Some(task),
+                messages,
+                is_owner,
+                ..(*self).clone()
+            },
+            Self::Action::SetVisibility(visibility) => Self {
+                task: self.task.clone().map(|task| Task {
+                    params: task.params.map(|params| TaskParams {
+                        visibility: visibility.into(),
+                        ..params
+                    }),
+                    ..task
+                }),
+                ..(*self).clone()
+            },
+            Self::Action::RemoveAsset(asset_id) => Self {
+                task: self.task.clone().map(|task| Task {
+                    assets: task.assets.into_iter().filter(|v| v.id != asset_id).collect(),
+                    ..task
+                }),
                 ..(*self).clone()
             },
         }.into()
@@ -56,88 +88,225 @@ impl Reducible for TaskState {
 pub fn task_page(props: &TaskPageProps) -> Html {
     let navigator = use_navigator().unwrap();
 
-    let client = Arc::new(Mutex::new(client()));
+    let client = Arc::new(Mutex::new(task_client(&props.task_id)));
     let state = use_reducer(TaskState::default);
     let state_dispatcher = state.dispatcher();
 
     {
-        let client = client.clone();
         let state_dispatcher = state_dispatcher.clone();
 
         use_effect_with_deps(move |id| {
-            let client = client.clone();
             let id = id.clone();
-            let state_dispatcher = state_dispatcher.clone();
+            // watch is stopped when this sender is dropped, i.e. when page is closed or task id changes.
+            let (stop_watching, mut stopped) = oneshot::channel::<()>();
 
-            {
-                let id = id.clone();
-                let client = client.clone();
-                let state_dispatcher = state_dispatcher.clone();
+            spawn_local(async move {
+                // separate client is used, because watch stream is kept open for as long as the page is open.
+                let mut client = task_client(&id);
+                let mut reconnect_delay = INITIAL_RECONNECT_DELAY_MS;
 
-                spawn_local(async move {
-                    let mut client = client.lock().unwrap();
-                
-                    let res = client.get_task(GetTaskRequest {
+                loop {
+                    let res = client.watch_task(WatchTaskRequest {
                         id: Some(TaskId {
-                            id,
+                            id: id.clone(),
                         }),
-                    }).await.unwrap().into_inner();
+                    }).await;
 
-                    state_dispatcher.dispatch(TaskStateAction::LoadTask(res.task.unwrap()));
-                });
-            }
-            
-            || {}
+                    match res {
+                        Ok(res) => {
+                            let mut stream = res.into_inner();
+
+                            loop {
+                                let message = match select(Box::pin(stream.message()), &mut stopped).await {
+                                    Either::Left((message, _)) => message,
+                                    Either::Right(_) => return,
+                                };
+
+                                match message {
+                                    Ok(Some(update)) => {
+                                        reconnect_delay = INITIAL_RECONNECT_DELAY_MS;
+                                        state_dispatcher.dispatch(TaskStateAction::LoadTask {
+                                            task: update.task.unwrap(),
+                                            messages: update.messages,
+                                            is_owner: update.is_owner,
+                                        });
+                                    },
+                                    Ok(None) => break,
+                                    Err(err) if err.code() == Code::NotFound => return,
+                                    Err(err) => {
+                                        error!("task updates stream failed: {:?}", err);
+                                        break;
+                                    }
+                                }
+                            }
+                        },
+                        Err(err) if err.code() == Code::NotFound => return,
+                        Err(err) => error!("failed to start watching task: {:?}", err),
+                    }
+
+                    info!("reconnecting to task updates in {}ms", reconnect_delay);
+                    if let Either::Right(_) = select(TimeoutFuture::new(reconnect_delay), &mut stopped).await {
+                        return;
+                    }
+                    reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY_MS);
+                }
+            });
+
+            move || drop(stop_watching)
         }, props.task_id.clone());
     }
 
-    {
+    let cancel_task = {
+        let client = client.clone();
+        let task_id = props.task_id.clone();
+
+        Callback::from(move |_| {
+            let client = client.clone();
+            let task_id = task_id.clone();
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+
+                let res = client.cancel_task(CancelTaskRequest {
+                    task_id: Some(TaskId {
+                        id: task_id,
+                    }),
+                }).await;
+
+                if let Err(err) = res {
+                    error!("failed to cancel task: {:?}", err);
+                }
+            });
+        })
+    };
+
+    let generate_more = {
+        let client = client.clone();
+        let task_id = props.task_id.clone();
+
+        Callback::from(move |number_of_images: u32| {
+            let client = client.clone();
+            let task_id = task_id.clone();
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+
+                let res = client.extend_task(ExtendTaskRequest {
+                    task_id: Some(TaskId {
+                        id: task_id,
+                    }),
+                    number_of_images,
+                }).await;
+
+                // task is queued again, updated status arrives through the watch stream.
+                if let Err(err) = res {
+                    error!("failed to generate more images: {:?}", err);
+                }
+            });
+        })
+    };
+
+    let retry_task = {
+        let client = client.clone();
+        let task_id = props.task_id.clone();
+
+        Callback::from(move |_| {
+            let client = client.clone();
+            let task_id = task_id.clone();
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+
+                let res = client.retry_task(RetryTaskRequest {
+                    task_id: Some(TaskId {
+                        id: task_id,
+                    }),
+                }).await;
+
+                if let Err(err) = res {
+                    error!("failed to retry task: {:?}", err);
+                }
+            });
+        })
+    };
+
+    let delete_task = {
+        let client = client.clone();
+        let task_id = props.task_id.clone();
+        let navigator = navigator.clone();
+
+        Callback::from(move |_| {
+            let client = client.clone();
+            let task_id = task_id.clone();
+            let navigator = navigator.clone();
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+
+                let res = client.delete_task(DeleteTaskRequest {
+                    task_id: Some(TaskId {
+                        id: task_id,
+                    }),
+                }).await;
+
+                match res {
+                    Ok(_) => navigator.push(&Route::History),
+                    Err(err) => error!("failed to delete task: {:?}", err),
+                }
+            });
+        })
+    };
+
+    let delete_asset = {
+        let client = client.clone();
         let state_dispatcher = state_dispatcher.clone();
 
-        use_effect_with_deps(move |(task_id, status)| {
-            let mut interval = None;            
-            
-            if status.is_none() {
-                info!("no info about task yet");
-            } else if let Some(rpc::task::Status::FinishedDetails(_)) = status {
-                info!("task is finished");
-            } else {
-                info!("task is not finished yet");
-                
-                let refresh_in_progress = Arc::new(AtomicBool::new(false));
-                let id = task_id.clone();
-                let state_dispatcher = state_dispatcher.clone();
-    
-                interval = Some(Interval::new(1000, move || {
-                    let id = id.clone();
-                    let client = client.clone();
-    
-                    if refresh_in_progress.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
-                        let refresh_in_progress = refresh_in_progress.clone();
-                        let state_dispatcher = state_dispatcher.clone();
-    
-                        spawn_local(async move {
-                            let mut client = client.lock().unwrap();
-    
-                            let res = client.get_task(GetTaskRequest {
-                                id: Some(TaskId { id }),
-                            }).await.unwrap().into_inner();
-                            
-                            state_dispatcher.dispatch(TaskStateAction::LoadTask(res.task.unwrap()));
-    
-                            refresh_in_progress.store(false, Ordering::SeqCst);
-                        });
-                    }
-                }));
-            }
-    
-            move || {
-                if let Some(interval) = interval {
-                    interval.cancel();
+        Callback::from(move |asset_id: String| {
+            let client = client.clone();
+            let state_dispatcher = state_dispatcher.clone();
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+
+                let res = client.delete_task_asset(DeleteTaskAssetRequest {
+                    asset_id: asset_id.clone(),
+                }).await;
+
+                match res {
+                    Ok(_) => state_dispatcher.dispatch(TaskStateAction::RemoveAsset(asset_id)),
+                    Err(err) => error!("failed to delete task asset: {:?}", err),
                 }
-            }
-        }, (props.task_id.clone(), state.task.as_ref().and_then(|v| v.status.clone())));
-    }
+            });
+        })
+    };
+
+    let set_visibility = {
+        let client = client.clone();
+        let task_id = props.task_id.clone();
+        let state_dispatcher = state_dispatcher.clone();
+
+        Callback::from(move |visibility: TaskVisibility| {
+            let client = client.clone();
+            let task_id = task_id.clone();
+            let state_dispatcher = state_dispatcher.clone();
+
+            spawn_local(async move {
+                let mut client = client.lock().unwrap();
+
+                let res = client.set_task_visibility(SetTaskVisibilityRequest {
+                    task_id: Some(TaskId {
+                        id: task_id,
+                    }),
+                    visibility: visibility.into(),
+                }).await;
+
+                match res {
+                    Ok(_) => state_dispatcher.dispatch(TaskStateAction::SetVisibility(visibility)),
+                    Err(err) => error!("failed to update task visibility: {:?}", err),
+                }
+            });
+        })
+    };
 
     let loading_style = style!(r#"
         text-align: center;
@@ -146,6 +315,97 @@ pub fn task_page(props: &TaskPageProps) -> Html {
         padding-top: 240px;
     "#).unwrap();
 
+    let controls_style = style!(r#"
+        width: 512px;
+        margin: 0 auto 20px auto;
+        display: flex;
+        justify-content: space-between;
+
+        .visibility span {
+            display: inline-block;
+            padding: 4px 8px;
+            font-size: 11pt;
+            border: 1px solid white;
+            border-right: 0px;
+            cursor: pointer;
+            user-select: none;
+            transition: color 0.2s ease-out, background-color 0.2s ease-out;
+        }
+
+        .visibility span:first-child {
+            border-radius: 4px 0 0 4px;
+        }
+
+        .visibility span:last-child {
+            border-radius: 0 4px 4px 0;
+            border-right: 1px solid white;
+        }
+
+        .visibility span:hover, .visibility .selected {
+            background-color: white;
+            color: black;
+        }
+
+        button {
+            padding: 4px 12px;
+            font-size: 11pt;
+            background-color: transparent;
+            color: white;
+            border: 1px solid white;
+            border-radius: 4px;
+            cursor: pointer;
+            user-select: none;
+            transition:
+                color 0.2s ease-out,
+                background-color 0.2s ease-out;
+        }
+
+        button:hover {
+            background-color: white;
+            color: black;
+        }
+    "#).unwrap();
+
+    let controls = match &state.task {
+        Some(task) if state.is_owner => {
+            let current_visibility = task.params.as_ref().map(|v| v.visibility()).unwrap_or(TaskVisibility::Unlisted);
+
+            let visibility_options = [
+                (TaskVisibility::Private, "private"),
+                (TaskVisibility::Unlisted, "unlisted"),
+                (TaskVisibility::Public, "public"),
+            ].into_iter()
+                .map(|(visibility, name)| {
+                    let set_visibility = set_visibility.clone();
+
+                    html!(<span 
+                        class={if visibility == current_visibility { "selected" } else { "" }}
+                        onclick={move |_| set_visibility.emit(visibility)}>{name}</span>)
+                })
+                .collect::<Vec<_>>();
+
+            let action = if is_active(task) {
+                html!(<button onclick={cancel_task}>{"cancel"}</button>)
+            } else if is_retryable(task) {
+                html!(<div>
+                    <button onclick={retry_task}>{"retry"}</button>
+                    {" "}
+                    <button onclick={delete_task}>{"delete task"}</button>
+                </div>)
+            } else {
+                html!(<button onclick={delete_task}>{"delete task"}</button>)
+            };
+
+            html!(
+                <div class={controls_style}>
+                    <div class="visibility">{ visibility_options }</div>
+                    { action }
+                </div>
+            )
+        },
+        _ => html!(),
+    };
+
     let rendered = match &state.task {
         None => html!(<div class={loading_style}>{"loading task status..."}</div>),
         Some(task) => {
@@ -154,15 +414,52 @@ pub fn task_page(props: &TaskPageProps) -> Html {
             }
 
             match task.params.clone().unwrap().params.unwrap() {
-                Params::ImageGeneration(v) => html!(<ImageGenerationTask status={task.status.clone().unwrap()} params={v} assets={task.assets.clone()} />),
-                Params::ChatMessageGeneration(_) => html!(<ChatMessageGenerationTask />),
+                Params::ImageGeneration(v) => html!(<ImageGenerationTask 
+                    status={task.status.clone().unwrap()} 
+                    params={v} 
+                    assets={task.assets.clone()}
+                    is_owner={state.is_owner}
+                    on_delete_asset={delete_asset}
+                    on_generate_more={generate_more} />),
+                Params::ChatMessageGeneration(_) => html!(<ChatMessageGenerationTask
+                    task_id={props.task_id.clone()}
+                    status={task.status.clone().unwrap()}
+                    messages={state.messages.clone()} />),
             }
         }
     };
 
+    let timeline = match &state.task {
+        Some(task) if task.status.is_some() => html!(<TaskTimeline task_id={props.task_id.clone()} revision={timeline_revision(task)} />),
+        _ => html!(),
+    };
+
     html!(
         <div>
+            { controls }
             { rendered }
+            { timeline }
         </div>
     )
 }
+
+fn timeline_revision(task: &Task) -> String {
+    let status = match task.status {
+        Some(rpc::task::Status::PendingDetails(_)) => "pending",
+        Some(rpc::task::Status::InProgressDetails(_)) => "in_progress",
+        Some(rpc::task::Status::FinishedDetails(_)) => "finished",
+        Some(rpc::task::Status::CancelledDetails(_)) => "cancelled",
+        Some(rpc::task::Status::FailedDetails(_)) => "failed",
+        None => "",
+    };
+
+    format!("{}-{}", status, task.assets.len())
+}
+
+fn is_active(task: &Task) -> bool {
+    matches!(task.status, Some(rpc::task::Status::PendingDetails(_)) | Some(rpc::task::Status::InProgressDetails(_)))
+}
+
+fn is_retryable(task: &Task) -> bool {
+    matches!(&task.status, Some(rpc::task::Status::FailedDetails(failed)) if failed.retryable)
+}
@@ -0,0 +1,185 @@
+use {
+    tracing::error,
+    yew::prelude::*,
+    stylist::{style, yew::styled_component},
+    wasm_bindgen_futures::spawn_local,
+    rpc::{TaskId, TaskEvent, TaskEventKind, GetTaskEventsRequest},
+    crate::utils::{task_client, task_failure_message},
+};
+
+#[derive(Properties, PartialEq)]
+pub struct TaskTimelineProps {
+    pub task_id: String,
+    // events are reloaded when this changes, i.e. when task status changes or a new asset is created, not on every step.
+    pub revision: String,
+}
+
+#[styled_component(TaskTimeline)]
+pub fn task_timeline(props: &TaskTimelineProps) -> Html {
+    let events = use_state(Vec::<TaskEvent>::new);
+    let expanded = use_state(|| false);
+
+    {
+        let events = events.clone();
+        let task_id = props.task_id.clone();
+
+        use_effect_with_deps(move |_| {
+            spawn_local(async move {
+                let res = task_client(&task_id).get_task_events(GetTaskEventsRequest {
+                    task_id: Some(TaskId {
+                        id: task_id.clone(),
+                    }),
+                }).await;
+
+                match res {
+                    Ok(res) => events.set(res.into_inner().events),
+                    Err(err) => error!("failed to load task events: {:?}", err),
+                }
+            });
+        }, (props.task_id.clone(), props.revision.clone()));
+    }
+
+    let timeline_style = style!(r#"
+        width: 512px;
+        margin: 20px auto 0 auto;
+        font-size: 10pt;
+        color: #CED0CE;
+
+        .summary {
+            cursor: pointer;
+            user-select: none;
+        }
+
+        .event {
+            display: flex;
+            padding: 2px 0;
+        }
+
+        .time {
+            width: 80px;
+            flex-shrink: 0;
+            text-align: right;
+            padding-right: 12px;
+            font-variant-numeric: tabular-nums;
+        }
+    "#).unwrap();
+
+    if events.is_empty() {
+        return html!();
+    }
+
+    let started_at = seconds(&events[0]);
+    let (queue_wait, run) = phase_totals(&events);
+    let summary = match run {
+        Some(run) => format!("waited in queue {}, ran {}", format_duration(queue_wait), format_duration(run)),
+        None => format!("waited in queue {}", format_duration(queue_wait)),
+    };
+
+    let toggle = {
+        let expanded = expanded.clone();
+        Callback::from(move |_| expanded.set(!*expanded))
+    };
+
+    let list = if *expanded {
+        events.iter()
+            .map(|event| html!(
+                <div class="event">
+                    <span class="time">{format!("+{}", format_duration(seconds(event) - started_at))}</span>
+                    <span>{describe_event(event)}</span>
+                </div>
+            ))
+            .collect::<Html>()
+    } else {
+        html!()
+    };
+
+    html!(
+        <div class={timeline_style}>
+            <div class="summary" onclick={toggle}>{format!("{} {}", if *expanded { "▾" } else { "▸" }, summary)}</div>
+            { list }
+        </div>
+    )
+}
+
+fn describe_event(event: &TaskEvent) -> String {
+    match event.kind() {
+        TaskEventKind::TaskCreated => "created".to_owned(),
+        TaskEventKind::TaskClaimed => {
+            let worker = event.worker_id.as_ref().map(|v| format!(" {}", v)).unwrap_or_default();
+            format!("picked by worker{} (attempt {})", worker, event.attempt)
+        },
+        TaskEventKind::TaskProgress => match &event.progress {
+            Some(progress) if progress.current_image > 0 => format!("generating image {}", progress.current_image + 1),
+            _ => "started".to_owned(),
+        },
+        TaskEventKind::TaskAssetCreated => "image generated".to_owned(),
+        TaskEventKind::TaskRequeued => "returned to queue".to_owned(),
+        TaskEventKind::TaskFinished => "finished".to_owned(),
+        TaskEventKind::TaskCancelled => "cancelled".to_owned(),
+        TaskEventKind::TaskFailed => event.failure.as_ref()
+            .map(|v| task_failure_message(&v.reason))
+            .unwrap_or_else(|| "failed".to_owned()),
+    }
+}
+
+// total time spent waiting for a worker and running, over all attempts. Run time is None until the task was claimed.
+fn phase_totals(events: &[TaskEvent]) -> (f64, Option<f64>) {
+    let mut queue_wait = 0.0;
+    let mut run = None::<f64>;
+    let mut queued_at = None;
+    let mut claimed_at = None;
+
+    for event in events {
+        let time = seconds(event);
+
+        match event.kind() {
+            TaskEventKind::TaskCreated | TaskEventKind::TaskRequeued => {
+                if let Some(claimed) = claimed_at.take() {
+                    run = Some(run.unwrap_or(0.0) + time - claimed);
+                }
+                queued_at = Some(time);
+            },
+            TaskEventKind::TaskClaimed => {
+                if let Some(queued) = queued_at.take() {
+                    queue_wait += time - queued;
+                }
+                claimed_at = Some(time);
+            },
+            TaskEventKind::TaskFinished | TaskEventKind::TaskCancelled | TaskEventKind::TaskFailed => {
+                if let Some(claimed) = claimed_at.take() {
+                    run = Some(run.unwrap_or(0.0) + time - claimed);
+                }
+                if let Some(queued) = queued_at.take() {
+                    queue_wait += time - queued;
+                }
+            },
+            TaskEventKind::TaskProgress | TaskEventKind::TaskAssetCreated => {},
+        }
+    }
+
+    // phases which have not ended yet are counted until now.
+    let now = web_time::SystemTime::now().duration_since(web_time::UNIX_EPOCH).unwrap().as_secs_f64();
+    if let Some(queued) = queued_at {
+        queue_wait += now - queued;
+    }
+    if let Some(claimed) = claimed_at {
+        run = Some(run.unwrap_or(0.0) + now - claimed);
+    }
+
+    (queue_wait, run)
+}
+
+fn seconds(event: &TaskEvent) -> f64 {
+    event.created_at.as_ref().map(|v| v.seconds as f64 + v.nanos as f64 / 1e9).unwrap_or(0.0)
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    if seconds < 60 {
+        format!("{}s", seconds)
+    } else if seconds < 60 * 60 {
+        format!("{}m {:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}h {:02}m", seconds / 3600, (seconds / 60) % 60)
+    }
+}
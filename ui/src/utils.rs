@@ -13,7 +13,7 @@ use {
     },
     stylist::Style,
     yew::Classes,
-    rpc::sandbox_service_client::SandboxServiceClient,
+    rpc::{sandbox_service_client::SandboxServiceClient, AuthProvider},
 };
 
 pub type SandboxClient = SandboxServiceClient<InterceptedService<Client, AuthTokenSetterInterceptor>>;
@@ -28,6 +28,8 @@ pub enum Route {
     Task { id: String },
     #[at("/history")]
     History,
+    #[at("/keys")]
+    ApiKeys,
     #[at("/about")]
     About,
 }
@@ -39,18 +41,38 @@ pub fn client() -> SandboxClient {
 pub fn client_with_token(token: Option<String>) -> SandboxClient {
     SandboxServiceClient::with_interceptor(
         Client::new(format!("{}/v1/rpc", window().unwrap().location().origin().unwrap())),
-        AuthTokenSetterInterceptor::new(token),
+        AuthTokenSetterInterceptor::new(token, None),
     )
 }
 
+// tasks created without an account can only be managed with the token returned on creation.
+pub fn task_client(task_id: &str) -> SandboxClient {
+    SandboxServiceClient::with_interceptor(
+        Client::new(format!("{}/v1/rpc", window().unwrap().location().origin().unwrap())),
+        AuthTokenSetterInterceptor::new(LocalStorage::get("access_token").ok(), LocalStorage::get(task_token_key(task_id)).ok()),
+    )
+}
+
+pub fn save_task_token(task_id: &str, token: &str) {
+    if !token.is_empty() {
+        LocalStorage::set(task_token_key(task_id), token).unwrap();
+    }
+}
+
+fn task_token_key(task_id: &str) -> String {
+    format!("task_token_{}", task_id)
+}
+
 pub struct AuthTokenSetterInterceptor {
     token: Option<String>,
+    task_token: Option<String>,
 }
 
 impl AuthTokenSetterInterceptor {
-    pub fn new(token: Option<String>) -> Self {
+    pub fn new(token: Option<String>, task_token: Option<String>) -> Self {
         Self {
             token,
+            task_token,
         }
     }
 }
@@ -60,6 +82,9 @@ impl Interceptor for AuthTokenSetterInterceptor {
         if let Some(token) = self.token.as_ref() {
             req.metadata_mut().insert("x-access-token", MetadataValue::try_from(token).unwrap());
         }
+        if let Some(task_token) = self.task_token.as_ref() {
+            req.metadata_mut().insert("x-task-token", MetadataValue::try_from(task_token).unwrap());
+        }
         Ok(req)
     }
 }
@@ -91,17 +116,80 @@ impl From<MultiClass> for Classes {
     }
 }
 
-pub fn start_oauth_flow() {
+pub fn start_oauth_flow(provider: &AuthProvider) {
     let redirect_uri = format!("{}/login", window().unwrap().location().origin().unwrap());
 
+    // provider is remembered to finish login after redirect back, state protects against login csrf.
+    let state = random_state();
+    LocalStorage::set("oauth_provider", &provider.id).unwrap();
+    LocalStorage::set("oauth_state", &state).unwrap();
+
     let mut query_params = HashMap::new();
-    query_params.insert("client_id", "916750455653-biu6q4c7llj7q1k14h3qaquktcdlkeo4.apps.googleusercontent.com".to_owned());
+    query_params.insert("client_id", provider.client_id.clone());
     query_params.insert("response_type", "code".to_owned());
-    query_params.insert("scope", "https://www.googleapis.com/auth/userinfo.profile https://www.googleapis.com/auth/userinfo.email".to_owned()); 
+    query_params.insert("scope", provider.scopes.join(" "));
+    query_params.insert("redirect_uri", redirect_uri);
+    query_params.insert("state", state);
 
     let query_string = form_urlencoded::Serializer::new("".to_owned())
         .extend_pairs(query_params.iter())
         .finish();
 
-    window().unwrap().location().set_href(&format!("https://accounts.google.com/o/oauth2/v2/auth?redirect_uri={}&{}", redirect_uri, query_string)).unwrap();
-}
\ No newline at end of file
+    let separator = if provider.authorization_endpoint.contains('?') { "&" } else { "?" };
+    window().unwrap().location().set_href(&format!("{}{}{}", provider.authorization_endpoint, separator, query_string)).unwrap();
+}
+
+fn random_state() -> String {
+    let mut bytes = [0u8; 16];
+    window().unwrap().crypto().unwrap().get_random_values_with_u8_array(&mut bytes).unwrap();
+    bytes.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+pub fn task_failure_message(reason: &str) -> String {
+    match reason {
+        "worker_lost" => "worker running this task stopped responding too many times.".to_owned(),
+        "task_params_missing" => "task could not be started by the worker.".to_owned(),
+        other => format!("task failed: {}", other),
+    }
+}
+
+pub fn error_message(status: &Status) -> String {
+    let message = match status.message() {
+        "too_many_images" | "number_of_images_too_large" => "too many images requested for a single task.",
+        "too_many_steps" => "too many steps requested for a single task.",
+        "too_many_active_tasks" => "you have too many tasks in progress.",
+        "daily_tasks_exceeded" => "you have reached the daily limit of tasks.",
+        "daily_tokens_exceeded" => "you have reached the daily usage limit.",
+        "reply_is_being_generated" => "please wait until the reply is generated.",
+        "prompt_is_empty" => "please enter a prompt.",
+        "message_is_empty" => "please enter a message.",
+        "number_of_images_is_zero" => "at least one image should be requested.",
+        "iterations_is_zero" => "at least one step is required.",
+        "task_not_found" => "this task does not exist.",
+        "not_task_owner" => "only the owner of this task can do that.",
+        "priority_not_allowed" => "you are not allowed to set task priority.",
+        "task_not_failed" => "only failed tasks can be retried.",
+        "task_not_retryable" => "this task cannot be retried.",
+        "token expired" | "invalid_token" => "your session has expired, please log in again.",
+        "database_unavailable" | "storage_unavailable" => "service is temporarily unavailable.",
+        _ => "something went wrong, please try again.",
+    };
+
+    let retry_after = status.metadata()
+        .get("retry-after")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    match retry_after {
+        Some(seconds) => format!("{} try again in {}.", message, format_retry_after(seconds)),
+        None => message.to_owned(),
+    }
+}
+
+fn format_retry_after(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{} seconds", seconds.max(1)),
+        60..=3599 => format!("{} minutes", seconds / 60),
+        _ => format!("{} hours", seconds / 3600),
+    }
+}